@@ -0,0 +1,11 @@
+//! Export the bundled Lua from the Lake executable, native plugins link against it when loaded
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    let target_os = std::env::var("CARGO_CFG_TARGET_OS").unwrap_or_default();
+    match target_os.as_str() {
+        "macos" | "ios" => println!("cargo:rustc-link-arg=-Wl,-export_dynamic"),
+        "windows" => {}
+        _ => println!("cargo:rustc-link-arg=-Wl,--export-dynamic"),
+    }
+}
@@ -0,0 +1,105 @@
+//! API for native Lake plugins
+//!
+//! Native plugins are shared libraries that implement [`Plugin`] and export
+//! their entry point with [`declare_plugin!`]:
+//!
+//! ```ignore
+//! use lake_plugin_api::mlua::{Lua, Result as LuaResult};
+//! use lake_plugin_api::{declare_plugin, Plugin};
+//!
+//! pub struct HelloPlugin;
+//!
+//! impl Plugin for HelloPlugin {
+//!     fn register(&self, lua: &Lua) -> LuaResult<()> {
+//!         let hello = lua.create_table()?;
+//!         hello.set("greet", lua.create_function(|_, name: String| Ok(format!("Hello, {}!", name)))?)?;
+//!         lua.globals().set("lake.hello", hello)
+//!     }
+//!
+//!     fn name(&self) -> &str {
+//!         "hello"
+//!     }
+//! }
+//!
+//! declare_plugin!(HelloPlugin);
+//! ```
+//!
+//! Build the plugin as a `cdylib` with the `module` feature, so it uses the
+//! Lua bundled in the Lake executable instead of its own copy, and put it on
+//! the plugin search path. It is then loaded with `plugin("hello")`.
+//!
+//! The plugin and Lake share a Lua state, so they must agree on
+//! [`API_VERSION`], [`LUA_VERSION`] and [`MLUA_VERSION`]. Lake checks them
+//! before calling into the plugin, but loading the library already runs its
+//! initializers.
+
+use std::os::raw::c_int;
+
+pub use mlua;
+
+use mlua::{ffi, Lua, Result as LuaResult, Value};
+
+/// Version of the plugin interface, bumped on every incompatible change
+pub const API_VERSION: u32 = 1;
+
+/// Lua version, as `LUA_VERSION_NUM`
+pub const LUA_VERSION: u32 = 504;
+
+/// Version of mlua as `[major, minor, patch]`, matching the pinned dependency
+pub const MLUA_VERSION: [u32; 3] = [0, 10, 3];
+
+/// Name of the symbol exported by [`declare_plugin!`]
+pub const DECLARATION_SYMBOL: &str = "LAKE_PLUGIN_DECLARATION";
+
+/// API for registering plugins
+pub trait Plugin {
+    /// Register the plugin with the Lua state
+    fn register(&self, lua: &Lua) -> LuaResult<()>;
+
+    /// Get the plugin name
+    fn name(&self) -> &str;
+}
+
+/// Entry point of a native plugin.
+///
+/// Lake reads `api_version` first and only calls `register` when it and the
+/// Lua and mlua versions match its own. `register` is a Lua C function
+/// returning the plugin table.
+#[repr(C)]
+pub struct PluginDeclaration {
+    pub api_version: u32,
+    pub lua_version: u32,
+    pub mlua_version: [u32; 3],
+    pub register: ffi::lua_CFunction,
+}
+
+/// Register a plugin with a Lua state owned by Lake and push its `lake.<name>` table
+#[doc(hidden)]
+pub unsafe fn register(state: *mut ffi::lua_State, plugin: impl Plugin) -> c_int {
+    Lua::entrypoint1(state, move |lua| {
+        plugin.register(lua)?;
+        lua.globals()
+            .get::<Value>(format!("lake.{}", plugin.name()))
+    })
+}
+
+/// Export the entry point of a native plugin, given an expression creating it
+#[macro_export]
+macro_rules! declare_plugin {
+    ($plugin:expr) => {
+        #[no_mangle]
+        pub static LAKE_PLUGIN_DECLARATION: $crate::PluginDeclaration = $crate::PluginDeclaration {
+            api_version: $crate::API_VERSION,
+            lua_version: $crate::LUA_VERSION,
+            mlua_version: $crate::MLUA_VERSION,
+            register: {
+                unsafe extern "C-unwind" fn register(
+                    state: *mut $crate::mlua::ffi::lua_State,
+                ) -> ::std::os::raw::c_int {
+                    $crate::register(state, $plugin)
+                }
+                register
+            },
+        };
+    };
+}
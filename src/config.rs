@@ -0,0 +1,273 @@
+//! Configuration for Lake
+//!
+//! Settings are read from the `lake.toml` next to the build.lake, then from
+//! the user configuration in `~/.config/lake/config.toml`, so users can
+//! override project settings on their machine. Command line options take
+//! precedence over both.
+//!
+//! Sandbox permissions are only granted by the user configuration and the
+//! command line. The `[allow]` table of a project lists the permissions it
+//! requests, so Lake can point out the missing flags without granting them.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::packages;
+use crate::permissions::Allow;
+
+/// Name of the project configuration file
+pub const PROJECT_CONFIG: &str = "lake.toml";
+
+/// Merged project and user configuration
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Task to run when none is given on the command line
+    pub default_task: Option<String>,
+    /// Number of tasks to run in parallel when `--jobs` is not given
+    pub jobs: Option<usize>,
+    /// Directories searched for plugins, relative to the file declaring them
+    pub plugin_paths: Vec<PathBuf>,
+    /// Log filter used when neither `RUST_LOG` nor `--verbose` is set
+    pub log_level: Option<String>,
+    /// Environment variables of the build
+    pub env: BTreeMap<String, String>,
+    /// Sandbox capabilities granted to scripts by the user configuration
+    pub allow: Allow,
+    /// Sandbox capabilities requested by the project configuration
+    #[serde(skip)]
+    pub requested: Allow,
+    /// Environment variables whose value comes from the project configuration
+    #[serde(skip)]
+    pub project_env: BTreeMap<String, String>,
+}
+
+impl Config {
+    /// Load and merge the project and user configuration.
+    ///
+    /// Tables such as `env` are merged key by key, any other value from the
+    /// user configuration replaces the project one.
+    pub fn load(project_dir: &Path) -> Result<Self> {
+        Self::load_files(
+            &project_dir.join(PROJECT_CONFIG),
+            user_config_path().as_deref(),
+        )
+    }
+
+    fn load_files(project_config: &Path, user_config: Option<&Path>) -> Result<Self> {
+        let mut merged = read_config(project_config)?.unwrap_or_default();
+
+        // A project must not grant itself permissions, only request them
+        let requested = match merged.remove("allow") {
+            Some(allow) => allow
+                .try_into()
+                .context(format!("Invalid configuration in {:?}", project_config))?,
+            None => Allow::default(),
+        };
+
+        // Neither may it set Lake's environment, which granted programs inherit
+        let env_names = |table: &toml::Table| -> Vec<String> {
+            match table.get("env") {
+                Some(toml::Value::Table(env)) => env.keys().cloned().collect(),
+                _ => Vec::new(),
+            }
+        };
+        let mut project_names = env_names(&merged);
+
+        if let Some(path) = user_config {
+            if let Some(table) = read_config(path)? {
+                let user_names = env_names(&table);
+                project_names.retain(|name| !user_names.contains(name));
+                merge_tables(&mut merged, table);
+            }
+        }
+
+        let config: Config = toml::Value::Table(merged)
+            .try_into()
+            .context("Invalid Lake configuration")?;
+        let project_env = config
+            .env
+            .iter()
+            .filter(|(name, _)| project_names.contains(name))
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect();
+        Ok(Config {
+            requested,
+            project_env,
+            ..config
+        })
+    }
+
+    /// Set the environment variables of the user configuration, unless the
+    /// environment already defines them. Those of the project are only
+    /// passed to the commands of the build, see [`Config::project_env`].
+    pub fn apply_env(&self) {
+        for (name, value) in &self.env {
+            if env::var_os(name).is_none() && !self.project_env.contains_key(name) {
+                env::set_var(name, value);
+            }
+        }
+    }
+
+    /// Task to run when none is given on the command line
+    pub fn default_task(&self) -> &str {
+        self.default_task.as_deref().unwrap_or("default")
+    }
+
+    /// Directories searched for Lua plugins: the project `plugins/` directory,
+    /// the installed packages, the configured `plugin_paths`, `LAKE_PLUGIN_PATH`
+    /// and `~/.lake/plugins`
+    pub fn plugin_search_path(&self, project_dir: &Path) -> Vec<PathBuf> {
+        let mut search_path = vec![
+            project_dir.join("plugins"),
+            project_dir.join(packages::PACKAGES_DIR),
+        ];
+        search_path.extend(self.plugin_paths.iter().cloned());
+        if let Some(paths) = env::var_os("LAKE_PLUGIN_PATH") {
+            search_path.extend(env::split_paths(&paths).filter(|p| !p.as_os_str().is_empty()));
+        }
+        if let Some(home) = env::var_os("HOME") {
+            search_path.push(PathBuf::from(home).join(".lake").join("plugins"));
+        }
+        search_path
+    }
+}
+
+/// Location of the per-user configuration, following the XDG base directory spec
+pub fn user_config_path() -> Option<PathBuf> {
+    let config_dir = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("lake").join("config.toml"))
+}
+
+/// Read a configuration file, if it exists
+fn read_config(path: &Path) -> Result<Option<toml::Table>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+
+    let content =
+        fs::read_to_string(path).context(format!("Failed to read configuration {:?}", path))?;
+    let mut table: toml::Table =
+        toml::from_str(&content).context(format!("Invalid configuration in {:?}", path))?;
+
+    // Check each file on its own, so errors name the file they come from
+    toml::Value::Table(table.clone())
+        .try_into::<Config>()
+        .context(format!("Invalid configuration in {:?}", path))?;
+
+    // Plugin paths and granted paths are relative to the file that declares them
+    let base = path.parent().unwrap_or(Path::new("."));
+    resolve_paths(table.get_mut("plugin_paths"), base);
+    if let Some(toml::Value::Table(allow)) = table.get_mut("allow") {
+        resolve_paths(allow.get_mut("read"), base);
+        resolve_paths(allow.get_mut("write"), base);
+    }
+
+    log::debug!("Loaded configuration {:?}", path);
+    Ok(Some(table))
+}
+
+/// Make the paths of a list relative to `base`
+fn resolve_paths(list: Option<&mut toml::Value>, base: &Path) {
+    if let Some(toml::Value::Array(paths)) = list {
+        for entry in paths.iter_mut() {
+            if let toml::Value::String(path) = entry {
+                *path = base.join(&*path).to_string_lossy().to_string();
+            }
+        }
+    }
+}
+
+/// Merge `overlay` into `base`, recursing into tables present in both
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base_table)), toml::Value::Table(overlay_table)) => {
+                merge_tables(base_table, overlay_table)
+            }
+            (_, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::permissions::{Grant, Permissions};
+
+    use super::*;
+
+    #[test]
+    fn user_configuration_overrides_the_project() {
+        let dir = tempfile::tempdir().unwrap();
+        let project_config = dir.path().join("project").join(PROJECT_CONFIG);
+        let user_config = dir.path().join("user").join("config.toml");
+        fs::create_dir_all(project_config.parent().unwrap()).unwrap();
+        fs::create_dir_all(user_config.parent().unwrap()).unwrap();
+        fs::write(
+            &project_config,
+            "jobs = 2\ndefault_task = \"build\"\nplugin_paths = [\"tools\"]\n[env]\nCC = \"gcc\"\nMODE = \"debug\"\n",
+        )
+        .unwrap();
+        fs::write(&user_config, "jobs = 8\n[env]\nCC = \"clang\"\n").unwrap();
+
+        let config = Config::load_files(&project_config, Some(&user_config)).unwrap();
+        assert_eq!(config.jobs, Some(8));
+        assert_eq!(config.default_task(), "build");
+        assert_eq!(config.env["CC"], "clang");
+        assert_eq!(config.env["MODE"], "debug");
+        let project_env: Vec<_> = config.project_env.keys().collect();
+        assert_eq!(project_env, ["MODE"]);
+        assert_eq!(
+            config.plugin_paths,
+            [dir.path().join("project").join("tools")]
+        );
+    }
+
+    #[test]
+    fn reports_the_invalid_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let project_config = dir.path().join(PROJECT_CONFIG);
+        fs::write(&project_config, "unknown = true\n").unwrap();
+
+        let error = match Config::load_files(&project_config, None) {
+            Ok(_) => panic!("unknown keys should be rejected"),
+            Err(error) => error,
+        };
+        assert!(format!("{:#}", error).contains("lake.toml"), "{:#}", error);
+        assert_eq!(Config::default().default_task(), "default");
+    }
+
+    #[test]
+    fn projects_request_permissions_without_granting_them() {
+        let dir = tempfile::tempdir().unwrap();
+        let project_config = dir.path().join(PROJECT_CONFIG);
+        let user_config = dir.path().join("config.toml");
+        fs::write(
+            &project_config,
+            "jobs = 2\n[allow]\nall = true\nrun = [\"npm\"]\n",
+        )
+        .unwrap();
+        fs::write(&user_config, "[allow]\nenv = [\"HOME\"]\n").unwrap();
+
+        let config = Config::load_files(&project_config, Some(&user_config)).unwrap();
+        assert_eq!(config.jobs, Some(2));
+        assert!(config.requested.all);
+        assert!(matches!(&config.requested.run, Grant::Only(run) if run == &["npm"]));
+        assert!(!config.allow.all);
+        assert!(matches!(config.allow.run, Grant::Any(false)));
+
+        let permissions = Permissions::new(&config.allow, dir.path());
+        assert!(permissions.check_env("HOME").is_ok());
+        assert_eq!(permissions.missing(&config.requested), ["--allow-all"]);
+    }
+}
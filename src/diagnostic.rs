@@ -0,0 +1,421 @@
+//! Error reports for Lake
+//!
+//! Lua errors raised by build files and tasks are kept as [`ScriptError`]s
+//! and rendered like compiler diagnostics: the message, its location with a
+//! snippet of the source, the Lua traceback and the chain of tasks that led
+//! to the failure.
+
+use std::fmt;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::PathBuf;
+
+use mlua::{Error as LuaError, Lua};
+
+/// A Lua error raised while loading a build file or running a task
+#[derive(Debug)]
+pub struct ScriptError {
+    message: String,
+    location: Option<Location>,
+    traceback: Vec<String>,
+    /// Directory the chunk names of build files are relative to
+    pub root: PathBuf,
+    /// Task that failed, preceded by the tasks that required it
+    pub tasks: Vec<String>,
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(f, "{}:{}: {}", location.file, location.line, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// Log an error, rendering Lua errors with their source and traceback
+pub fn report(err: &anyhow::Error) {
+    match err.downcast_ref::<ScriptError>() {
+        Some(script_error) => eprint!("{}", render(script_error, use_color())),
+        None => log::error!("Error: {:#}", err),
+    }
+}
+
+/// Position in a Lua source file
+#[derive(Debug)]
+struct Location {
+    file: String,
+    line: usize,
+}
+
+impl ScriptError {
+    /// Split a Lua error into its message, location and traceback
+    pub fn new(error: LuaError) -> Self {
+        // Errors of Rust callbacks carry the traceback of the Lua code that called them
+        let mut error = &error;
+        let mut traceback = None;
+        loop {
+            match error {
+                LuaError::CallbackError {
+                    traceback: callback_traceback,
+                    cause,
+                } => {
+                    traceback.get_or_insert(callback_traceback.as_str());
+                    error = cause;
+                }
+                LuaError::WithContext { cause, .. } => error = cause,
+                _ => break,
+            }
+        }
+
+        let text = match error {
+            LuaError::RuntimeError(message) => message.clone(),
+            LuaError::SyntaxError { message, .. } => message.clone(),
+            LuaError::MemoryError(message) => message.clone(),
+            other => other.to_string(),
+        };
+
+        // Errors raised in Lua have the traceback appended by the message handler
+        let (text, appended) = match text.split_once("\nstack traceback:\n") {
+            Some((text, appended)) => (text.to_string(), Some(appended.to_string())),
+            None => (text, None),
+        };
+        let traceback: Vec<String> = appended
+            .as_deref()
+            .or_else(|| traceback.and_then(|t| t.strip_prefix("stack traceback:\n")))
+            .unwrap_or_default()
+            .lines()
+            .map(str::trim)
+            .filter(|frame| !frame.is_empty() && *frame != "[C]: in ?")
+            .map(str::to_string)
+            .collect();
+
+        // The message starts with its location when raised from Lua,
+        // otherwise the first Lua frame of the traceback points at the call
+        let (location, message) = match split_location(&text) {
+            Some((location, message)) => (Some(location), message.to_string()),
+            None => (
+                traceback
+                    .iter()
+                    .find_map(|frame| split_location(frame).map(|(location, _)| location)),
+                text.clone(),
+            ),
+        };
+
+        ScriptError {
+            message,
+            location,
+            traceback,
+            root: PathBuf::new(),
+            tasks: Vec::new(),
+        }
+    }
+
+    /// Record the task the error was raised in
+    pub fn in_task(mut self, task: &str) -> Self {
+        self.tasks = vec![task.to_string()];
+        self
+    }
+}
+
+/// Render the stack of the running coroutine like Lua's own tracebacks,
+/// from `level` up to the first frame of the chunk named `until`
+pub fn traceback(lua: &Lua, level: usize, until: &str) -> String {
+    let mut frames = Vec::new();
+    let mut level = level;
+    let mut previous_is_c = false;
+    while let Some(frame) = lua.inspect_stack(level) {
+        let source = frame.source();
+        if source.source.as_deref() == Some(until) {
+            // Leave out the C function, such as `xpcall`, that `until` called
+            if previous_is_c {
+                frames.pop();
+            }
+            break;
+        }
+        previous_is_c = source.what == "C";
+        let names = frame.names();
+        let short_src = source.short_src.as_deref().unwrap_or("?");
+        let place = match frame.curr_line() {
+            line if line > 0 => format!("{}:{}:", short_src, line),
+            _ => format!("{}:", short_src),
+        };
+        let function = match (&names.name, names.name_what) {
+            (Some(name), Some("global") | None) => format!("function '{}'", name),
+            (Some(name), Some(what)) => format!("{} '{}'", what, name),
+            _ if source.what == "main" => "main chunk".to_string(),
+            _ if source.what == "C" => "?".to_string(),
+            _ => format!(
+                "function <{}:{}>",
+                short_src,
+                source.line_defined.unwrap_or_default()
+            ),
+        };
+        frames.push(format!("\t{} in {}", place, function));
+        level += 1;
+    }
+    format!("stack traceback:\n{}", frames.join("\n"))
+}
+
+/// Split `file:line: rest` into a location and the rest
+fn split_location(text: &str) -> Option<(Location, &str)> {
+    let mut search = 0;
+    while let Some(offset) = text[search..].find(':') {
+        let colon = search + offset;
+        let after = &text[colon + 1..];
+        let digits = after.chars().take_while(char::is_ascii_digit).count();
+        if digits > 0 && after[digits..].starts_with(':') && !text[..colon].starts_with('[') {
+            let location = Location {
+                file: text[..colon].to_string(),
+                line: after[..digits].parse().ok()?,
+            };
+            return Some((location, after[digits + 1..].trim_start()));
+        }
+        search = colon + 1;
+    }
+    None
+}
+
+/// ANSI styles of the report
+struct Style {
+    error: &'static str,
+    accent: &'static str,
+    bold: &'static str,
+    reset: &'static str,
+}
+
+fn use_color() -> bool {
+    std::env::var_os("NO_COLOR").is_none() && std::io::stderr().is_terminal()
+}
+
+/// Render a Lua error like a compiler diagnostic
+fn render(script_error: &ScriptError, color: bool) -> String {
+    let style = if color {
+        Style {
+            error: "\x1b[1;31m",
+            accent: "\x1b[1;34m",
+            bold: "\x1b[1m",
+            reset: "\x1b[0m",
+        }
+    } else {
+        Style {
+            error: "",
+            accent: "",
+            bold: "",
+            reset: "",
+        }
+    };
+    let mut lines = script_error.message.lines();
+    let mut out = format!(
+        "{}error{}{}: {}{}\n",
+        style.error,
+        style.reset,
+        style.bold,
+        lines.next().unwrap_or_default(),
+        style.reset
+    );
+    for line in lines {
+        out.push_str(&format!("  {}\n", line));
+    }
+
+    let source = script_error.location.as_ref().and_then(|location| {
+        let content = fs::read_to_string(script_error.root.join(&location.file)).ok()?;
+        let content = content.replace('\t', "    ");
+        let line = content
+            .lines()
+            .nth(location.line.checked_sub(1)?)?
+            .to_string();
+        Some((location, content, line))
+    });
+
+    let gutter = match &source {
+        Some((location, ..)) => " ".repeat((location.line + 1).to_string().len()),
+        None => " ".to_string(),
+    };
+
+    match (&script_error.location, &source) {
+        (_, Some((location, content, line))) => {
+            let (column, width) = highlight(line, &script_error.message, &script_error.traceback);
+            out.push_str(&format!(
+                "{}{}-->{} {}:{}:{}\n",
+                gutter,
+                style.accent,
+                style.reset,
+                location.file,
+                location.line,
+                column + 1
+            ));
+            out.push_str(&format!("{} {}|{}\n", gutter, style.accent, style.reset));
+
+            // One line of context on each side of the error
+            let first = location.line.saturating_sub(1).max(1);
+            for (index, text) in content.lines().enumerate().skip(first - 1).take(3) {
+                let number = index + 1;
+                if number > location.line && text.trim().is_empty() {
+                    break;
+                }
+                out.push_str(&format!(
+                    "{}{:>width$} |{} {}\n",
+                    style.accent,
+                    number,
+                    style.reset,
+                    text,
+                    width = gutter.len()
+                ));
+                if number == location.line {
+                    out.push_str(&format!(
+                        "{} {}|{} {}{}{}{}\n",
+                        gutter,
+                        style.accent,
+                        style.reset,
+                        " ".repeat(column),
+                        style.error,
+                        "^".repeat(width),
+                        style.reset
+                    ));
+                }
+            }
+            out.push_str(&format!("{} {}|{}\n", gutter, style.accent, style.reset));
+        }
+        (Some(location), None) => out.push_str(&format!(
+            "{}{}-->{} {}:{}\n",
+            gutter, style.accent, style.reset, location.file, location.line
+        )),
+        (None, None) => {}
+    }
+
+    if !script_error.traceback.is_empty() {
+        out.push_str(&format!(
+            "{} {}={} {}traceback{}:\n",
+            gutter, style.accent, style.reset, style.bold, style.reset
+        ));
+        for frame in &script_error.traceback {
+            out.push_str(&format!("{}     {}\n", gutter, frame));
+        }
+    }
+
+    if let Some(task) = script_error.tasks.last() {
+        out.push_str(&format!(
+            "{} {}={} {}task{}: '{}' failed",
+            gutter, style.accent, style.reset, style.bold, style.reset, task
+        ));
+        if let Some((_, required_by)) = script_error.tasks.split_last() {
+            if !required_by.is_empty() {
+                let required_by: Vec<String> = required_by
+                    .iter()
+                    .map(|task| format!("'{}'", task))
+                    .collect();
+                out.push_str(&format!(", required by {}", required_by.join(" -> ")));
+            }
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+/// Find the column and width to underline in a source line.
+///
+/// Lua only reports lines, so the name the message is about, or else the
+/// Rust function that failed, is looked up in the line. Without either the
+/// whole line is underlined.
+fn highlight(line: &str, message: &str, traceback: &[String]) -> (usize, usize) {
+    let callee = traceback
+        .first()
+        .filter(|frame| frame.starts_with("[C]:"))
+        .and_then(|frame| quoted_name(frame));
+    for name in [quoted_name(message), callee].into_iter().flatten() {
+        let is_word = |c: char| c.is_alphanumeric() || c == '_';
+        let found = line.match_indices(name).find(|(column, _)| {
+            let before = line[..*column].chars().next_back();
+            let after = line[column + name.len()..].chars().next();
+            let joins_before = before.is_some_and(is_word) && name.starts_with(is_word);
+            let joins_after = after.is_some_and(is_word) && name.ends_with(is_word);
+            !joins_before && !joins_after
+        });
+        if let Some((column, _)) = found {
+            return (line[..column].chars().count(), name.chars().count());
+        }
+    }
+
+    let trimmed = line.trim();
+    let column = line.len() - line.trim_start().len();
+    (column, trimmed.chars().count().max(1))
+}
+
+/// Name quoted by Lua in messages such as `attempt to call a nil value (global 'foo')`
+fn quoted_name(text: &str) -> Option<&str> {
+    [
+        "global", "field", "method", "local", "upvalue", "constant", "function", "near",
+    ]
+    .iter()
+    .find_map(|kind| {
+        let start = text.find(&format!("{} '", kind))? + kind.len() + 2;
+        let end = text[start..].find('\'')? + start;
+        Some(&text[start..end])
+    })
+    .filter(|name| !name.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn splits_lua_errors() {
+        let lua = Lua::new();
+        let error = lua
+            .load("local x = 1\nerror('broken')")
+            .set_name("@build.lake")
+            .exec()
+            .unwrap_err();
+        let script_error = ScriptError::new(error);
+        assert_eq!(script_error.to_string(), "build.lake:2: broken");
+
+        let error = ScriptError::new(LuaError::RuntimeError("no location".to_string()));
+        assert!(error.location.is_none());
+        assert_eq!(error.to_string(), "no location");
+    }
+
+    #[test]
+    fn renders_the_failing_line() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = "local x = 1\nmissing(x)\nprint(x)\n";
+        fs::write(dir.path().join("build.lake"), source).unwrap();
+
+        let lua = Lua::new();
+        let error = lua.load(source).set_name("@build.lake").exec().unwrap_err();
+        let mut script_error = ScriptError::new(error);
+        script_error.root = dir.path().to_path_buf();
+        script_error.tasks = vec!["default".to_string(), "build".to_string()];
+
+        let report = render(&script_error, false);
+        assert!(report.starts_with("error: attempt to call a nil value (global 'missing')\n"));
+        assert!(report.contains("--> build.lake:2:1\n"), "{}", report);
+        assert!(
+            report.contains("2 | missing(x)\n  | ^^^^^^^\n"),
+            "{}",
+            report
+        );
+        assert!(report.contains("3 | print(x)\n"), "{}", report);
+        assert!(
+            report.ends_with("task: 'build' failed, required by 'default'\n"),
+            "{}",
+            report
+        );
+    }
+
+    #[test]
+    fn highlights_the_quoted_name() {
+        let message = "attempt to index a nil value (field 'config')";
+        assert_eq!(
+            highlight("  local c = self.config.x", message, &[]),
+            (17, 6)
+        );
+        assert_eq!(highlight("  reconfigure()", message, &[]), (2, 13));
+        let traceback = ["[C]: in function 'fs.copy'".to_string()];
+        assert_eq!(highlight("fs.copy(a, b)", "failed", &traceback), (0, 7));
+    }
+}
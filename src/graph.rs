@@ -0,0 +1,306 @@
+//! Task graph for Lake
+//!
+//! Resolves task dependencies into an execution order.
+
+use std::collections::{BTreeMap, HashSet};
+use std::path::PathBuf;
+
+use anyhow::{bail, Result};
+use mlua::{Table, Value};
+
+use crate::params::Param;
+
+/// A task registered in `__lake_tasks`
+pub struct TaskInfo {
+    pub desc: Option<String>,
+    pub deps: Vec<String>,
+    pub params: Vec<Param>,
+    pub inputs: Vec<String>,
+    pub outputs: Vec<String>,
+    pub watch: Vec<String>,
+    /// Directory of the build file that defines the task, where it runs
+    pub dir: PathBuf,
+}
+
+/// Dependency graph of the tasks registered in `__lake_tasks`
+pub struct TaskGraph {
+    tasks: BTreeMap<String, TaskInfo>,
+}
+
+impl TaskGraph {
+    /// Build the graph from the Lua task registry
+    pub fn from_registry(registry: &Table) -> Result<Self> {
+        let mut tasks = BTreeMap::new();
+
+        for pair in registry.pairs::<String, Table>() {
+            let (name, entry) = pair.map_err(|e| anyhow::anyhow!("Invalid task entry: {}", e))?;
+
+            let deps = string_list(&entry, &name, "deps")?;
+            let inputs = string_list(&entry, &name, "inputs")?;
+            let outputs = string_list(&entry, &name, "outputs")?;
+            let watch = string_list(&entry, &name, "watch")?;
+
+            let desc = entry
+                .get::<Option<String>>("desc")
+                .map_err(|e| anyhow::anyhow!("Invalid description for task '{}': {}", name, e))?;
+
+            let dir = entry
+                .get::<Option<String>>("dir")
+                .map_err(|e| anyhow::anyhow!("Invalid directory for task '{}': {}", name, e))?
+                .map(PathBuf::from)
+                .unwrap_or_default();
+
+            let mut params = Vec::new();
+            if let Value::Table(params_table) = entry
+                .get::<Value>("params")
+                .map_err(|e| anyhow::anyhow!("Invalid parameters for task '{}': {}", name, e))?
+            {
+                for param in params_table.sequence_values::<Table>() {
+                    let param = param.map_err(|e| {
+                        anyhow::anyhow!("Invalid parameter for task '{}': {}", name, e)
+                    })?;
+                    params.push(Param::from_table(&name, &param)?);
+                }
+            }
+
+            tasks.insert(
+                name,
+                TaskInfo {
+                    desc,
+                    deps,
+                    params,
+                    inputs,
+                    outputs,
+                    watch,
+                    dir,
+                },
+            );
+        }
+
+        Ok(TaskGraph { tasks })
+    }
+
+    /// Iterate over all tasks, sorted by name
+    pub fn tasks(&self) -> impl Iterator<Item = (&String, &TaskInfo)> {
+        self.tasks.iter()
+    }
+
+    /// Check if a task is registered
+    pub fn contains(&self, name: &str) -> bool {
+        self.tasks.contains_key(name)
+    }
+
+    /// Get a registered task
+    pub fn get(&self, name: &str) -> Option<&TaskInfo> {
+        self.tasks.get(name)
+    }
+
+    /// Get the description of a task
+    pub fn description(&self, name: &str) -> Option<&str> {
+        self.tasks.get(name).and_then(|task| task.desc.as_deref())
+    }
+
+    /// Get the parameters declared by a task
+    pub fn params(&self, name: &str) -> &[Param] {
+        self.tasks
+            .get(name)
+            .map(|task| task.params.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Find how `name` is reached from `targets`, as the list of tasks from a
+    /// target down to `name`
+    pub fn dependency_chain(&self, targets: &[&str], name: &str) -> Vec<String> {
+        fn find(graph: &TaskGraph, task: &str, name: &str, chain: &mut Vec<String>) -> bool {
+            if chain.iter().any(|visited| visited == task) {
+                return false;
+            }
+            chain.push(task.to_string());
+            if task == name
+                || graph
+                    .dependencies(task)
+                    .iter()
+                    .any(|dep| find(graph, dep, name, chain))
+            {
+                return true;
+            }
+            chain.pop();
+            false
+        }
+
+        let mut chain = Vec::new();
+        for target in targets {
+            if find(self, target, name, &mut chain) {
+                return chain;
+            }
+        }
+        vec![name.to_string()]
+    }
+
+    /// Get the direct dependencies of a task
+    pub fn dependencies(&self, name: &str) -> &[String] {
+        self.tasks
+            .get(name)
+            .map(|task| task.deps.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Resolve the tasks needed to run `targets`, in topological order.
+    ///
+    /// Every task appears exactly once, after all of its dependencies.
+    pub fn resolve(&self, targets: &[&str]) -> Result<Vec<String>> {
+        let mut order = Vec::new();
+        let mut visited = HashSet::new();
+        let mut stack = Vec::new();
+
+        for target in targets {
+            self.visit(target, None, &mut visited, &mut stack, &mut order)?;
+        }
+
+        Ok(order)
+    }
+
+    fn visit(
+        &self,
+        name: &str,
+        required_by: Option<&str>,
+        visited: &mut HashSet<String>,
+        stack: &mut Vec<String>,
+        order: &mut Vec<String>,
+    ) -> Result<()> {
+        if visited.contains(name) {
+            return Ok(());
+        }
+
+        // A task already on the stack means we walked back into it
+        if let Some(start) = stack.iter().position(|task| task == name) {
+            let mut cycle = stack[start..].to_vec();
+            cycle.push(name.to_string());
+            bail!("Dependency cycle detected: {}", cycle.join(" -> "));
+        }
+
+        let Some(task) = self.tasks.get(name) else {
+            match required_by {
+                Some(parent) => bail!(
+                    "Task '{}' depends on '{}', which is not defined in build.lake",
+                    parent,
+                    name
+                ),
+                None => bail!("Task '{}' not found in build.lake", name),
+            }
+        };
+
+        stack.push(name.to_string());
+        for dep in &task.deps {
+            self.visit(dep, Some(name), visited, stack, order)?;
+        }
+        stack.pop();
+
+        visited.insert(name.to_string());
+        order.push(name.to_string());
+        Ok(())
+    }
+}
+
+/// Read a list of strings such as `deps` from a registry entry
+fn string_list(entry: &Table, task_name: &str, key: &str) -> Result<Vec<String>> {
+    let mut list = Vec::new();
+    if let Value::Table(table) = entry
+        .get::<Value>(key)
+        .map_err(|e| anyhow::anyhow!("Invalid {} for task '{}': {}", key, task_name, e))?
+    {
+        for value in table.sequence_values::<String>() {
+            list.push(
+                value.map_err(|e| {
+                    anyhow::anyhow!("Invalid {} for task '{}': {}", key, task_name, e)
+                })?,
+            );
+        }
+    }
+    Ok(list)
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::Lua;
+
+    use super::*;
+
+    fn graph(registry: &str) -> Result<TaskGraph> {
+        let lua = Lua::new();
+        let registry: Table = lua.load(registry).eval().unwrap();
+        TaskGraph::from_registry(&registry)
+    }
+
+    #[test]
+    fn resolves_dependencies_before_dependents() {
+        let graph = graph(
+            r#"return {
+                build = { deps = { "compile", "assets" } },
+                compile = { deps = { "generate" } },
+                assets = { deps = { "generate" } },
+                generate = {},
+            }"#,
+        )
+        .unwrap();
+
+        let order = graph.resolve(&["build"]).unwrap();
+        assert_eq!(order, ["generate", "compile", "assets", "build"]);
+    }
+
+    #[test]
+    fn resolves_shared_dependencies_once() {
+        let graph = graph(
+            r#"return {
+                a = { deps = { "shared" } },
+                b = { deps = { "shared" } },
+                shared = {},
+            }"#,
+        )
+        .unwrap();
+
+        let order = graph.resolve(&["a", "b"]).unwrap();
+        assert_eq!(order, ["shared", "a", "b"]);
+    }
+
+    #[test]
+    fn reports_cycles() {
+        let graph = graph(
+            r#"return {
+                a = { deps = { "b" } },
+                b = { deps = { "c" } },
+                c = { deps = { "a" } },
+            }"#,
+        )
+        .unwrap();
+
+        let error = graph.resolve(&["a"]).unwrap_err().to_string();
+        assert_eq!(error, "Dependency cycle detected: a -> b -> c -> a");
+    }
+
+    #[test]
+    fn reports_missing_tasks() {
+        let graph = graph(r#"return { build = { deps = { "missing" } } }"#).unwrap();
+
+        let error = graph.resolve(&["build"]).unwrap_err().to_string();
+        assert!(error.contains("depends on 'missing'"), "{}", error);
+
+        let error = graph.resolve(&["other"]).unwrap_err().to_string();
+        assert!(error.contains("Task 'other' not found"), "{}", error);
+    }
+
+    #[test]
+    fn finds_dependency_chain() {
+        let graph = graph(
+            r#"return {
+                build = { deps = { "compile" } },
+                compile = { deps = { "generate" } },
+                generate = {},
+            }"#,
+        )
+        .unwrap();
+
+        let chain = graph.dependency_chain(&["build"], "generate");
+        assert_eq!(chain, ["build", "compile", "generate"]);
+    }
+}
@@ -0,0 +1,276 @@
+//! Build scripts split across several files
+//!
+//! `include(path)` evaluates another .lake file into the current namespace,
+//! while `import(path, [namespace])` loads a sub-project whose tasks are
+//! prefixed with its namespace, as in `core:build`. Every file is evaluated
+//! with its own directory as the working directory, and its tasks run there.
+
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use mlua::{Error as LuaError, Lua, Result as LuaResult};
+
+use crate::permissions;
+
+/// Separator between a namespace and a task name
+const NAMESPACE_SEPARATOR: char = ':';
+
+/// A build file being evaluated
+struct Scope {
+    file: PathBuf,
+    dir: PathBuf,
+    namespace: String,
+}
+
+/// Build files being evaluated, innermost last
+#[derive(Default)]
+struct ScopeStack(Vec<Scope>);
+
+/// Every build file evaluated into a Lua state, in load order
+#[derive(Default)]
+pub struct LoadedFiles(pub Vec<PathBuf>);
+
+/// Evaluate the root build file of a project
+pub fn exec_root(lua: &Lua, path: &Path) -> LuaResult<()> {
+    lua.set_app_data(ScopeStack::default());
+    lua.set_app_data(LoadedFiles::default());
+    exec_file(lua, path, String::new())
+}
+
+/// Evaluate a build file into the namespace of the file including it
+pub fn include(lua: &Lua, path: &str) -> LuaResult<()> {
+    let file = resolve_build_file(lua, path)?;
+    exec_file(lua, &file, current_namespace(lua))
+}
+
+/// Evaluate a sub-project under its own namespace, by default the name of its directory
+pub fn import(lua: &Lua, path: &str, namespace: Option<String>) -> LuaResult<()> {
+    let file = resolve_build_file(lua, path)?;
+    let namespace = match namespace {
+        Some(namespace) => namespace,
+        None => default_namespace(&file).ok_or_else(|| {
+            LuaError::RuntimeError(format!(
+                "Cannot derive a namespace from {:?}, pass one as import(path, namespace)",
+                path
+            ))
+        })?,
+    };
+
+    if namespace.is_empty() || namespace.contains(NAMESPACE_SEPARATOR) {
+        return Err(LuaError::RuntimeError(format!(
+            "Invalid namespace {:?}: must be non-empty and cannot contain '{}'",
+            namespace, NAMESPACE_SEPARATOR
+        )));
+    }
+
+    exec_file(lua, &file, qualify(&current_namespace(lua), &namespace))
+}
+
+/// Namespace of the build file being evaluated, empty for the root project
+pub fn current_namespace(lua: &Lua) -> String {
+    lua.app_data_ref::<ScopeStack>()
+        .and_then(|stack| stack.0.last().map(|scope| scope.namespace.clone()))
+        .unwrap_or_default()
+}
+
+/// Directory of the build file being evaluated
+pub fn current_dir(lua: &Lua) -> LuaResult<PathBuf> {
+    let dir = lua
+        .app_data_ref::<ScopeStack>()
+        .and_then(|stack| stack.0.last().map(|scope| scope.dir.clone()));
+    match dir {
+        Some(dir) => Ok(dir),
+        None => Ok(env::current_dir()?),
+    }
+}
+
+/// Prefix a task name with a namespace
+pub fn qualify(namespace: &str, name: &str) -> String {
+    if namespace.is_empty() {
+        name.to_string()
+    } else {
+        format!("{}{}{}", namespace, NAMESPACE_SEPARATOR, name)
+    }
+}
+
+/// Resolve a dependency declared in a namespace.
+///
+/// Names are relative to the namespace of the declaring file, a leading `:`
+/// refers to a task of the root project.
+pub fn qualify_dependency(namespace: &str, dep: &str) -> String {
+    match dep.strip_prefix(NAMESPACE_SEPARATOR) {
+        Some(absolute) => absolute.to_string(),
+        None => qualify(namespace, dep),
+    }
+}
+
+/// Evaluate a build file from its own directory
+fn exec_file(lua: &Lua, file: &Path, namespace: String) -> LuaResult<()> {
+    let chunk_name = {
+        let stack = lua.app_data_ref::<ScopeStack>();
+        let stack = stack
+            .as_ref()
+            .map(|stack| stack.0.as_slice())
+            .unwrap_or_default();
+
+        if let Some(start) = stack.iter().position(|scope| scope.file == file) {
+            let mut cycle: Vec<String> = stack[start..]
+                .iter()
+                .map(|scope| scope.file.display().to_string())
+                .collect();
+            cycle.push(file.display().to_string());
+            return Err(LuaError::RuntimeError(format!(
+                "Include cycle detected: {}",
+                cycle.join(" -> ")
+            )));
+        }
+
+        // Name chunks relative to the root project, so errors point at the real file
+        let relative = match stack.first() {
+            Some(root) => file.strip_prefix(&root.dir).unwrap_or(file),
+            None => Path::new(file.file_name().unwrap_or(file.as_os_str())),
+        };
+        format!("@{}", relative.display())
+    };
+
+    let content = fs::read_to_string(file)
+        .map_err(|e| LuaError::RuntimeError(format!("Failed to read {:?}: {}", file, e)))?;
+    let dir = file.parent().unwrap_or(Path::new("/")).to_path_buf();
+
+    let previous_dir = env::current_dir()?;
+    env::set_current_dir(&dir).map_err(|e| {
+        LuaError::RuntimeError(format!(
+            "Failed to set working directory to {:?}: {}",
+            dir, e
+        ))
+    })?;
+
+    if let Some(mut loaded) = lua.app_data_mut::<LoadedFiles>() {
+        loaded.0.push(file.to_path_buf());
+    }
+    if let Some(mut stack) = lua.app_data_mut::<ScopeStack>() {
+        stack.0.push(Scope {
+            file: file.to_path_buf(),
+            dir,
+            namespace,
+        });
+    }
+    log::debug!("Loading build file {:?}", file);
+
+    let result = lua.load(content).set_name(chunk_name).exec();
+
+    if let Some(mut stack) = lua.app_data_mut::<ScopeStack>() {
+        stack.0.pop();
+    }
+    env::set_current_dir(&previous_dir)?;
+
+    result
+}
+
+/// Resolve the build file of an include, either a .lake file or a directory containing a build.lake
+fn resolve_build_file(lua: &Lua, path: &str) -> LuaResult<PathBuf> {
+    let mut file = PathBuf::from(path);
+    if file.is_dir() {
+        file.push("build.lake");
+    }
+    permissions::check_read(lua, &file)?;
+
+    file.canonicalize()
+        .map_err(|e| LuaError::RuntimeError(format!("Failed to include {:?}: {}", path, e)))
+}
+
+/// Name a sub-project after its directory, or after the file for other .lake files
+fn default_namespace(file: &Path) -> Option<String> {
+    let name = if file.file_name()? == "build.lake" {
+        file.parent()?.file_name()?
+    } else {
+        file.file_stem()?
+    };
+    Some(name.to_string_lossy().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lake::Project;
+    use crate::permissions::{Allow, Grant};
+    use crate::test_support::{lock_cwd, options};
+
+    use super::*;
+
+    #[test]
+    fn qualifies_names_and_dependencies() {
+        assert_eq!(qualify("", "build"), "build");
+        assert_eq!(qualify("core", "build"), "core:build");
+        assert_eq!(qualify_dependency("core", "gen"), "core:gen");
+        assert_eq!(qualify_dependency("core", ":lint"), "lint");
+        assert_eq!(qualify_dependency("", "gen"), "gen");
+    }
+
+    #[test]
+    fn imports_sub_projects_under_their_namespace() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().canonicalize().unwrap();
+        fs::create_dir(root.join("core")).unwrap();
+        fs::write(
+            root.join("core/build.lake"),
+            "task('gen', function() end)\ntask('build', { deps = { 'gen', ':lint' } }, function() end)",
+        )
+        .unwrap();
+        fs::write(root.join("common.lake"), "task('lint', function() end)").unwrap();
+        let build_file = root.join("build.lake");
+        fs::write(&build_file, "include('common.lake')\nimport('core')").unwrap();
+
+        let project = Project::load(&build_file, &options(&root, &Allow::default())).unwrap();
+        let tasks: mlua::Table = project.lua.globals().get("__lake_tasks").unwrap();
+        let mut names: Vec<String> = tasks
+            .pairs::<String, mlua::Value>()
+            .map(|p| p.unwrap().0)
+            .collect();
+        names.sort();
+        assert_eq!(names, ["core:build", "core:gen", "lint"]);
+
+        let build: mlua::Table = tasks.get("core:build").unwrap();
+        assert_eq!(
+            build.get::<Vec<String>>("deps").unwrap(),
+            ["core:gen", "lint"]
+        );
+        let dir: String = build.get("dir").unwrap();
+        assert_eq!(Path::new(&dir), root.join("core"));
+
+        fs::write(&build_file, "import('core', 'a:b')").unwrap();
+        let invalid = Project::load(&build_file, &options(&root, &Allow::default()));
+        let message = format!("{:#}", invalid.err().unwrap());
+        assert!(message.contains("Invalid namespace \"a:b\""), "{}", message);
+    }
+
+    #[test]
+    fn includes_outside_the_project_need_read_permission() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let project_dir = dir.path().join("project");
+        fs::create_dir(&project_dir).unwrap();
+        fs::write(
+            dir.path().join("outside.lake"),
+            "task('outside', function() end)",
+        )
+        .unwrap();
+        let build_file = project_dir.join("build.lake");
+        fs::write(&build_file, "include('../outside.lake')").unwrap();
+
+        let denied = Project::load(&build_file, &options(&project_dir, &Allow::default()));
+        let message = format!("{:#}", denied.err().unwrap());
+        assert!(
+            message.contains("permission denied: needs --allow-read="),
+            "{}",
+            message
+        );
+
+        let allow = Allow {
+            read: Grant::Only(vec![dir.path().to_string_lossy().to_string()]),
+            ..Allow::default()
+        };
+        assert!(Project::load(&build_file, &options(&project_dir, &allow)).is_ok());
+    }
+}
@@ -0,0 +1,373 @@
+//! Incremental builds for Lake
+//!
+//! Fingerprints the declared inputs and outputs of a task, together with its
+//! arguments and body, so tasks whose fingerprint is unchanged can be skipped.
+//!
+//! The body is fingerprinted from the bytecode of the task function alone:
+//! the values of its upvalues and the helper functions it calls are not part
+//! of it, so changing them does not rerun the task.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use mlua::{Function, Table};
+use sha2::{Digest, Sha256};
+
+use crate::params::TaskArgs;
+
+/// Directory holding the persisted task fingerprints
+const STATE_DIR: &str = ".lake/tasks";
+
+/// Whether a task needs to run
+pub enum Freshness {
+    UpToDate,
+    Stale(String),
+}
+
+/// Content hashes describing one run of a task
+#[derive(Default)]
+pub struct Fingerprint {
+    body: String,
+    args: String,
+    inputs: BTreeMap<String, String>,
+    outputs: BTreeMap<String, String>,
+}
+
+/// Incremental state of a task that declares inputs or outputs
+pub struct TaskState {
+    name: String,
+    input_patterns: Vec<String>,
+    output_patterns: Vec<String>,
+    current: Fingerprint,
+}
+
+impl TaskState {
+    /// Fingerprint a task from its registry entry.
+    ///
+    /// Returns `None` for tasks without declared inputs or outputs, which always run.
+    pub fn from_entry(name: &str, entry: &Table, args: &TaskArgs) -> Result<Option<Self>> {
+        let input_patterns: Vec<String> = entry
+            .get("inputs")
+            .map_err(|e| anyhow::anyhow!("Invalid inputs for task '{}': {}", name, e))?;
+        let output_patterns: Vec<String> = entry
+            .get("outputs")
+            .map_err(|e| anyhow::anyhow!("Invalid outputs for task '{}': {}", name, e))?;
+
+        if input_patterns.is_empty() && output_patterns.is_empty() {
+            return Ok(None);
+        }
+
+        let task: Function = entry
+            .get("run")
+            .map_err(|e| anyhow::anyhow!("Failed to get task '{}': {}", name, e))?;
+
+        let current = Fingerprint {
+            body: hash_bytes(&task.dump(true)),
+            args: hash_bytes(args.fingerprint().as_bytes()),
+            inputs: hash_patterns(&input_patterns)?,
+            outputs: hash_patterns(&output_patterns)?,
+        };
+
+        Ok(Some(TaskState {
+            name: name.to_string(),
+            input_patterns,
+            output_patterns,
+            current,
+        }))
+    }
+
+    /// Compare the current fingerprint with the one saved by the last successful run
+    pub fn freshness(&self) -> Result<Freshness> {
+        let Some(previous) = Fingerprint::load(&self.state_path())? else {
+            return Ok(Freshness::Stale("no previous build state".to_string()));
+        };
+
+        if previous.body != self.current.body {
+            return Ok(Freshness::Stale("task body changed".to_string()));
+        }
+        if previous.args != self.current.args {
+            return Ok(Freshness::Stale("arguments changed".to_string()));
+        }
+
+        let changed = changed_paths(&previous.inputs, &self.current.inputs);
+        if !changed.is_empty() {
+            return Ok(Freshness::Stale(format!(
+                "inputs changed: {}",
+                changed.join(", ")
+            )));
+        }
+
+        let changed = changed_paths(&previous.outputs, &self.current.outputs);
+        if !changed.is_empty() {
+            return Ok(Freshness::Stale(format!(
+                "outputs changed or missing: {}",
+                changed.join(", ")
+            )));
+        }
+
+        Ok(Freshness::UpToDate)
+    }
+
+    /// Persist the fingerprint after a successful run
+    pub fn save(mut self) -> Result<()> {
+        // Inputs and outputs may have been written by the task itself
+        self.current.inputs = hash_patterns(&self.input_patterns)?;
+        self.current.outputs = hash_patterns(&self.output_patterns)?;
+
+        let path = self.state_path();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .context(format!("Failed to create state directory {:?}", parent))?;
+        }
+        fs::write(&path, self.current.serialize())
+            .context(format!("Failed to write build state {:?}", path))
+    }
+
+    fn state_path(&self) -> PathBuf {
+        // Keep the file name portable, task names may contain separators. The
+        // hash of the name tells apart names that only differ by those.
+        let readable: String = self
+            .name
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        let hash = hash_bytes(self.name.as_bytes());
+        Path::new(STATE_DIR).join(format!("{}-{}.state", readable, &hash[..16]))
+    }
+}
+
+impl Fingerprint {
+    /// Load a saved fingerprint, if any
+    fn load(path: &Path) -> Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+
+        let content =
+            fs::read_to_string(path).context(format!("Failed to read build state {:?}", path))?;
+
+        let mut fingerprint = Fingerprint::default();
+        for line in content.lines() {
+            let mut parts = line.splitn(3, ' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some("body"), Some(hash), None) => fingerprint.body = hash.to_string(),
+                (Some("args"), Some(hash), None) => fingerprint.args = hash.to_string(),
+                (Some("input"), Some(hash), Some(path)) => {
+                    fingerprint
+                        .inputs
+                        .insert(path.to_string(), hash.to_string());
+                }
+                (Some("output"), Some(hash), Some(path)) => {
+                    fingerprint
+                        .outputs
+                        .insert(path.to_string(), hash.to_string());
+                }
+                _ => {
+                    // Unreadable state is treated as missing, forcing a rerun
+                    log::debug!("Ignoring corrupt build state {:?}", path);
+                    return Ok(None);
+                }
+            }
+        }
+
+        Ok(Some(fingerprint))
+    }
+
+    fn serialize(&self) -> String {
+        let mut content = format!("body {}\nargs {}\n", self.body, self.args);
+        for (path, hash) in &self.inputs {
+            content.push_str(&format!("input {} {}\n", hash, path));
+        }
+        for (path, hash) in &self.outputs {
+            content.push_str(&format!("output {} {}\n", hash, path));
+        }
+        content
+    }
+}
+
+/// Resolve glob patterns and hash every matching path
+fn hash_patterns(patterns: &[String]) -> Result<BTreeMap<String, String>> {
+    let mut hashes = BTreeMap::new();
+
+    for pattern in patterns {
+        let entries =
+            glob::glob(pattern).context(format!("Invalid glob pattern: {:?}", pattern))?;
+
+        for entry in entries {
+            let path = entry.context(format!("Error in glob pattern {:?}", pattern))?;
+            let hash = if path.is_dir() {
+                hash_dir(&path)?
+            } else {
+                hash_file(&path)?
+            };
+            hashes.insert(path.to_string_lossy().to_string(), hash);
+        }
+    }
+
+    Ok(hashes)
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    let mut file = fs::File::open(path).context(format!("Failed to open {:?}", path))?;
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut file, &mut hasher).context(format!("Failed to read {:?}", path))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+/// Hash the names, types and contents of everything inside a directory
+fn hash_dir(path: &Path) -> Result<String> {
+    fn visit(dir: &Path, prefix: &str, hasher: &mut Sha256) -> Result<()> {
+        let mut entries = fs::read_dir(dir)
+            .context(format!("Failed to read directory {:?}", dir))?
+            .collect::<std::io::Result<Vec<_>>>()
+            .context(format!("Failed to read directory {:?}", dir))?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in entries {
+            let path = entry.path();
+            let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+            let file_type = entry
+                .file_type()
+                .context(format!("Failed to read {:?}", path))?;
+            // Links are not followed, their target is part of the content
+            let line = if file_type.is_symlink() {
+                let target = fs::read_link(&path).context(format!("Failed to read {:?}", path))?;
+                format!("link {} {}\n", name, target.to_string_lossy())
+            } else if file_type.is_dir() {
+                format!("dir {}\n", name)
+            } else {
+                format!("file {} {}\n", name, hash_file(&path)?)
+            };
+            hasher.update(line.as_bytes());
+
+            if file_type.is_dir() {
+                visit(&path, &format!("{}/", name), hasher)?;
+            }
+        }
+        Ok(())
+    }
+
+    let mut hasher = Sha256::new();
+    visit(path, "", &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn hash_bytes(bytes: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(bytes))
+}
+
+/// Paths that were added, removed or modified between two sets of hashes
+fn changed_paths(
+    previous: &BTreeMap<String, String>,
+    current: &BTreeMap<String, String>,
+) -> Vec<String> {
+    let mut changed: Vec<String> = current
+        .iter()
+        .filter(|(path, hash)| previous.get(*path) != Some(hash))
+        .map(|(path, _)| path.clone())
+        .collect();
+    changed.extend(
+        previous
+            .keys()
+            .filter(|path| !current.contains_key(*path))
+            .cloned(),
+    );
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::Lua;
+
+    use crate::test_support::lock_cwd;
+
+    use super::*;
+
+    /// Fingerprint a task with the given options, in the current directory
+    fn state(name: &str, options: &str, args: &[&str]) -> TaskState {
+        let lua = Lua::new();
+        let entry: Table = lua
+            .load(format!("return {{ run = function() end, {} }}", options))
+            .eval()
+            .unwrap();
+        let args = TaskArgs::Positional(args.iter().map(|arg| arg.to_string()).collect());
+        TaskState::from_entry(name, &entry, &args).unwrap().unwrap()
+    }
+
+    fn is_up_to_date(state: &TaskState) -> bool {
+        matches!(state.freshness().unwrap(), Freshness::UpToDate)
+    }
+
+    #[test]
+    fn skips_unchanged_tasks_and_reruns_changed_ones() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(dir.path()).unwrap();
+        let options = r#"inputs = { "src/*" }, outputs = { "out.txt" }"#;
+        fs::create_dir("src").unwrap();
+        fs::write("src/main.c", "int main;").unwrap();
+
+        let first = state("build", options, &[]);
+        assert!(!is_up_to_date(&first));
+        fs::write("out.txt", "built").unwrap();
+        first.save().unwrap();
+        assert!(is_up_to_date(&state("build", options, &[])));
+
+        // Changed input
+        fs::write("src/main.c", "int main();").unwrap();
+        let changed = state("build", options, &[]);
+        match changed.freshness().unwrap() {
+            Freshness::Stale(reason) => assert_eq!(reason, "inputs changed: src/main.c"),
+            Freshness::UpToDate => panic!("changed input not detected"),
+        }
+        changed.save().unwrap();
+
+        // Changed arguments
+        assert!(!is_up_to_date(&state("build", options, &["--release"])));
+
+        // Missing output
+        fs::remove_file("out.txt").unwrap();
+        assert!(!is_up_to_date(&state("build", options, &[])));
+    }
+
+    #[test]
+    fn hashes_directory_contents() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(dir.path()).unwrap();
+        let options = r#"inputs = { "assets" }, outputs = {}"#;
+        fs::create_dir_all("assets/images").unwrap();
+        fs::write("assets/images/logo.svg", "<svg/>").unwrap();
+
+        state("copy", options, &[]).save().unwrap();
+        assert!(is_up_to_date(&state("copy", options, &[])));
+
+        fs::write("assets/images/logo.svg", "<svg></svg>").unwrap();
+        assert!(!is_up_to_date(&state("copy", options, &[])));
+    }
+
+    #[test]
+    fn keeps_names_with_separators_apart() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        std::env::set_current_dir(dir.path()).unwrap();
+        let options = r#"inputs = {}, outputs = { "out.txt" }"#;
+        fs::write("out.txt", "built").unwrap();
+
+        let namespaced = state("core:build", options, &[]);
+        let plain = state("core_build", options, &[]);
+        assert_ne!(namespaced.state_path(), plain.state_path());
+
+        namespaced.save().unwrap();
+        assert!(is_up_to_date(&state("core:build", options, &[])));
+        assert!(!is_up_to_date(&state("core_build", options, &[])));
+    }
+}
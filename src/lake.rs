@@ -1,12 +1,23 @@
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::env;
-use std::fs;
 use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
 
 use anyhow::{bail, Context, Result};
-use mlua::{Function, Lua, LuaOptions, StdLib, Table, Value};
+use mlua::{
+    Error as LuaError, Function, Lua, LuaOptions, MultiValue, StdLib, Table, Thread, ThreadStatus,
+    Value,
+};
 
-use crate::plugins;
-use crate::sandbox;
+use crate::diagnostic::{self, ScriptError};
+use crate::graph::TaskGraph;
+use crate::include::{self, LoadedFiles};
+use crate::incremental::{Freshness, TaskState};
+use crate::params::{self, Invocation, TaskArgs};
+use crate::plugins::{self, PluginOptions};
+use crate::sandbox::{self, CurrentTask, OutputPrefix, TaskThread};
 
 /// Find the build.lake in the current directory or parent directories
 pub fn find_build_file() -> Result<PathBuf> {
@@ -24,14 +35,290 @@ pub fn find_build_file() -> Result<PathBuf> {
     }
 }
 
-/// Run the Lake build system with the specified build.lake and task
-pub fn run_lake(build_file_path: &Path, task_name: &str, task_args: &[&str]) -> Result<()> {
-    if !build_file_path.exists() {
-        bail!("build.lake not found at {:?}", build_file_path);
+/// Run the Lake build system with the specified build.lake and command line.
+///
+/// `command_line` lists the tasks to run, in order, each followed by its
+/// `--name=value` parameters. Every task runs at most once, after its
+/// dependencies. `task_args` are passed to the requested tasks that declare
+/// no parameters.
+pub fn run_lake(
+    build_file_path: &Path,
+    options: &PluginOptions,
+    command_line: &[String],
+    task_args: &[String],
+    jobs: usize,
+) -> Result<()> {
+    Project::load(build_file_path, options)?.run(command_line, task_args, jobs)
+}
+
+/// Chunk name of the code running tasks alongside others
+const TASK_CHUNK: &str = "=[lake task]";
+
+/// Calls a task function in its coroutine, returning whether it succeeded
+/// and the error with the traceback of the task
+const TASK_SOURCE: &str = r#"
+local handler = ...
+local xpcall = xpcall
+return function(run, args)
+    return xpcall(run, handler, args)
+end
+"#;
+
+/// How long to wait before resuming tasks that all wait for commands
+const POLL_INTERVAL: Duration = Duration::from_millis(5);
+
+/// A build.lake evaluated into a Lua state, along with its task graph
+pub struct Project {
+    pub(crate) lua: Lua,
+    build_file: PathBuf,
+    files: Vec<PathBuf>,
+    task_registry: Table,
+    graph: TaskGraph,
+    options: PluginOptions,
+}
+
+impl Project {
+    /// Read the build.lake, move into its directory and evaluate it
+    pub fn load(build_file_path: &Path, options: &PluginOptions) -> Result<Self> {
+        if !build_file_path.exists() {
+            bail!("build.lake not found at {:?}", build_file_path);
+        }
+
+        // Included files are loaded after moving into the project directory
+        let build_file = build_file_path.canonicalize().context(format!(
+            "Failed to resolve build.lake path {:?}",
+            build_file_path
+        ))?;
+
+        // Get current directory for relative paths
+        let current_dir = build_file.parent().unwrap_or(Path::new("/"));
+        env::set_current_dir(current_dir).context(format!(
+            "Failed to set working directory to {:?}",
+            current_dir
+        ))?;
+
+        let lua = create_lua(&build_file, options)?;
+        let task_registry = get_task_registry(&lua)?;
+        let graph = TaskGraph::from_registry(&task_registry)?;
+        let files = lua
+            .app_data_ref::<LoadedFiles>()
+            .map(|loaded| loaded.0.clone())
+            .unwrap_or_default();
+
+        Ok(Project {
+            lua,
+            build_file,
+            files,
+            task_registry,
+            graph,
+            options: options.clone(),
+        })
+    }
+
+    /// Get the task graph of the project
+    pub fn graph(&self) -> &TaskGraph {
+        &self.graph
     }
 
-    // Initialize Lua with safe defaults
-    let lua = Lua::new_with(StdLib::ALL_SAFE, LuaOptions::new().catch_rust_panics(true))
+    /// Get the build files of the project, the build.lake and every file it includes
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    /// Run the tasks of a command line, see [`run_lake`]
+    pub fn run(&self, command_line: &[String], task_args: &[String], jobs: usize) -> Result<()> {
+        // `lake help <task>` prints the usage of a task, unless the script defines its own
+        if command_line.first().map(String::as_str) == Some("help") && !self.graph.contains("help")
+        {
+            return print_task_help(&self.graph, &command_line[1..]);
+        }
+
+        let invocations = self.invocations(command_line);
+
+        // Validate the parameters of the requested tasks
+        let mut args: HashMap<String, TaskArgs> = HashMap::new();
+        for invocation in &invocations {
+            if !self.graph.contains(&invocation.task) {
+                bail!("Task '{}' not found in build.lake", invocation.task);
+            }
+            if args.contains_key(&invocation.task) {
+                bail!(
+                    "Task '{}' is requested more than once, it only runs once per invocation",
+                    invocation.task
+                );
+            }
+            let parsed =
+                params::parse_args(&self.graph, &invocation.task, &invocation.tokens, task_args)?;
+            args.insert(invocation.task.clone(), parsed);
+        }
+
+        // Resolve the execution order
+        let targets: Vec<&str> = invocations.iter().map(|i| i.task.as_str()).collect();
+        let order = self.graph.resolve(&targets)?;
+        log::debug!("Execution order: {}", order.join(", "));
+
+        // Dependencies run with their default parameters
+        for name in &order {
+            if !args.contains_key(name) {
+                let parsed = params::parse_args(&self.graph, name, &[], &[])
+                    .context(format!("Invalid parameters for dependency '{}'", name))?;
+                args.insert(name.clone(), parsed);
+            }
+        }
+
+        // Execute the requested tasks along with their dependencies
+        let result = if jobs > 1 && order.len() > 1 {
+            execute_parallel(
+                &self.lua,
+                &self.task_registry,
+                &self.graph,
+                &order,
+                jobs,
+                &targets,
+                &args,
+                self.options.dry_run,
+            )
+        } else {
+            execute_sequential(
+                &self.lua,
+                &self.task_registry,
+                &self.graph,
+                &order,
+                &targets,
+                &args,
+                self.options.dry_run,
+            )
+        };
+
+        // Commands spawned by the tasks do not outlive the build
+        plugins::reap_children(&self.lua);
+
+        // Tasks of included files move into their own directory
+        if let Some(dir) = self.build_file.parent() {
+            env::set_current_dir(dir)
+                .context(format!("Failed to set working directory to {:?}", dir))?;
+        }
+
+        // Report which of the requested tasks needed the failed one
+        result.map_err(|mut err| {
+            if let Some(script_error) = err.downcast_mut::<ScriptError>() {
+                if let Some(task) = script_error.tasks.last().cloned() {
+                    script_error.tasks = self.graph.dependency_chain(&targets, &task);
+                }
+                script_error.root = self.build_file.parent().unwrap_or(Path::new("/")).into();
+            }
+            err
+        })
+    }
+
+    /// Resolve the tasks a command line runs, in execution order
+    pub fn resolve(&self, command_line: &[String]) -> Result<Vec<String>> {
+        let invocations = self.invocations(command_line);
+        let targets: Vec<&str> = invocations.iter().map(|i| i.task.as_str()).collect();
+        self.graph.resolve(&targets)
+    }
+
+    /// Split a command line into task invocations, falling back to the configured default task
+    fn invocations(&self, command_line: &[String]) -> Vec<Invocation> {
+        let mut invocations = params::split_invocations(&self.graph, command_line);
+        if invocations.is_empty() {
+            invocations.push(Invocation {
+                task: self.options.config.default_task().to_string(),
+                tokens: Vec::new(),
+            });
+        }
+        invocations
+    }
+}
+
+/// Print the usage of the given tasks, or list all tasks when none are given
+fn print_task_help(graph: &TaskGraph, tasks: &[String]) -> Result<()> {
+    if tasks.is_empty() {
+        print!("{}", task_list(graph));
+        return Ok(());
+    }
+
+    for task_name in tasks {
+        if !graph.contains(task_name) {
+            bail!("Task '{}' not found in build.lake", task_name);
+        }
+        println!("{}", params::command(graph, task_name).render_help());
+    }
+
+    Ok(())
+}
+
+/// Print every task registered in the build.lake with its description and dependencies
+pub fn list_tasks(build_file_path: &Path, options: &PluginOptions, json: bool) -> Result<()> {
+    let project = Project::load(build_file_path, options)?;
+    let graph = project.graph();
+
+    if json {
+        println!("{}", serde_json::to_string_pretty(&task_list_json(graph))?);
+        return Ok(());
+    }
+
+    print!("{}", task_list(graph));
+    Ok(())
+}
+
+/// Describe the tasks for `--list --json`, sorted by name
+fn task_list_json(graph: &TaskGraph) -> serde_json::Value {
+    graph
+        .tasks()
+        .map(|(name, task)| {
+            serde_json::json!({
+                "name": name,
+                "desc": task.desc,
+                "deps": task.deps,
+            })
+        })
+        .collect()
+}
+
+/// Render the task names, descriptions and dependencies in aligned columns
+fn task_list(graph: &TaskGraph) -> String {
+    // Align names and descriptions in columns
+    let name_width = graph.tasks().map(|(name, _)| name.len()).max().unwrap_or(0);
+    let desc_width = graph
+        .tasks()
+        .map(|(_, task)| task.desc.as_deref().map_or(0, str::len))
+        .max()
+        .unwrap_or(0);
+
+    let mut list = "Available tasks:\n".to_string();
+    for (name, task) in graph.tasks() {
+        let desc = task.desc.as_deref().unwrap_or("");
+        let mut line = format!("  {:<name_width$}  {:<desc_width$}", name, desc);
+        if !task.deps.is_empty() {
+            line.push_str(&format!("  deps: {}", task.deps.join(", ")));
+        }
+        list.push_str(line.trim_end());
+        list.push('\n');
+    }
+    list
+}
+
+/// Get the task registry from a Lua state
+fn get_task_registry(lua: &Lua) -> Result<Table> {
+    lua.globals().get("__lake_tasks").map_err(|e| {
+        anyhow::anyhow!(
+            "Task registry not found. Did you define any tasks? Error: {}",
+            e
+        )
+    })
+}
+
+/// Create a Lua state with the sandbox and core plugins, and evaluate the build.lake
+fn create_lua(build_file: &Path, options: &PluginOptions) -> Result<Lua> {
+    // Scripts reach files, processes and packages only through the core plugins
+    let libs = StdLib::COROUTINE
+        | StdLib::TABLE
+        | StdLib::STRING
+        | StdLib::UTF8
+        | StdLib::MATH
+        | StdLib::OS;
+    let lua = Lua::new_with(libs, LuaOptions::new().catch_rust_panics(true))
         .map_err(|e| anyhow::anyhow!("Failed to initialize Lua: {}", e))?;
 
     // Create a sandbox
@@ -39,70 +326,619 @@ pub fn run_lake(build_file_path: &Path, task_name: &str, task_args: &[&str]) ->
         .map_err(|e| anyhow::anyhow!("Failed to create sandbox: {}", e))?;
 
     // Register core plugins
-    plugins::register_all(&lua)
+    plugins::register_all(&lua, options)
         .map_err(|e| anyhow::anyhow!("Failed to register core plugins: {}", e))?;
 
-    // Load the build.lake
-    let build_file_content = fs::read_to_string(build_file_path).context(format!(
-        "Failed to read build.lake at {:?}",
-        build_file_path
-    ))?;
-
-    // Get current directory for relative paths
-    let current_dir = build_file_path.parent().unwrap_or(Path::new("."));
-    env::set_current_dir(current_dir).context(format!(
-        "Failed to set working directory to {:?}",
-        current_dir
-    ))?;
-
-    // Execute the build.lake
-    lua.load(&build_file_content)
-        .set_name("build.lake")
-        .exec()
-        .map_err(|e| anyhow::anyhow!("Failed to execute build.lake: {}", e))?;
-
-    // Execute the requested task
-    execute_task(&lua, task_name, task_args)
-        .context(format!("Failed to execute task '{}'", task_name))?;
+    // Execute the build.lake, along with the files it includes
+    include::exec_root(&lua, build_file).map_err(|e| {
+        let mut script_error = ScriptError::new(e);
+        script_error.root = build_file.parent().unwrap_or(Path::new("/")).into();
+        anyhow::Error::new(script_error).context("Failed to execute build.lake")
+    })?;
+
+    Ok(lua)
+}
+
+/// Execute the resolved tasks one after another in a single Lua state
+fn execute_sequential(
+    lua: &Lua,
+    task_registry: &Table,
+    graph: &TaskGraph,
+    order: &[String],
+    targets: &[&str],
+    args: &HashMap<String, TaskArgs>,
+    dry_run: bool,
+) -> Result<()> {
+    let predecessors = predecessors(graph, order, targets);
+    for (name, waited_for) in order.iter().zip(&predecessors) {
+        let waited_for: Vec<&str> = waited_for.iter().map(|&i| order[i].as_str()).collect();
+        plugins::inherit_env(lua, name, &waited_for);
+        run_task(lua, task_registry, name, &args[name], dry_run)
+            .context(format!("Failed to execute task '{}'", name))?;
+    }
 
     Ok(())
 }
 
-/// Execute a task from the build.lake
-fn execute_task(lua: &Lua, task_name: &str, args: &[&str]) -> Result<()> {
-    let globals = lua.globals();
+/// Execute the resolved tasks, up to `jobs` of them at the same time.
+///
+/// Tasks share the Lua state of the build, each running in a coroutine. A
+/// task yields to the others while it waits for a command started by
+/// `lake.process`, so only commands run in parallel and the tasks see the
+/// same globals as when they run one after another. A task starts as soon as
+/// all of its dependencies, and the task named before it in `targets`, have
+/// finished. After the first failure no new tasks are started.
+#[allow(clippy::too_many_arguments)]
+fn execute_parallel(
+    lua: &Lua,
+    task_registry: &Table,
+    graph: &TaskGraph,
+    order: &[String],
+    jobs: usize,
+    targets: &[&str],
+    args: &HashMap<String, TaskArgs>,
+    dry_run: bool,
+) -> Result<()> {
+    let handler = lua
+        .create_function(|lua, error: Value| {
+            // Level 0 is the handler, level 1 the function that raised the error
+            let traceback = diagnostic::traceback(lua, 1, TASK_CHUNK);
+            let message = match error {
+                // Errors of Rust functions carry their traceback, like mlua does
+                #[allow(clippy::arc_with_non_send_sync)]
+                Value::Error(error) => {
+                    return Ok(Value::Error(Box::new(LuaError::CallbackError {
+                        traceback,
+                        cause: Arc::new(*error),
+                    })))
+                }
+                Value::String(message) => message.to_string_lossy(),
+                other => other.to_string()?,
+            };
+            lua.create_string(format!("{}\n{}", message, traceback))
+                .map(Value::String)
+        })
+        .map_err(|e| anyhow::anyhow!("Failed to create the task runner: {}", e))?;
+    let runner: Function = lua
+        .load(TASK_SOURCE)
+        .set_name(TASK_CHUNK)
+        .call(handler)
+        .map_err(|e| anyhow::anyhow!("Failed to create the task runner: {}", e))?;
 
-    // Get the task registry
-    let task_registry: Table = globals.get("__lake_tasks").map_err(|e| {
-        anyhow::anyhow!(
-            "Task registry not found. Did you define any tasks? Error: {}",
-            e
-        )
-    })?;
+    let mut schedule = Schedule::new(graph, order, targets);
+    let mut running: Vec<RunningTask> = Vec::new();
+
+    loop {
+        // Keep at most `jobs` tasks in flight
+        let mut progressed = false;
+        while running.len() < jobs {
+            let Some(index) = schedule.next() else {
+                break;
+            };
+            let name = &order[index];
+            log::debug!("Starting task: {}", name);
+            progressed = true;
+            plugins::inherit_env(lua, name, &schedule.waited_for(index));
+
+            let started = prepare_task(lua, task_registry, name, &args[name]).and_then(|task| {
+                let Some(task) = task else {
+                    return Ok(None);
+                };
+                let thread = lua
+                    .create_thread(runner.clone())
+                    .map_err(|e| anyhow::anyhow!("Failed to start task '{}': {}", name, e))?;
+                Ok(Some(RunningTask {
+                    index,
+                    thread,
+                    task,
+                    started: false,
+                }))
+            });
+            match started {
+                Ok(Some(task)) => running.push(task),
+                Ok(None) => schedule.complete(index, Ok(())),
+                Err(err) => schedule.complete(index, Err(err)),
+            }
+        }
+
+        if running.is_empty() {
+            break;
+        }
+
+        // Let every task run until it waits for a command or finishes
+        let mut waiting = Vec::with_capacity(running.len());
+        for mut task in running {
+            match task.resume(lua, &order[task.index], dry_run) {
+                Some(result) => {
+                    schedule.complete(task.index, result);
+                    progressed = true;
+                }
+                None => waiting.push(task),
+            }
+        }
+        running = waiting;
+
+        if !progressed {
+            thread::sleep(POLL_INTERVAL);
+        }
+    }
+
+    schedule.finish()
+}
+
+/// Tasks each task waits for, by their position in `order`: its dependencies
+/// and, to keep the command line order between requested tasks, the target
+/// named before it. A target and the dependencies it pulls in wait for the
+/// target named before it, unless that one already ran as a dependency of
+/// an earlier target.
+fn predecessors(graph: &TaskGraph, order: &[String], targets: &[&str]) -> Vec<Vec<usize>> {
+    let position = |name: &str| order.iter().position(|n| n == name).unwrap_or_default();
+    let mut predecessors: Vec<Vec<usize>> = order
+        .iter()
+        .map(|name| {
+            graph
+                .dependencies(name)
+                .iter()
+                .map(|dep| position(dep))
+                .collect()
+        })
+        .collect();
+    for pair in targets.windows(2) {
+        let (previous, next) = (position(pair[0]), position(pair[1]));
+        if previous < next {
+            for waiting in &mut predecessors[previous + 1..=next] {
+                waiting.push(previous);
+            }
+        }
+    }
+    predecessors
+}
+
+/// Order in which tasks may start, as their dependencies finish
+struct Schedule<'a> {
+    order: &'a [String],
+    /// Tasks each task waits for
+    predecessors: Vec<Vec<usize>>,
+    /// Unfinished dependencies of each task
+    pending_deps: Vec<usize>,
+    /// Tasks waiting for each task
+    dependents: HashMap<&'a str, Vec<usize>>,
+    ready: VecDeque<usize>,
+    finished: HashSet<&'a str>,
+    failure: Option<anyhow::Error>,
+}
+
+impl<'a> Schedule<'a> {
+    fn new(graph: &'a TaskGraph, order: &'a [String], targets: &[&str]) -> Self {
+        // Count unfinished predecessors and index the reverse edges
+        let predecessors = predecessors(graph, order, targets);
+        let mut pending_deps: Vec<usize> = Vec::with_capacity(order.len());
+        let mut dependents: HashMap<&str, Vec<usize>> = HashMap::new();
+        for (index, waited_for) in predecessors.iter().enumerate() {
+            pending_deps.push(waited_for.len());
+            for &previous in waited_for {
+                dependents
+                    .entry(order[previous].as_str())
+                    .or_default()
+                    .push(index);
+            }
+        }
 
-    // Check if the task exists
-    let task_exists = task_registry
-        .contains_key(task_name)
-        .map_err(|e| anyhow::anyhow!("Failed to check if task '{}' exists: {}", task_name, e))?;
+        let ready = (0..order.len())
+            .filter(|&index| pending_deps[index] == 0)
+            .collect();
 
-    if !task_exists {
-        bail!("Task '{}' not found in build.lake", task_name);
+        Schedule {
+            order,
+            predecessors,
+            pending_deps,
+            dependents,
+            ready,
+            finished: HashSet::new(),
+            failure: None,
+        }
+    }
+
+    /// Names of the tasks a task waited for
+    fn waited_for(&self, index: usize) -> Vec<&'a str> {
+        self.predecessors[index]
+            .iter()
+            .map(|&previous| self.order[previous].as_str())
+            .collect()
+    }
+
+    /// Next task to start, none after a failure
+    fn next(&mut self) -> Option<usize> {
+        match self.failure {
+            Some(_) => None,
+            None => self.ready.pop_front(),
+        }
     }
 
+    /// Record the outcome of a task, making the tasks waiting for it ready
+    fn complete(&mut self, index: usize, result: Result<()>) {
+        let name = self.order[index].as_str();
+        self.finished.insert(name);
+
+        match result {
+            Ok(()) => {
+                for &dependent in self.dependents.get(name).into_iter().flatten() {
+                    self.pending_deps[dependent] -= 1;
+                    if self.pending_deps[dependent] == 0 {
+                        self.ready.push_back(dependent);
+                    }
+                }
+            }
+            Err(err) => {
+                let err = err.context(format!("Failed to execute task '{}'", name));
+                if self.failure.is_none() {
+                    self.failure = Some(err);
+                } else {
+                    log::error!("{:#}", err);
+                }
+            }
+        }
+    }
+
+    /// Return the first failure, after reporting the tasks it cancelled
+    fn finish(self) -> Result<()> {
+        let Some(err) = self.failure else {
+            return Ok(());
+        };
+        let cancelled: Vec<&str> = self
+            .order
+            .iter()
+            .map(String::as_str)
+            .filter(|name| !self.finished.contains(name))
+            .collect();
+        if !cancelled.is_empty() {
+            log::warn!("Cancelled pending tasks: {}", cancelled.join(", "));
+        }
+        Err(err)
+    }
+}
+
+/// A task running in its coroutine
+struct RunningTask {
+    index: usize,
+    thread: Thread,
+    task: PreparedTask,
+    started: bool,
+}
+
+impl RunningTask {
+    /// Run the task until it waits for a command, or return its result once it finished
+    fn resume(&mut self, lua: &Lua, name: &str, dry_run: bool) -> Option<Result<()>> {
+        if let Err(err) = self.task.enter() {
+            return Some(Err(err));
+        }
+
+        // Prefix everything the task prints with its name
+        lua.set_app_data(CurrentTask(name.to_string()));
+        lua.set_app_data(OutputPrefix(name.to_string()));
+        lua.set_app_data(TaskThread(self.thread.clone()));
+        let result = if self.started {
+            self.thread.resume::<MultiValue>(())
+        } else {
+            self.started = true;
+            self.thread
+                .resume::<MultiValue>((self.task.run.clone(), self.task.args.clone()))
+        };
+        lua.remove_app_data::<TaskThread>();
+        lua.remove_app_data::<OutputPrefix>();
+        lua.remove_app_data::<CurrentTask>();
+
+        let error = match result {
+            Ok(_) if self.thread.status() == ThreadStatus::Resumable => return None,
+            Ok(values) => {
+                let mut values = values.into_iter();
+                match (values.next(), values.next()) {
+                    (Some(Value::Boolean(true)), _) => None,
+                    (_, Some(Value::Error(error))) => Some(*error),
+                    (_, Some(Value::String(message))) => {
+                        Some(LuaError::RuntimeError(message.to_string_lossy()))
+                    }
+                    (_, other) => Some(LuaError::RuntimeError(format!(
+                        "Task '{}' failed: {:?}",
+                        name, other
+                    ))),
+                }
+            }
+            Err(error) => Some(error),
+        };
+
+        Some(match error {
+            Some(error) => Err(ScriptError::new(error).in_task(name).into()),
+            None => self.task.finish(dry_run),
+        })
+    }
+}
+
+/// A task about to run, in the directory of the build file that defines it
+struct PreparedTask {
+    run: Function,
+    args: Table,
+    dir: Option<String>,
+    /// Inputs and outputs recorded once the task succeeds
+    state: Option<TaskState>,
+}
+
+impl PreparedTask {
+    /// Move into the directory of the task
+    fn enter(&self) -> Result<()> {
+        if let Some(dir) = &self.dir {
+            env::set_current_dir(dir)
+                .context(format!("Failed to set working directory to {:?}", dir))?;
+        }
+        Ok(())
+    }
+
+    /// Record the state of a task that succeeded
+    fn finish(&mut self, dry_run: bool) -> Result<()> {
+        // A dry run did not produce the outputs, so it must not be recorded
+        if let Some(state) = self.state.take().filter(|_| !dry_run) {
+            self.enter()?;
+            state.save()?;
+        }
+        Ok(())
+    }
+}
+
+/// Get a task ready to run, or `None` when it is up to date
+fn prepare_task(
+    lua: &Lua,
+    task_registry: &Table,
+    task_name: &str,
+    args: &TaskArgs,
+) -> Result<Option<PreparedTask>> {
     // Get the task function
-    let task: Function = task_registry
+    let entry: Table = task_registry
         .get(task_name)
         .map_err(|e| anyhow::anyhow!("Failed to get task '{}': {}", task_name, e))?;
+    let run: Function = entry
+        .get("run")
+        .map_err(|e| anyhow::anyhow!("Failed to get task '{}': {}", task_name, e))?;
 
     // Convert arguments to Lua values
-    let lua_args: Vec<Value> = args
-        .iter()
-        .map(|&arg| Value::String(lua.create_string(arg).unwrap()))
-        .collect();
+    let lua_args = args
+        .to_lua(lua)
+        .map_err(|e| anyhow::anyhow!("Failed to convert arguments: {}", e))?;
+
+    // Run the task in the directory of the build file that defines it
+    let dir = entry.get::<Option<String>>("dir").ok().flatten();
+    let mut task = PreparedTask {
+        run,
+        args: lua_args,
+        dir,
+        state: None,
+    };
+    task.enter()?;
+
+    // Skip the task when its declared inputs and outputs are unchanged
+    task.state = TaskState::from_entry(task_name, &entry, args)?;
+    if let Some(state) = &task.state {
+        match state.freshness()? {
+            Freshness::UpToDate => {
+                log::info!("Skipping task '{}': up to date", task_name);
+                return Ok(None);
+            }
+            Freshness::Stale(reason) => log::info!("Running task '{}': {}", task_name, reason),
+        }
+    }
+
+    log::debug!("Running task: {}", task_name);
+    Ok(Some(task))
+}
+
+/// Run a single task function from the registry
+fn run_task(
+    lua: &Lua,
+    task_registry: &Table,
+    task_name: &str,
+    args: &TaskArgs,
+    dry_run: bool,
+) -> Result<()> {
+    let Some(mut task) = prepare_task(lua, task_registry, task_name, args)? else {
+        return Ok(());
+    };
 
     // Execute the task
-    task.call::<()>(lua_args)
-        .map_err(|e| anyhow::anyhow!("Failed to execute task '{}': {}", task_name, e))?;
+    lua.set_app_data(CurrentTask(task_name.to_string()));
+    let result = task.run.call::<()>(&task.args);
+    lua.remove_app_data::<CurrentTask>();
+    result.map_err(|e| ScriptError::new(e).in_task(task_name))?;
 
-    Ok(())
+    task.finish(dry_run)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::permissions::{Allow, Grant};
+    use crate::test_support::{lock_cwd, options};
+
+    use super::*;
+
+    const BUILD_FILE: &str = r#"
+process = plugin("lake.process")
+env = plugin("lake.env")
+
+process.sh("echo loaded >> loads.txt")
+events = {}
+
+task("slow", function()
+    process.sh("sleep 0.5")
+    table.insert(events, "slow " .. tostring(env.get("LAKE_TEST_VAR")))
+end)
+task("fast", function()
+    process.sh("true")
+    table.insert(events, "fast")
+end)
+task("set", function()
+    shared = "shared"
+    env.set("LAKE_TEST_VAR", "set")
+    table.insert(events, "set " .. process.sh("echo $LAKE_TEST_VAR").stdout)
+end)
+task("get", { deps = { "set" } }, function()
+    table.insert(events, "get " .. shared .. " " .. tostring(env.get("LAKE_TEST_VAR")))
+end)
+task("all", { deps = { "slow", "fast", "get" } }, function()
+    table.insert(events, "all " .. #events)
+end)
+"#;
+
+    /// Run the `all` task and return the events the tasks recorded
+    fn run_all(jobs: usize) -> Vec<String> {
+        let dir = tempfile::tempdir().unwrap();
+        let build_file = dir.path().join("build.lake");
+        fs::write(&build_file, BUILD_FILE).unwrap();
+        let allow = Allow {
+            run: Grant::Any(true),
+            env: Grant::Any(true),
+            ..Allow::default()
+        };
+
+        let project = Project::load(&build_file, &options(dir.path(), &allow)).unwrap();
+        project.run(&["all".to_string()], &[], jobs).unwrap();
+
+        let loads = fs::read_to_string(dir.path().join("loads.txt")).unwrap();
+        assert_eq!(loads, "loaded\n", "the build file is evaluated once");
+
+        let events: Table = project.lua.globals().get("events").unwrap();
+        events
+            .sequence_values()
+            .collect::<mlua::Result<_>>()
+            .unwrap()
+    }
+
+    #[test]
+    fn parallel_tasks_share_the_build_state() {
+        let _cwd = lock_cwd();
+        let sequential = run_all(1);
+        let parallel = run_all(4);
+
+        assert_eq!(
+            sequential,
+            ["slow nil", "fast", "set set\n", "get shared set", "all 4"]
+        );
+        // Only the order of independent tasks changes, none of them waits for the slow one
+        let (quick, last) = parallel.split_at(3);
+        assert_eq!(last, ["slow nil", "all 4"]);
+        let mut quick = quick.to_vec();
+        quick.sort();
+        assert_eq!(quick, ["fast", "get shared set", "set set\n"]);
+        let position = |event: &str| parallel.iter().position(|e| e == event);
+        assert!(position("set set\n") < position("get shared set"));
+    }
+
+    #[test]
+    fn project_environment_is_only_passed_to_commands() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let build_file = dir.path().join("build.lake");
+        fs::write(
+            &build_file,
+            r#"output = plugin("lake.process").sh("echo $LAKE_TEST_GRANTED-$LAKE_TEST_DENIED").stdout"#,
+        )
+        .unwrap();
+        let allow = Allow {
+            run: Grant::Only(vec!["sh".to_string()]),
+            env: Grant::Only(vec!["LAKE_TEST_GRANTED".to_string()]),
+            ..Allow::default()
+        };
+        let mut options = options(dir.path(), &allow);
+        for name in ["LAKE_TEST_GRANTED", "LAKE_TEST_DENIED"] {
+            options
+                .config
+                .project_env
+                .insert(name.to_string(), "set".to_string());
+        }
+
+        let project = Project::load(&build_file, &options).unwrap();
+        let output: String = project.lua.globals().get("output").unwrap();
+        assert_eq!(output, "set-\n");
+        assert!(std::env::var_os("LAKE_TEST_GRANTED").is_none());
+    }
+
+    #[test]
+    fn dry_runs_only_report_side_effects() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let build_file = dir.path().join("build.lake");
+        fs::write(
+            &build_file,
+            r#"
+local fs = plugin("lake.fs")
+local process = plugin("lake.process")
+local net = plugin("lake.net")
+local env = plugin("lake.env")
+results = {}
+
+task("prepare", function()
+    table.insert(results, fs.write_file("out.txt", "written"))
+    table.insert(results, fs.rm("keep.txt"))
+    table.insert(results, net.download("http://127.0.0.1:9/file", "downloaded.bin"))
+    env.set("LAKE_DRY_RUN_VAR", "set")
+    local result = process.exec("sh", { "-c", "touch ran.txt" })
+    table.insert(results, result.status == 0 and result.stdout == "")
+end)
+task("build", { deps = { "prepare" }, inputs = { "src.txt" }, outputs = { "built.txt" } }, function()
+    table.insert(results, env.get("LAKE_DRY_RUN_VAR") == nil)
+    table.insert(results, process.sh("echo built > built.txt").status == 0)
+end)
+"#,
+        )
+        .unwrap();
+        fs::write(dir.path().join("keep.txt"), "kept").unwrap();
+        fs::write(dir.path().join("src.txt"), "source").unwrap();
+        let allow = Allow {
+            all: true,
+            ..Allow::default()
+        };
+        let mut options = options(dir.path(), &allow);
+        options.dry_run = true;
+
+        let project = Project::load(&build_file, &options).unwrap();
+        project.run(&["build".to_string()], &[], 1).unwrap();
+
+        // Every task body ran and got stub results
+        let results: Vec<bool> = project.lua.globals().get("results").unwrap();
+        assert_eq!(results, [true; 6]);
+        for created in ["out.txt", "downloaded.bin", "ran.txt", "built.txt", ".lake"] {
+            assert!(!dir.path().join(created).exists(), "{} exists", created);
+        }
+        assert!(dir.path().join("keep.txt").exists());
+        assert!(std::env::var_os("LAKE_DRY_RUN_VAR").is_none());
+    }
+
+    #[test]
+    fn lists_tasks_with_descriptions_and_dependencies() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let build_file = dir.path().join("build.lake");
+        fs::write(
+            &build_file,
+            r#"
+task("test", { desc = "Run the tests", deps = { "build", "lint" } }, function() end)
+task("build", { desc = "Compile" }, function() end)
+task("lint", function() end)
+"#,
+        )
+        .unwrap();
+
+        let project = Project::load(&build_file, &options(dir.path(), &Allow::default())).unwrap();
+        assert_eq!(
+            task_list_json(project.graph()),
+            serde_json::json!([
+                { "name": "build", "desc": "Compile", "deps": [] },
+                { "name": "lint", "desc": null, "deps": [] },
+                { "name": "test", "desc": "Run the tests", "deps": ["build", "lint"] },
+            ])
+        );
+        assert_eq!(
+            task_list(project.graph()),
+            "Available tasks:\n  build  Compile\n  lint\n  test   Run the tests  deps: build, lint\n"
+        );
+    }
 }
@@ -1,10 +1,25 @@
 use anyhow::{Context, Result};
-use clap::Parser;
-use std::path::PathBuf;
+use clap::{Parser, Subcommand};
+use std::path::{Path, PathBuf};
 
+mod config;
+mod diagnostic;
+mod graph;
+mod include;
+mod incremental;
 mod lake;
+mod packages;
+mod params;
+mod permissions;
 mod plugins;
 mod sandbox;
+#[cfg(test)]
+mod test_support;
+mod watch;
+
+use config::Config;
+use permissions::{Allow, Grant, Permissions};
+use plugins::PluginOptions;
 
 /// Lake - A universal build system with Lua scripting
 #[derive(Parser, Debug)]
@@ -14,23 +29,101 @@ struct Args {
     #[clap(short, long, value_name = "FILE")]
     file: Option<PathBuf>,
 
-    /// Task to execute
-    #[clap(value_name = "TASK")]
-    task: Option<String>,
+    /// Tasks to execute, in order, each followed by its --name=value parameters.
+    /// Arguments after `--` are passed to tasks without declared parameters.
+    #[clap(value_name = "TASKS", trailing_var_arg = true)]
+    tasks: Vec<String>,
+
+    /// List the tasks defined in the build.lake
+    #[clap(short, long)]
+    list: bool,
+
+    /// Print the task list as JSON
+    #[clap(long, requires = "list")]
+    json: bool,
+
+    /// Rerun the tasks whenever their inputs change
+    #[clap(short, long)]
+    watch: bool,
+
+    /// Print the side effects of the tasks instead of performing them
+    #[clap(short = 'n', long)]
+    dry_run: bool,
+
+    /// Number of tasks to run in parallel [default: `jobs` from lake.toml, or 1]
+    #[clap(short, long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Allow reading paths outside the project directory, or anywhere when given no paths
+    #[clap(long, value_name = "PATHS", num_args = 0..=1, require_equals = true, value_delimiter = ',')]
+    allow_read: Option<Vec<String>>,
+
+    /// Allow writing the given paths, or anywhere when given no paths
+    #[clap(long, value_name = "PATHS", num_args = 0..=1, require_equals = true, value_delimiter = ',')]
+    allow_write: Option<Vec<String>>,
+
+    /// Allow connecting to the given hosts, or to any host when given none
+    #[clap(long, value_name = "HOSTS", num_args = 0..=1, require_equals = true, value_delimiter = ',')]
+    allow_net: Option<Vec<String>>,
+
+    /// Allow running the given programs, or any program when given none
+    #[clap(long, value_name = "PROGRAMS", num_args = 0..=1, require_equals = true, value_delimiter = ',')]
+    allow_run: Option<Vec<String>>,
 
-    /// Arguments for the task
-    #[clap(value_name = "ARGS")]
-    args: Vec<String>,
+    /// Allow reading and setting the given environment variables, or all of them when given none
+    #[clap(long, value_name = "NAMES", num_args = 0..=1, require_equals = true, value_delimiter = ',')]
+    allow_env: Option<Vec<String>>,
+
+    /// Allow loading the given native plugins, or any native plugin when given none
+    #[clap(long, value_name = "NAMES", num_args = 0..=1, require_equals = true, value_delimiter = ',')]
+    allow_native: Option<Vec<String>>,
+
+    /// Grant every permission, disabling the sandbox
+    #[clap(short = 'A', long)]
+    allow_all: bool,
 
     /// Enable verbose logging (debug level)
     #[clap(short, long)]
     verbose: bool,
+
+    #[clap(subcommand)]
+    command: Option<LakeCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+enum LakeCommand {
+    /// Manage the plugin packages installed in .lake/plugins
+    #[clap(subcommand)]
+    Plugin(PluginCommand),
+}
+
+#[derive(Subcommand, Debug)]
+enum PluginCommand {
+    /// Fetch a plugin from a git URL (`url.git#ref`), a tarball URL or a local path
+    Add {
+        source: String,
+
+        /// Name of the plugin [default: derived from the source]
+        #[clap(long)]
+        name: Option<String>,
+    },
+    /// Fetch the latest version of the given plugins, or of all of them
+    Update { names: Vec<String> },
+    /// Remove a plugin
+    Remove { name: String },
+    /// Install the plugins recorded in lake.lock
+    Install,
 }
 
 /// Entry point for Lake build system
 fn main() {
     if let Err(err) = run() {
-        log::error!("Error: {:#}", err);
+        // Task parameter errors and help are reported like Lake's own options
+        if let Some(clap_err) = err.downcast_ref::<clap::Error>() {
+            clap_err.exit();
+        }
+
+        diagnostic::report(&err);
         std::process::exit(1);
     }
 }
@@ -39,34 +132,109 @@ fn run() -> Result<()> {
     // Parse command line arguments using clap derive
     let args = Args::parse();
 
-    // Setup the logger
-    setup_logger(args.verbose);
+    // The configuration sets the log level, so errors are reported once the logger is set up
+    let project = locate_project(args.file.clone());
+    let log_level = project
+        .as_ref()
+        .ok()
+        .and_then(|(_, config)| config.log_level.clone());
+    setup_logger(args.verbose, log_level.as_deref());
+    let (build_file_path, config) = project?;
 
-    // Get the build.lake path
-    let build_file_path = match args.file {
-        Some(path) => path,
-        None => lake::find_build_file().context("Could not find build.lake")?,
+    // Preload the configured environment before the build.lake runs
+    config.apply_env();
+
+    // Command line grants are added to the configured ones
+    let allowed = Allow {
+        all: args.allow_all,
+        read: Grant::from_flag(args.allow_read),
+        write: Grant::from_flag(args.allow_write),
+        net: Grant::from_flag(args.allow_net),
+        run: Grant::from_flag(args.allow_run),
+        env: Grant::from_flag(args.allow_env),
+        native: Grant::from_flag(args.allow_native),
+    };
+    // Resolve paths before the build.lake moves into its directory
+    let project_dir = match build_file_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => std::path::absolute(dir)?,
+        _ => std::env::current_dir()?,
+    };
+    let permissions = Permissions::new(&config.allow.union(&allowed), &project_dir);
+    log::debug!("Sandbox permissions: {}", permissions.describe());
+    let missing = permissions.missing(&config.requested);
+    if !missing.is_empty() {
+        log::warn!(
+            "The project requests permissions that are not granted: {}",
+            missing.join(" ")
+        );
+    }
+    let search_path = config.plugin_search_path(&project_dir);
+
+    if let Some(LakeCommand::Plugin(command)) = args.command {
+        return match command {
+            PluginCommand::Add { source, name } => {
+                packages::add(&project_dir, &source, name.as_deref())
+            }
+            PluginCommand::Update { names } => packages::update(&project_dir, &names),
+            PluginCommand::Remove { name } => packages::remove(&project_dir, &name),
+            PluginCommand::Install => packages::install(&project_dir),
+        };
+    }
+
+    let jobs = args.jobs.or(config.jobs).unwrap_or(1).max(1);
+    let options = PluginOptions {
+        dry_run: args.dry_run,
+        config,
+        permissions,
+        search_path,
+        project_dir,
+    };
+
+    if args.list {
+        return lake::list_tasks(&build_file_path, &options, args.json)
+            .context("Failed to list tasks");
+    }
+
+    // Split the tasks from the arguments given after `--`
+    let (tasks, task_args) = match args.tasks.iter().position(|arg| arg == "--") {
+        Some(index) => (&args.tasks[..index], &args.tasks[index + 1..]),
+        None => (&args.tasks[..], &[][..]),
     };
 
-    // Get task name and arguments
-    let task_name = args.task.unwrap_or_else(|| "default".to_string());
-    let task_args: Vec<&str> = args.args.iter().map(|s| s.as_str()).collect();
+    if args.watch {
+        return watch::watch(&build_file_path, &options, tasks, task_args, jobs);
+    }
 
-    // Initialize and run Lake engine with the specified build.lake and task
-    lake::run_lake(&build_file_path, &task_name, &task_args)
+    // Initialize and run Lake engine with the specified build.lake and tasks
+    lake::run_lake(&build_file_path, &options, tasks, task_args, jobs)
         .context("Failed to execute Lake build system")?;
 
     Ok(())
 }
 
-fn setup_logger(verbose: bool) {
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info");
-    }
+/// Find the build.lake and load the configuration of its project
+fn locate_project(file: Option<PathBuf>) -> Result<(PathBuf, Config)> {
+    // Get the build.lake path
+    let build_file_path = match file {
+        Some(path) => path,
+        None => lake::find_build_file().context("Could not find build.lake")?,
+    };
+
+    let project_dir = build_file_path.parent().unwrap_or(Path::new("."));
+    let config = Config::load(project_dir)?;
+
+    Ok((build_file_path, config))
+}
+
+/// Log at debug level with `--verbose`, otherwise follow `RUST_LOG`, then the configured level
+fn setup_logger(verbose: bool, log_level: Option<&str>) {
+    let default_level = log_level.unwrap_or("info");
+    let mut builder =
+        env_logger::Builder::from_env(env_logger::Env::default().default_filter_or(default_level));
 
     if verbose {
-        std::env::set_var("RUST_LOG", "debug");
+        builder.parse_filters("debug");
     }
 
-    env_logger::init();
+    builder.init();
 }
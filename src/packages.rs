@@ -0,0 +1,683 @@
+//! Plugin packages for Lake
+//!
+//! `lake plugin add <source>` fetches a Lua plugin from a git URL, a tarball
+//! URL or a local path into `.lake/plugins/<name>`, and records its source,
+//! resolved version and a sha256 of its files in `lake.lock`. Installed
+//! plugins are checked against the lockfile every time they are loaded.
+
+use std::fs;
+use std::io::Read;
+use std::path::{Component, Path, PathBuf};
+use std::process::Command;
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// Directory holding the installed plugins, relative to the project
+pub const PACKAGES_DIR: &str = ".lake/plugins";
+
+/// Lockfile recording the installed plugins, relative to the project
+const LOCK_FILE: &str = "lake.lock";
+
+/// Directory for plugins being fetched, relative to the project
+const STAGING_DIR: &str = ".lake/tmp";
+
+const LOCK_HEADER: &str = "# This file is generated by `lake plugin`, do not edit it by hand.\n\n";
+
+/// Contents of lake.lock
+#[derive(Default, Deserialize, Serialize)]
+struct LockFile {
+    #[serde(default, rename = "plugin")]
+    plugins: Vec<LockedPlugin>,
+}
+
+/// A plugin recorded in lake.lock
+#[derive(Clone, Deserialize, Serialize)]
+struct LockedPlugin {
+    name: String,
+    source: String,
+    /// Commit of git sources
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    version: Option<String>,
+    /// Hash of the installed files
+    sha256: String,
+}
+
+/// Where a plugin is fetched from
+enum Source {
+    /// A git repository, optionally at a branch, tag or commit given as `url#ref`
+    Git {
+        url: String,
+        reference: Option<String>,
+    },
+    /// A gzipped tarball
+    Tarball(String),
+    /// A directory, a .lua file or a tarball on disk, relative to the project
+    Path(PathBuf),
+}
+
+impl Source {
+    fn parse(source: &str, project_dir: &Path) -> Source {
+        let (location, reference) = match source.split_once('#') {
+            Some((location, reference)) => (location, Some(reference.to_string())),
+            None => (source, None),
+        };
+
+        if let Some(url) = location.strip_prefix("git+") {
+            return Source::Git {
+                url: url.to_string(),
+                reference,
+            };
+        }
+        if location.ends_with(".git") || location.starts_with("git@") {
+            return Source::Git {
+                url: location.to_string(),
+                reference,
+            };
+        }
+        if location.starts_with("http://") || location.starts_with("https://") {
+            return Source::Tarball(source.to_string());
+        }
+        Source::Path(project_dir.join(source))
+    }
+}
+
+/// Fetch a plugin and record it in lake.lock
+pub fn add(project_dir: &Path, source: &str, name: Option<&str>) -> Result<()> {
+    // Local paths are recorded relative to the project, so the lockfile can be shared
+    let source = match Source::parse(source, project_dir) {
+        Source::Path(_) => {
+            let absolute =
+                std::path::absolute(source).context(format!("Invalid plugin path {:?}", source))?;
+            relative_path(project_dir, &absolute)
+        }
+        _ => source.to_string(),
+    };
+
+    let name = match name {
+        Some(name) => name.to_string(),
+        None => default_name(&source).context(format!(
+            "Cannot derive a plugin name from {:?}, pass one with --name",
+            source
+        ))?,
+    };
+    validate_name(&name)?;
+
+    let mut lock = LockFile::load(project_dir)?;
+    if lock.plugins.iter().any(|plugin| plugin.name == name) {
+        bail!(
+            "Plugin '{}' is already added, use `lake plugin update {}` to fetch it again",
+            name,
+            name
+        );
+    }
+
+    let plugin = fetch(project_dir, &name, &source, None)?;
+    log::info!("Added plugin '{}' from {}", name, source);
+    lock.plugins.push(plugin);
+    lock.save(project_dir)
+}
+
+/// Fetch the latest version of plugins from their recorded sources, or of every plugin
+pub fn update(project_dir: &Path, names: &[String]) -> Result<()> {
+    let mut lock = LockFile::load(project_dir)?;
+    for name in names {
+        if !lock.plugins.iter().any(|plugin| plugin.name == *name) {
+            bail!("Plugin '{}' is not in {}", name, LOCK_FILE);
+        }
+    }
+
+    for plugin in &mut lock.plugins {
+        if !names.is_empty() && !names.contains(&plugin.name) {
+            continue;
+        }
+
+        let updated = fetch(project_dir, &plugin.name, &plugin.source, None)?;
+        if updated.sha256 == plugin.sha256 {
+            log::info!("Plugin '{}' is up to date", plugin.name);
+        } else {
+            log::info!("Updated plugin '{}'", plugin.name);
+        }
+        *plugin = updated;
+    }
+
+    lock.save(project_dir)
+}
+
+/// Remove an installed plugin and its lockfile entry
+pub fn remove(project_dir: &Path, name: &str) -> Result<()> {
+    let mut lock = LockFile::load(project_dir)?;
+    let count = lock.plugins.len();
+    lock.plugins.retain(|plugin| plugin.name != name);
+    if lock.plugins.len() == count {
+        bail!("Plugin '{}' is not in {}", name, LOCK_FILE);
+    }
+
+    let dir = project_dir.join(PACKAGES_DIR).join(name);
+    if dir.exists() {
+        fs::remove_dir_all(&dir).context(format!("Failed to remove {:?}", dir))?;
+    }
+
+    log::info!("Removed plugin '{}'", name);
+    lock.save(project_dir)
+}
+
+/// Install the plugins recorded in lake.lock that are missing or modified
+pub fn install(project_dir: &Path) -> Result<()> {
+    let lock = LockFile::load(project_dir)?;
+
+    for plugin in &lock.plugins {
+        let dir = project_dir.join(PACKAGES_DIR).join(&plugin.name);
+        if dir.exists() && hash_tree(&dir)? == plugin.sha256 {
+            log::debug!("Plugin '{}' is installed", plugin.name);
+            continue;
+        }
+
+        let fetched = fetch(
+            project_dir,
+            &plugin.name,
+            &plugin.source,
+            plugin.version.as_deref(),
+        )?;
+        if fetched.sha256 != plugin.sha256 {
+            // Do not leave a plugin that fails verification behind
+            let _ = fs::remove_dir_all(&dir);
+            bail!(
+                "Plugin '{}' from {} does not match {}: expected sha256 {}, got {}",
+                plugin.name,
+                plugin.source,
+                LOCK_FILE,
+                plugin.sha256,
+                fetched.sha256
+            );
+        }
+        log::info!("Installed plugin '{}'", plugin.name);
+    }
+
+    Ok(())
+}
+
+/// Check an installed plugin against its lockfile entry
+pub fn verify(project_dir: &Path, name: &str) -> Result<()> {
+    let lock = LockFile::load(project_dir)?;
+    let Some(plugin) = lock.plugins.iter().find(|plugin| plugin.name == name) else {
+        bail!(
+            "Plugin '{}' is installed in {} but not recorded in {}, add it with `lake plugin add`",
+            name,
+            PACKAGES_DIR,
+            LOCK_FILE
+        );
+    };
+
+    let actual = hash_tree(&project_dir.join(PACKAGES_DIR).join(name))?;
+    if actual != plugin.sha256 {
+        bail!(
+            "Plugin '{}' does not match {}: expected sha256 {}, got {}. Run `lake plugin install` to restore it",
+            name,
+            LOCK_FILE,
+            plugin.sha256,
+            actual
+        );
+    }
+
+    Ok(())
+}
+
+impl LockFile {
+    fn load(project_dir: &Path) -> Result<Self> {
+        let path = project_dir.join(LOCK_FILE);
+        if !path.exists() {
+            return Ok(LockFile::default());
+        }
+
+        let content = fs::read_to_string(&path).context(format!("Failed to read {:?}", path))?;
+        let lock: LockFile =
+            toml::from_str(&content).context(format!("Invalid lockfile {:?}", path))?;
+        // Names become directories below .lake/plugins
+        for plugin in &lock.plugins {
+            validate_name(&plugin.name).context(format!("Invalid lockfile {:?}", path))?;
+        }
+        Ok(lock)
+    }
+
+    fn save(&mut self, project_dir: &Path) -> Result<()> {
+        self.plugins.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let path = project_dir.join(LOCK_FILE);
+        let content = toml::to_string(self).context("Failed to serialize the lockfile")?;
+        fs::write(&path, format!("{}{}", LOCK_HEADER, content))
+            .context(format!("Failed to write {:?}", path))
+    }
+}
+
+/// Fetch a plugin into `.lake/plugins/<name>`, replacing any previous version.
+///
+/// `version` pins git sources to a commit.
+fn fetch(
+    project_dir: &Path,
+    name: &str,
+    source: &str,
+    version: Option<&str>,
+) -> Result<LockedPlugin> {
+    let staging = project_dir
+        .join(STAGING_DIR)
+        .join(uuid::Uuid::new_v4().to_string());
+    fs::create_dir_all(&staging).context(format!("Failed to create {:?}", staging))?;
+
+    let result = fetch_into(project_dir, name, source, version, &staging);
+    let _ = fs::remove_dir_all(&staging);
+    result
+}
+
+fn fetch_into(
+    project_dir: &Path,
+    name: &str,
+    source: &str,
+    version: Option<&str>,
+    staging: &Path,
+) -> Result<LockedPlugin> {
+    log::info!("Fetching plugin '{}' from {}", name, source);
+
+    let mut resolved_version = None;
+    match Source::parse(source, project_dir) {
+        Source::Git { url, reference } => {
+            // Sources come from lake.lock too, they must not pass as options
+            let reference = version.or(reference.as_deref());
+            validate_git_argument("URL", &url)?;
+            if let Some(reference) = reference {
+                validate_git_argument("reference", reference)?;
+            }
+
+            let checkout = staging.join("checkout");
+            git(
+                &["clone", "--quiet", "--", &url, &checkout.to_string_lossy()],
+                None,
+            )?;
+            if let Some(reference) = reference {
+                git(&["checkout", "--quiet", reference, "--"], Some(&checkout))?;
+            }
+            let commit = git(&["rev-parse", "HEAD"], Some(&checkout))?;
+            fs::remove_dir_all(checkout.join(".git"))
+                .context("Failed to remove the git metadata")?;
+            resolved_version = Some(commit);
+        }
+        Source::Tarball(url) => {
+            let response =
+                reqwest::blocking::get(&url).context(format!("Failed to download {}", url))?;
+            if !response.status().is_success() {
+                bail!("Failed to download {}: status {}", url, response.status());
+            }
+            let bytes = response
+                .bytes()
+                .context(format!("Failed to download {}", url))?;
+            unpack_tarball(&bytes[..], &staging.join("checkout"))
+                .context(format!("Failed to unpack {}", url))?;
+        }
+        Source::Path(path) => {
+            let checkout = staging.join("checkout");
+            if path.is_dir() {
+                copy_tree(&path, &checkout)?;
+            } else if path.extension().is_some_and(|ext| ext == "lua") {
+                fs::create_dir_all(&checkout)?;
+                fs::copy(&path, checkout.join("init.lua"))
+                    .context(format!("Failed to copy {:?}", path))?;
+            } else {
+                let file = fs::File::open(&path).context(format!("Failed to open {:?}", path))?;
+                unpack_tarball(file, &checkout).context(format!("Failed to unpack {:?}", path))?;
+            }
+        }
+    }
+
+    let root = package_root(&staging.join("checkout"))?;
+    if !root.join("init.lua").is_file() {
+        bail!("Plugin source {} has no init.lua", source);
+    }
+
+    let target = project_dir.join(PACKAGES_DIR).join(name);
+    if target.exists() {
+        fs::remove_dir_all(&target).context(format!("Failed to remove {:?}", target))?;
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).context(format!("Failed to create {:?}", parent))?;
+    }
+    fs::rename(&root, &target).context(format!("Failed to install into {:?}", target))?;
+
+    Ok(LockedPlugin {
+        name: name.to_string(),
+        source: source.to_string(),
+        version: resolved_version,
+        sha256: hash_tree(&target)?,
+    })
+}
+
+/// Run a git command and return its trimmed output
+fn git(args: &[&str], dir: Option<&Path>) -> Result<String> {
+    let mut command = Command::new("git");
+    // The ext transport runs arbitrary commands
+    command.args(["-c", "protocol.ext.allow=never"]).args(args);
+    if let Some(dir) = dir {
+        command.current_dir(dir);
+    }
+
+    let output = command
+        .output()
+        .context("Failed to run git, is it installed?")?;
+    if !output.status.success() {
+        bail!(
+            "git {} failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+fn unpack_tarball(reader: impl Read, dest: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(flate2::read::GzDecoder::new(reader));
+    archive.unpack(dest)?;
+    Ok(())
+}
+
+/// Tarballs usually wrap their files in a single directory, which is the plugin itself
+fn package_root(checkout: &Path) -> Result<PathBuf> {
+    if checkout.join("init.lua").exists() {
+        return Ok(checkout.to_path_buf());
+    }
+
+    let entries: Vec<PathBuf> = fs::read_dir(checkout)
+        .context(format!("Failed to read {:?}", checkout))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<std::io::Result<_>>()?;
+    match entries.as_slice() {
+        [single] if single.is_dir() => Ok(single.clone()),
+        _ => Ok(checkout.to_path_buf()),
+    }
+}
+
+/// Copy a directory, leaving out version control metadata
+fn copy_tree(src: &Path, dst: &Path) -> Result<()> {
+    fs::create_dir_all(dst).context(format!("Failed to create {:?}", dst))?;
+    for entry in fs::read_dir(src).context(format!("Failed to read {:?}", src))? {
+        let entry = entry?;
+        let path = entry.path();
+        if entry.file_name() == ".git" {
+            continue;
+        }
+
+        let target = dst.join(entry.file_name());
+        if path.is_dir() {
+            copy_tree(&path, &target)?;
+        } else {
+            fs::copy(&path, &target).context(format!("Failed to copy {:?}", path))?;
+        }
+    }
+    Ok(())
+}
+
+/// Hash the relative paths and contents of every file below `dir`
+fn hash_tree(dir: &Path) -> Result<String> {
+    let mut files = Vec::new();
+    collect_files(dir, dir, &mut files)?;
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for (relative, path) in files {
+        let content = fs::read(&path).context(format!("Failed to read {:?}", path))?;
+        hasher.update(relative.as_bytes());
+        hasher.update([0]);
+        hasher.update(Sha256::digest(&content));
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn collect_files(root: &Path, dir: &Path, files: &mut Vec<(String, PathBuf)>) -> Result<()> {
+    for entry in fs::read_dir(dir).context(format!("Failed to read {:?}", dir))? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_files(root, &path, files)?;
+        } else {
+            // Use `/` on every platform, so hashes match across machines
+            let relative: Vec<String> = path
+                .strip_prefix(root)
+                .unwrap_or(path.as_path())
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect();
+            files.push((relative.join("/"), path));
+        }
+    }
+    Ok(())
+}
+
+/// Name a plugin after the last component of its source
+fn default_name(source: &str) -> Option<String> {
+    let location = source.split('#').next()?.trim_end_matches('/');
+    let last = location.rsplit(['/', ':']).next()?;
+    let name = [".git", ".tar.gz", ".tgz", ".lua"]
+        .iter()
+        .fold(last, |name, suffix| {
+            name.strip_suffix(suffix).unwrap_or(name)
+        });
+    (!name.is_empty()).then(|| name.to_string())
+}
+
+fn validate_name(name: &str) -> Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        bail!(
+            "Invalid plugin name '{}': use letters, digits, '-' and '_'",
+            name
+        );
+    }
+    Ok(())
+}
+
+/// Refuse git URLs and references that git would parse as options
+fn validate_git_argument(kind: &str, value: &str) -> Result<()> {
+    if value.is_empty() || value.starts_with('-') {
+        bail!(
+            "Invalid git {} '{}': must not be empty or start with '-'",
+            kind,
+            value
+        );
+    }
+    Ok(())
+}
+
+/// Express `path` relative to `base`, both absolute
+fn relative_path(base: &Path, path: &Path) -> String {
+    let base: Vec<Component> = base.components().collect();
+    let path: Vec<Component> = path.components().collect();
+    let common = base.iter().zip(&path).take_while(|(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..base.len() {
+        relative.push("..");
+    }
+    for component in &path[common..] {
+        relative.push(component);
+    }
+
+    if relative.as_os_str().is_empty() {
+        ".".to_string()
+    } else {
+        relative.to_string_lossy().to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    use crate::test_support::lock_cwd;
+
+    use super::*;
+
+    /// Run git in a test repository, with an identity for commits
+    fn run_git(dir: &Path, args: &[&str]) {
+        let status = Command::new("git")
+            .args(["-c", "user.name=Lake", "-c", "user.email=lake@example.com"])
+            .args(args)
+            .current_dir(dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "git {:?} failed", args);
+    }
+
+    /// Create a git repository holding a plugin that returns `version`
+    fn git_repo(dir: &Path, version: &str) {
+        fs::create_dir_all(dir).unwrap();
+        run_git(dir, &["init", "--quiet"]);
+        commit_version(dir, version);
+    }
+
+    fn commit_version(dir: &Path, version: &str) {
+        fs::write(dir.join("init.lua"), format!("return '{}'", version)).unwrap();
+        run_git(dir, &["add", "init.lua"]);
+        run_git(dir, &["commit", "--quiet", "-m", version]);
+    }
+
+    fn installed(project_dir: &Path, name: &str) -> String {
+        fs::read_to_string(project_dir.join(PACKAGES_DIR).join(name).join("init.lua")).unwrap()
+    }
+
+    /// Serve the same body to every request, returning the address
+    fn serve(body: Vec<u8>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 2 {
+                    line.clear();
+                }
+                let header = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(&body).unwrap();
+            }
+        });
+        address
+    }
+
+    fn tarball(files: &[(&str, &str)]) -> Vec<u8> {
+        let encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+        let mut builder = tar::Builder::new(encoder);
+        for (path, content) in files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(content.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, content.as_bytes())
+                .unwrap();
+        }
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn installs_git_plugins_at_their_locked_commit() {
+        // Git fails when another test removed the working directory
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("hello.git");
+        let project_dir = dir.path().join("project");
+        fs::create_dir(&project_dir).unwrap();
+        git_repo(&repo, "v1");
+
+        let source = format!("git+file://{}", repo.display());
+        add(&project_dir, &source, None).unwrap();
+        assert_eq!(installed(&project_dir, "hello"), "return 'v1'");
+        verify(&project_dir, "hello").unwrap();
+
+        // The lockfile pins the commit, newer ones are only fetched by update
+        commit_version(&repo, "v2");
+        fs::remove_dir_all(project_dir.join(PACKAGES_DIR)).unwrap();
+        install(&project_dir).unwrap();
+        assert_eq!(installed(&project_dir, "hello"), "return 'v1'");
+
+        update(&project_dir, &[]).unwrap();
+        assert_eq!(installed(&project_dir, "hello"), "return 'v2'");
+        let lock = LockFile::load(&project_dir).unwrap();
+        assert_eq!(lock.plugins[0].source, source);
+        assert_eq!(lock.plugins[0].version.as_ref().unwrap().len(), 40);
+
+        // Modified plugins fail verification until they are installed again
+        fs::write(
+            project_dir.join(PACKAGES_DIR).join("hello/init.lua"),
+            "return 'modified'",
+        )
+        .unwrap();
+        assert!(verify(&project_dir, "hello").is_err());
+        install(&project_dir).unwrap();
+        assert_eq!(installed(&project_dir, "hello"), "return 'v2'");
+
+        remove(&project_dir, "hello").unwrap();
+        assert!(!project_dir.join(PACKAGES_DIR).join("hello").exists());
+        assert!(LockFile::load(&project_dir).unwrap().plugins.is_empty());
+    }
+
+    #[test]
+    fn installs_tarball_plugins_and_checks_their_hash() {
+        let dir = tempfile::tempdir().unwrap();
+        let address = serve(tarball(&[("hello-1.0/init.lua", "return 'tarball'")]));
+
+        // The fragment is not part of the location
+        let source = format!("http://{}/hello.tar.gz#1.0", address);
+        add(dir.path(), &source, Some("hello")).unwrap();
+        assert_eq!(installed(dir.path(), "hello"), "return 'tarball'");
+
+        let lock_path = dir.path().join(LOCK_FILE);
+        let content = fs::read_to_string(&lock_path).unwrap();
+        let sha256 = LockFile::load(dir.path()).unwrap().plugins[0]
+            .sha256
+            .clone();
+        fs::write(&lock_path, content.replace(&sha256, &"0".repeat(64))).unwrap();
+        fs::remove_dir_all(dir.path().join(PACKAGES_DIR)).unwrap();
+
+        let error = install(dir.path()).unwrap_err().to_string();
+        assert!(error.contains("does not match lake.lock"), "{}", error);
+        assert!(!dir.path().join(PACKAGES_DIR).join("hello").exists());
+    }
+
+    #[test]
+    fn refuses_git_options_from_the_lockfile() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let repo = dir.path().join("hello.git");
+        git_repo(&repo, "v1");
+        let pwned = dir.path().join("pwned");
+
+        let sources = [
+            format!("git+--upload-pack=touch {}", pwned.display()),
+            format!("file://{}#--output={}", repo.display(), pwned.display()),
+        ];
+        for source in sources {
+            let lock = format!(
+                "[[plugin]]\nname = \"hello\"\nsource = {:?}\nsha256 = \"0\"\n",
+                source
+            );
+            fs::write(dir.path().join(LOCK_FILE), lock).unwrap();
+            let error = install(dir.path()).unwrap_err().to_string();
+            assert!(
+                error.contains("must not be empty or start with '-'"),
+                "{}",
+                error
+            );
+        }
+        assert!(!pwned.exists());
+
+        let lock = "[[plugin]]\nname = \"../escape\"\nsource = \"plugin\"\nsha256 = \"0\"\n";
+        fs::write(dir.path().join(LOCK_FILE), lock).unwrap();
+        assert!(install(dir.path()).is_err());
+    }
+}
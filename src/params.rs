@@ -0,0 +1,461 @@
+//! Task parameters for Lake
+//!
+//! Parses named, typed task parameters from the command line. Every task gets
+//! a clap command built from its declared parameters, so validation errors and
+//! usage messages look the same as Lake's own.
+
+use std::collections::BTreeMap;
+
+use anyhow::{bail, Result};
+use clap::builder::BoolishValueParser;
+use clap::{Arg, ArgAction, ArgMatches, Command};
+use mlua::{Lua, Result as LuaResult, Table, Value};
+
+use crate::graph::TaskGraph;
+
+/// Type of a task parameter
+#[derive(Clone, Copy, PartialEq)]
+pub enum ParamType {
+    String,
+    Number,
+    Boolean,
+    List,
+}
+
+impl ParamType {
+    fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "string" => Some(ParamType::String),
+            "number" => Some(ParamType::Number),
+            "boolean" => Some(ParamType::Boolean),
+            "list" => Some(ParamType::List),
+            _ => None,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            ParamType::String => "string",
+            ParamType::Number => "number",
+            ParamType::Boolean => "boolean",
+            ParamType::List => "list",
+        }
+    }
+}
+
+/// A named parameter declared by a task
+pub struct Param {
+    pub name: String,
+    pub kind: ParamType,
+    pub default: Vec<String>,
+    pub required: bool,
+    pub help: Option<String>,
+}
+
+impl Param {
+    /// Read a parameter declaration such as `{ name = "env", type = "string", required = true }`
+    pub fn from_table(task_name: &str, table: &Table) -> Result<Self> {
+        let invalid = |e: mlua::Error| {
+            anyhow::anyhow!(
+                "Invalid parameter declaration in task '{}': {}",
+                task_name,
+                e
+            )
+        };
+
+        let name: String = table.get("name").map_err(invalid)?;
+        let kind_name: Option<String> = table.get("type").map_err(invalid)?;
+        let kind = match kind_name.as_deref() {
+            None => ParamType::String,
+            Some(kind_name) => match ParamType::from_name(kind_name) {
+                Some(kind) => kind,
+                None => bail!(
+                    "Invalid type '{}' for parameter '{}' of task '{}': expected string, number, boolean or list",
+                    kind_name,
+                    name,
+                    task_name
+                ),
+            },
+        };
+
+        // Defaults are kept as text so clap validates them like command line values
+        let default = match table.get::<Value>("default").map_err(invalid)? {
+            Value::Nil => Vec::new(),
+            Value::Table(values) => values
+                .sequence_values::<String>()
+                .collect::<LuaResult<Vec<String>>>()
+                .map_err(invalid)?,
+            Value::Boolean(value) => vec![value.to_string()],
+            Value::Integer(value) => vec![value.to_string()],
+            Value::Number(value) => vec![value.to_string()],
+            Value::String(value) => vec![value.to_str().map_err(invalid)?.to_string()],
+            other => bail!(
+                "Invalid default for parameter '{}' of task '{}': unexpected {}",
+                name,
+                task_name,
+                other.type_name()
+            ),
+        };
+
+        Ok(Param {
+            name,
+            kind,
+            default,
+            required: table
+                .get::<Option<bool>>("required")
+                .map_err(invalid)?
+                .unwrap_or(false),
+            help: table.get("help").map_err(invalid)?,
+        })
+    }
+
+    /// Check if `--name` consumes the following command line token as its value
+    fn takes_value(&self) -> bool {
+        self.kind != ParamType::Boolean
+    }
+
+    fn to_arg(&self) -> Arg {
+        let mut arg = Arg::new(self.name.clone())
+            .long(self.name.clone())
+            .required(self.required);
+
+        let type_help = format!("[{}]", self.kind.name());
+        arg = match &self.help {
+            Some(help) => arg.help(format!("{} {}", help, type_help)),
+            None => arg.help(type_help),
+        };
+
+        arg = match self.kind {
+            ParamType::String => arg.value_name("VALUE"),
+            ParamType::Number => arg
+                .value_name("NUMBER")
+                .value_parser(clap::value_parser!(f64)),
+            ParamType::Boolean => arg
+                .value_name("BOOL")
+                .num_args(0..=1)
+                .require_equals(true)
+                .default_missing_value("true")
+                .value_parser(BoolishValueParser::new()),
+            ParamType::List => arg
+                .value_name("ITEMS")
+                .action(ArgAction::Append)
+                .value_delimiter(','),
+        };
+
+        if !self.default.is_empty() {
+            arg = arg.default_values(self.default.clone());
+        } else if self.kind == ParamType::Boolean && !self.required {
+            arg = arg.default_value("false");
+        }
+
+        arg
+    }
+
+    fn value(&self, matches: &ArgMatches) -> Option<ParamValue> {
+        match self.kind {
+            ParamType::String => matches
+                .get_one::<String>(&self.name)
+                .map(|value| ParamValue::String(value.clone())),
+            ParamType::Number => matches.get_one::<f64>(&self.name).map(|&value| {
+                // Whole numbers become Lua integers, so `--replicas 3` is not `3.0`
+                if value.fract() == 0.0 && value.abs() < i64::MAX as f64 {
+                    ParamValue::Integer(value as i64)
+                } else {
+                    ParamValue::Number(value)
+                }
+            }),
+            ParamType::Boolean => matches
+                .get_one::<bool>(&self.name)
+                .map(|&value| ParamValue::Boolean(value)),
+            ParamType::List => matches
+                .get_many::<String>(&self.name)
+                .map(|values| ParamValue::List(values.cloned().collect())),
+        }
+    }
+}
+
+/// A converted parameter value
+pub enum ParamValue {
+    String(String),
+    Integer(i64),
+    Number(f64),
+    Boolean(bool),
+    List(Vec<String>),
+}
+
+impl ParamValue {
+    fn to_lua(&self, lua: &Lua) -> LuaResult<Value> {
+        Ok(match self {
+            ParamValue::String(value) => Value::String(lua.create_string(value)?),
+            ParamValue::Integer(value) => Value::Integer(*value),
+            ParamValue::Number(value) => Value::Number(*value),
+            ParamValue::Boolean(value) => Value::Boolean(*value),
+            ParamValue::List(values) => Value::Table(lua.create_sequence_from(values.clone())?),
+        })
+    }
+
+    fn to_text(&self) -> String {
+        match self {
+            ParamValue::String(value) => value.clone(),
+            ParamValue::Integer(value) => value.to_string(),
+            ParamValue::Number(value) => value.to_string(),
+            ParamValue::Boolean(value) => value.to_string(),
+            ParamValue::List(values) => values.join(","),
+        }
+    }
+}
+
+/// Arguments a task is called with, always as a single table
+pub enum TaskArgs {
+    /// Strings given after `--`, for tasks that declare no parameters
+    Positional(Vec<String>),
+    /// Validated values, by parameter name
+    Named(BTreeMap<String, ParamValue>),
+}
+
+impl TaskArgs {
+    /// Convert the arguments to the table the task function is called with
+    pub fn to_lua(&self, lua: &Lua) -> LuaResult<Table> {
+        match self {
+            TaskArgs::Positional(args) => lua.create_sequence_from(args.iter().map(String::as_str)),
+            TaskArgs::Named(values) => {
+                let table = lua.create_table()?;
+                for (name, value) in values {
+                    table.set(name.as_str(), value.to_lua(lua)?)?;
+                }
+                Ok(table)
+            }
+        }
+    }
+
+    /// Stable textual form of the arguments, used to detect changes between runs
+    pub fn fingerprint(&self) -> String {
+        match self {
+            TaskArgs::Positional(args) => args.join("\0"),
+            TaskArgs::Named(values) => values
+                .iter()
+                .map(|(name, value)| format!("{}={}", name, value.to_text()))
+                .collect::<Vec<_>>()
+                .join("\0"),
+        }
+    }
+}
+
+/// A task named on the command line, with the tokens that follow it
+pub struct Invocation {
+    pub task: String,
+    pub tokens: Vec<String>,
+}
+
+/// Split a command line like `clean deploy --env=prod --replicas 3 test` into
+/// one invocation per task.
+///
+/// A token starts a new task unless it is a flag or the value of a preceding
+/// `--name` flag that takes one.
+pub fn split_invocations(graph: &TaskGraph, command_line: &[String]) -> Vec<Invocation> {
+    let mut invocations: Vec<Invocation> = Vec::new();
+    let mut expects_value = false;
+
+    for token in command_line {
+        let current = invocations
+            .last_mut()
+            .filter(|_| expects_value || token.starts_with('-'));
+
+        match current {
+            Some(current) => {
+                expects_value = !expects_value && flag_takes_value(graph, &current.task, token);
+                current.tokens.push(token.clone());
+            }
+            None => invocations.push(Invocation {
+                task: token.clone(),
+                tokens: Vec::new(),
+            }),
+        }
+    }
+
+    invocations
+}
+
+/// Check if a `--name` token without `=value` expects the next token as its value
+fn flag_takes_value(graph: &TaskGraph, task_name: &str, token: &str) -> bool {
+    let Some(flag) = token.strip_prefix("--") else {
+        return false;
+    };
+
+    !flag.contains('=')
+        && graph
+            .params(task_name)
+            .iter()
+            .any(|param| param.name == flag && param.takes_value())
+}
+
+/// Build the clap command describing a task's parameters
+pub fn command(graph: &TaskGraph, task_name: &str) -> Command {
+    let mut command = Command::new(task_name.to_string())
+        .bin_name(format!("lake {}", task_name))
+        .no_binary_name(true);
+
+    if let Some(desc) = graph.description(task_name) {
+        command = command.about(desc.to_string());
+    }
+
+    for param in graph.params(task_name) {
+        command = command.arg(param.to_arg());
+    }
+
+    command
+}
+
+/// Validate and convert the arguments of a task.
+///
+/// `tokens` are the `--name=value` flags that followed the task on the command
+/// line, and `positional` the arguments given after `--`.
+pub fn parse_args(
+    graph: &TaskGraph,
+    task_name: &str,
+    tokens: &[String],
+    positional: &[String],
+) -> Result<TaskArgs> {
+    let params = graph.params(task_name);
+    let matches = command(graph, task_name).try_get_matches_from(tokens)?;
+
+    if params.is_empty() {
+        return Ok(TaskArgs::Positional(positional.to_vec()));
+    }
+
+    if !positional.is_empty() {
+        bail!(
+            "Task '{}' takes named parameters, pass them as `lake {} --name=value`",
+            task_name,
+            task_name
+        );
+    }
+
+    let values = params
+        .iter()
+        .filter_map(|param| {
+            param
+                .value(&matches)
+                .map(|value| (param.name.clone(), value))
+        })
+        .collect();
+
+    Ok(TaskArgs::Named(values))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const REGISTRY: &str = r#"return {
+        clean = {},
+        test = {},
+        deploy = {
+            params = {
+                { name = "env", required = true },
+                { name = "replicas", type = "number", default = 1 },
+                { name = "force", type = "boolean" },
+                { name = "tags", type = "list", default = { "latest" } },
+            },
+        },
+    }"#;
+
+    fn graph() -> TaskGraph {
+        let lua = Lua::new();
+        let registry: Table = lua.load(REGISTRY).eval().unwrap();
+        TaskGraph::from_registry(&registry).unwrap()
+    }
+
+    fn strings(tokens: &[&str]) -> Vec<String> {
+        tokens.iter().map(|token| token.to_string()).collect()
+    }
+
+    fn named(args: TaskArgs) -> BTreeMap<String, ParamValue> {
+        match args {
+            TaskArgs::Named(values) => values,
+            TaskArgs::Positional(_) => panic!("expected named arguments"),
+        }
+    }
+
+    #[test]
+    fn splits_tasks_and_their_flags() {
+        let command_line = strings(&[
+            "clean",
+            "deploy",
+            "--env",
+            "prod",
+            "--force",
+            "--replicas=3",
+            "test",
+        ]);
+        let invocations = split_invocations(&graph(), &command_line);
+
+        let split: Vec<(&str, Vec<&str>)> = invocations
+            .iter()
+            .map(|i| {
+                (
+                    i.task.as_str(),
+                    i.tokens.iter().map(String::as_str).collect(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            split,
+            [
+                ("clean", vec![]),
+                ("deploy", vec!["--env", "prod", "--force", "--replicas=3"]),
+                ("test", vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn converts_named_parameters_to_their_types() {
+        let graph = graph();
+        let tokens = strings(&["--env=prod", "--replicas", "2.5", "--force", "--tags=a,b"]);
+        let values = named(parse_args(&graph, "deploy", &tokens, &[]).unwrap());
+        assert!(matches!(&values["env"], ParamValue::String(env) if env == "prod"));
+        assert!(matches!(values["replicas"], ParamValue::Number(replicas) if replicas == 2.5));
+        assert!(matches!(values["force"], ParamValue::Boolean(true)));
+        assert!(matches!(&values["tags"], ParamValue::List(tags) if tags == &["a", "b"]));
+
+        let values = named(parse_args(&graph, "deploy", &strings(&["--env=dev"]), &[]).unwrap());
+        assert!(matches!(values["replicas"], ParamValue::Integer(1)));
+        assert!(matches!(values["force"], ParamValue::Boolean(false)));
+        assert!(matches!(&values["tags"], ParamValue::List(tags) if tags == &["latest"]));
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        let graph = graph();
+        let invalid = [
+            (strings(&[]), vec![]),
+            (strings(&["--env=prod", "--replicas=many"]), vec![]),
+            (strings(&["--env=prod", "--unknown"]), vec![]),
+            (strings(&["--env=prod"]), strings(&["extra"])),
+        ];
+        for (tokens, positional) in invalid {
+            assert!(
+                parse_args(&graph, "deploy", &tokens, &positional).is_err(),
+                "{:?} {:?}",
+                tokens,
+                positional
+            );
+        }
+
+        // Tasks without parameters take positional arguments only
+        assert!(parse_args(&graph, "clean", &strings(&["--env=prod"]), &[]).is_err());
+        let args = parse_args(&graph, "clean", &[], &strings(&["a", "b"])).unwrap();
+        assert_eq!(args.fingerprint(), "a\0b");
+    }
+
+    #[test]
+    fn positional_args_are_a_single_table() {
+        let lua = Lua::new();
+        let args = TaskArgs::Positional(vec!["one".to_string(), "two".to_string()]);
+
+        let table = args.to_lua(&lua).unwrap();
+        let values: Vec<String> = table.sequence_values().collect::<LuaResult<_>>().unwrap();
+        assert_eq!(values, ["one", "two"]);
+    }
+}
@@ -0,0 +1,578 @@
+//! Sandbox permissions for Lake
+//!
+//! Scripts may read the project directory, anything else has to be granted
+//! with `--allow-*` flags or in the `[allow]` table of the user configuration.
+//! The `[allow]` table of a project's lake.toml only requests permissions, so
+//! a checked out project cannot grant itself more than the user did. The core
+//! plugins check the permissions before acting and fail with an error naming
+//! the flag that would allow the operation.
+
+use std::env;
+use std::path::{Component, Path, PathBuf};
+
+use mlua::{Error as LuaError, Lua, Result as LuaResult};
+use serde::{Deserialize, Serialize};
+
+/// Resources a capability is granted for
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(untagged)]
+pub enum Grant {
+    /// `true` grants every resource, `false` none
+    Any(bool),
+    /// Only the listed paths, hosts, programs, variables or plugins
+    Only(Vec<String>),
+}
+
+impl Default for Grant {
+    fn default() -> Self {
+        Grant::Any(false)
+    }
+}
+
+impl Grant {
+    /// Convert a `--allow-*` flag, which grants everything when given without values
+    pub fn from_flag(values: Option<Vec<String>>) -> Self {
+        match values {
+            None => Grant::Any(false),
+            Some(values) if values.is_empty() => Grant::Any(true),
+            Some(values) => Grant::Only(values),
+        }
+    }
+
+    /// Check if the grant lists an entry, or grants everything
+    fn contains(&self, entry: &str) -> bool {
+        match self {
+            Grant::Any(any) => *any,
+            Grant::Only(entries) => entries.iter().any(|e| e == entry),
+        }
+    }
+
+    /// Combine two grants, allowing what either of them allows
+    fn union(&self, other: &Grant) -> Grant {
+        match (self, other) {
+            (Grant::Any(true), _) | (_, Grant::Any(true)) => Grant::Any(true),
+            (Grant::Any(false), grant) | (grant, Grant::Any(false)) => grant.clone(),
+            (Grant::Only(first), Grant::Only(second)) => {
+                Grant::Only(first.iter().chain(second).cloned().collect())
+            }
+        }
+    }
+
+    fn entries(&self) -> &[String] {
+        match self {
+            Grant::Only(entries) => entries,
+            Grant::Any(_) => &[],
+        }
+    }
+}
+
+/// Capabilities granted to scripts, as declared in the `[allow]` table
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Allow {
+    /// Grant every capability
+    pub all: bool,
+    /// Paths that can be read, besides the project directory
+    pub read: Grant,
+    /// Paths that can be written
+    pub write: Grant,
+    /// Hosts, optionally with a port, that can be reached
+    pub net: Grant,
+    /// Programs that can be executed
+    pub run: Grant,
+    /// Environment variables that can be read and set
+    pub env: Grant,
+    /// Native plugins that can be loaded, which run outside of the sandbox
+    pub native: Grant,
+}
+
+impl Allow {
+    /// Combine two sets of grants, allowing what either of them allows
+    pub fn union(&self, other: &Allow) -> Allow {
+        Allow {
+            all: self.all || other.all,
+            read: self.read.union(&other.read),
+            write: self.write.union(&other.write),
+            net: self.net.union(&other.net),
+            run: self.run.union(&other.run),
+            env: self.env.union(&other.env),
+            native: self.native.union(&other.native),
+        }
+    }
+}
+
+/// Path grants resolved to absolute paths
+#[derive(Clone, Default)]
+enum PathGrant {
+    #[default]
+    None,
+    Any,
+    Only(Vec<PathBuf>),
+}
+
+impl PathGrant {
+    /// Resolve the granted paths, relative ones against the current directory
+    fn resolve(grant: &Grant) -> Self {
+        match grant {
+            Grant::Any(false) => PathGrant::None,
+            Grant::Any(true) => PathGrant::Any,
+            Grant::Only(paths) => {
+                PathGrant::Only(paths.iter().map(|p| resolve_path(Path::new(p))).collect())
+            }
+        }
+    }
+
+    fn allows(&self, path: &Path) -> bool {
+        match self {
+            PathGrant::None => false,
+            PathGrant::Any => true,
+            PathGrant::Only(paths) => paths.iter().any(|granted| path.starts_with(granted)),
+        }
+    }
+}
+
+/// Permissions checked by the core plugins
+#[derive(Clone, Default)]
+pub struct Permissions {
+    all: bool,
+    project_dir: PathBuf,
+    read: PathGrant,
+    write: PathGrant,
+    net: Grant,
+    run: Grant,
+    /// Programs granted by path, resolved to the files they lead to
+    run_paths: Vec<PathBuf>,
+    env: Grant,
+    native: Grant,
+}
+
+impl Permissions {
+    /// Resolve the granted capabilities of a project.
+    ///
+    /// Must be called before moving into the project directory, so relative
+    /// paths given on the command line resolve against the directory Lake was started in.
+    pub fn new(allow: &Allow, project_dir: &Path) -> Self {
+        Permissions {
+            all: allow.all,
+            project_dir: resolve_path(project_dir),
+            read: PathGrant::resolve(&allow.read),
+            write: PathGrant::resolve(&allow.write),
+            net: allow.net.clone(),
+            run: allow.run.clone(),
+            run_paths: allow
+                .run
+                .entries()
+                .iter()
+                .filter(|program| is_program_path(program))
+                .map(|program| resolve_path(Path::new(program)))
+                .collect(),
+            env: allow.env.clone(),
+            native: allow.native.clone(),
+        }
+    }
+
+    /// Check that a path may be read
+    pub fn check_read(&self, path: &Path) -> LuaResult<()> {
+        let path = resolve_path(path);
+        if self.all || path.starts_with(&self.project_dir) || self.read.allows(&path) {
+            return Ok(());
+        }
+        Err(self.denied_path("read", &path))
+    }
+
+    /// Check that a path may be created, modified or removed
+    pub fn check_write(&self, path: &Path) -> LuaResult<()> {
+        let path = resolve_path(path);
+        if self.all || self.write.allows(&path) {
+            return Ok(());
+        }
+        Err(self.denied_path("write", &path))
+    }
+
+    fn check_net(&self, url: &str) -> LuaResult<()> {
+        let parsed = reqwest::Url::parse(url)
+            .map_err(|e| LuaError::RuntimeError(format!("Invalid URL {:?}: {}", url, e)))?;
+        let host = parsed.host_str().unwrap_or_default();
+        let host_port = match parsed.port_or_known_default() {
+            Some(port) => format!("{}:{}", host, port),
+            None => host.to_string(),
+        };
+
+        if self.all || self.net.contains(host) || self.net.contains(&host_port) {
+            return Ok(());
+        }
+        Err(denied("net", host))
+    }
+
+    /// Check that a program may be executed. A program given by name is
+    /// granted by that name, or by the path of the file found on PATH, and a
+    /// program given by path only by the path of the same file.
+    fn check_run(&self, program: &str) -> LuaResult<()> {
+        let allowed = match &self.run {
+            Grant::Any(any) => *any,
+            Grant::Only(_) if is_program_path(program) => {
+                self.run_paths.contains(&resolve_path(Path::new(program)))
+            }
+            Grant::Only(programs) => {
+                programs.iter().any(|p| p == program)
+                    || find_program(program).is_some_and(|path| self.run_paths.contains(&path))
+            }
+        };
+        if self.all || allowed {
+            return Ok(());
+        }
+        Err(denied("run", program))
+    }
+
+    /// Check that an environment variable may be read or set
+    pub fn check_env(&self, name: &str) -> LuaResult<()> {
+        if self.all || self.env.contains(name) {
+            return Ok(());
+        }
+        Err(denied("env", name))
+    }
+
+    /// Check that a native plugin may be loaded
+    pub fn check_native(&self, name: &str) -> LuaResult<()> {
+        if self.all || self.native.contains(name) {
+            return Ok(());
+        }
+        Err(denied("native", name))
+    }
+
+    /// List the flags that would grant what a project requests in the
+    /// `[allow]` table of its lake.toml and is not granted
+    pub fn missing(&self, requested: &Allow) -> Vec<String> {
+        if self.all {
+            return Vec::new();
+        }
+        if requested.all {
+            return vec!["--allow-all".to_string()];
+        }
+
+        let capabilities = [
+            ("read", &requested.read),
+            ("write", &requested.write),
+            ("net", &requested.net),
+            ("run", &requested.run),
+            ("env", &requested.env),
+            ("native", &requested.native),
+        ];
+        let mut missing = Vec::new();
+        for (capability, grant) in capabilities {
+            let denied: Vec<String> = match grant {
+                Grant::Any(false) => continue,
+                Grant::Any(true) => {
+                    if !self.grants_any(capability) {
+                        missing.push(format!("--allow-{}", capability));
+                    }
+                    continue;
+                }
+                Grant::Only(entries) => entries
+                    .iter()
+                    .filter(|entry| !self.grants(capability, entry))
+                    .map(|entry| match capability {
+                        "read" | "write" => self.shown_path(&resolve_path(Path::new(entry))),
+                        _ => entry.clone(),
+                    })
+                    .collect(),
+            };
+            if !denied.is_empty() {
+                missing.push(format!("--allow-{}={}", capability, denied.join(",")));
+            }
+        }
+        missing
+    }
+
+    /// Check if a capability is granted for everything
+    fn grants_any(&self, capability: &str) -> bool {
+        match capability {
+            "read" => matches!(self.read, PathGrant::Any),
+            "write" => matches!(self.write, PathGrant::Any),
+            "net" => matches!(self.net, Grant::Any(true)),
+            "run" => matches!(self.run, Grant::Any(true)),
+            "env" => matches!(self.env, Grant::Any(true)),
+            _ => matches!(self.native, Grant::Any(true)),
+        }
+    }
+
+    /// Check if a capability is granted for a path, host, program, variable or plugin
+    fn grants(&self, capability: &str, entry: &str) -> bool {
+        match capability {
+            "read" => self.check_read(Path::new(entry)).is_ok(),
+            "write" => self.check_write(Path::new(entry)).is_ok(),
+            "net" => self.net.contains(entry),
+            "run" => self.check_run(entry).is_ok(),
+            "env" => self.env.contains(entry),
+            _ => self.native.contains(entry),
+        }
+    }
+
+    /// Deny access to a path, suggesting it relative to the project when possible
+    fn denied_path(&self, capability: &str, path: &Path) -> LuaError {
+        denied(capability, &self.shown_path(path))
+    }
+
+    /// Show a path relative to the project when possible
+    fn shown_path(&self, path: &Path) -> String {
+        match path.strip_prefix(&self.project_dir) {
+            Ok(relative) => format!("./{}", relative.display()),
+            Err(_) => path.display().to_string(),
+        }
+    }
+
+    /// Describe the grants, for `--verbose` output
+    pub fn describe(&self) -> String {
+        if self.all {
+            return "all".to_string();
+        }
+
+        let mut granted = Vec::new();
+        let grants = [
+            ("net", &self.net),
+            ("run", &self.run),
+            ("env", &self.env),
+            ("native", &self.native),
+        ];
+        for (name, grant) in grants {
+            match grant {
+                Grant::Any(true) => granted.push(name.to_string()),
+                Grant::Any(false) => {}
+                Grant::Only(_) => granted.push(format!("{}={}", name, grant.entries().join(","))),
+            }
+        }
+        for (name, grant) in [("read", &self.read), ("write", &self.write)] {
+            match grant {
+                PathGrant::Any => granted.push(name.to_string()),
+                PathGrant::None => {}
+                PathGrant::Only(paths) => {
+                    let paths: Vec<String> =
+                        paths.iter().map(|p| p.display().to_string()).collect();
+                    granted.push(format!("{}={}", name, paths.join(",")));
+                }
+            }
+        }
+
+        if granted.is_empty() {
+            "read of the project directory".to_string()
+        } else {
+            granted.join(" ")
+        }
+    }
+}
+
+/// Check that the script may read a path
+pub fn check_read(lua: &Lua, path: impl AsRef<Path>) -> LuaResult<()> {
+    with_permissions(lua, |permissions| permissions.check_read(path.as_ref()))
+}
+
+/// Check that the script may create, modify or remove a path
+pub fn check_write(lua: &Lua, path: impl AsRef<Path>) -> LuaResult<()> {
+    with_permissions(lua, |permissions| permissions.check_write(path.as_ref()))
+}
+
+/// Check that the script may connect to the host of a URL
+pub fn check_net(lua: &Lua, url: &str) -> LuaResult<()> {
+    with_permissions(lua, |permissions| permissions.check_net(url))
+}
+
+/// Check that the script may execute a program
+pub fn check_run(lua: &Lua, program: &str) -> LuaResult<()> {
+    with_permissions(lua, |permissions| permissions.check_run(program))
+}
+
+/// Check that the script may read or set an environment variable
+pub fn check_env(lua: &Lua, name: &str) -> LuaResult<()> {
+    with_permissions(lua, |permissions| permissions.check_env(name))
+}
+
+/// Check that the script may load a native plugin
+pub fn check_native(lua: &Lua, name: &str) -> LuaResult<()> {
+    with_permissions(lua, |permissions| permissions.check_native(name))
+}
+
+fn with_permissions(lua: &Lua, check: impl FnOnce(&Permissions) -> LuaResult<()>) -> LuaResult<()> {
+    match lua.app_data_ref::<Permissions>() {
+        Some(permissions) => check(&permissions),
+        None => Err(LuaError::RuntimeError(
+            "permission denied: sandbox permissions are not initialized".to_string(),
+        )),
+    }
+}
+
+fn denied(capability: &str, resource: &str) -> LuaError {
+    LuaError::RuntimeError(format!(
+        "permission denied: needs --allow-{}={}",
+        capability, resource
+    ))
+}
+
+/// Check if a program is given by path rather than by a name found on PATH
+fn is_program_path(program: &str) -> bool {
+    Path::new(program).components().count() > 1
+}
+
+/// Find the file a program name runs from PATH
+fn find_program(name: &str) -> Option<PathBuf> {
+    let names = [
+        name.to_string(),
+        format!("{}{}", name, env::consts::EXE_SUFFIX),
+    ];
+    env::split_paths(&env::var_os("PATH")?).find_map(|dir| {
+        names
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.is_file())
+            .map(|path| resolve_path(&path))
+    })
+}
+
+/// Make a path absolute and resolve `..` and symbolic links, so granted
+/// directories cannot be escaped. Components that do not exist yet are kept as is.
+fn resolve_path(path: &Path) -> PathBuf {
+    let absolute = env::current_dir().unwrap_or_default().join(path);
+
+    let mut normalized = PathBuf::new();
+    for component in absolute.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                normalized.pop();
+            }
+            other => normalized.push(other),
+        }
+    }
+
+    // Resolve symbolic links in the part of the path that exists
+    let mut existing = normalized.as_path();
+    let mut missing = Vec::new();
+    while !existing.exists() {
+        match (existing.file_name(), existing.parent()) {
+            (Some(name), Some(parent)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => break,
+        }
+    }
+
+    let mut resolved = existing
+        .canonicalize()
+        .unwrap_or_else(|_| existing.to_path_buf());
+    for name in missing.iter().rev() {
+        resolved.push(name);
+    }
+    resolved
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn only(entries: &[&str]) -> Grant {
+        Grant::Only(entries.iter().map(|e| e.to_string()).collect())
+    }
+
+    #[test]
+    fn denies_what_is_not_granted() {
+        let project_dir = Path::new("/project");
+        let allow = Allow {
+            write: only(&["/project/target"]),
+            run: only(&["git"]),
+            ..Allow::default()
+        };
+        let permissions = Permissions::new(&allow, project_dir);
+
+        assert!(permissions.check_read(Path::new("/project/src")).is_ok());
+        assert!(permissions
+            .check_write(Path::new("/project/target/out"))
+            .is_ok());
+        assert!(permissions.check_run("git").is_ok());
+
+        let denials = [
+            (
+                permissions.check_read(Path::new("/project/../etc/passwd")),
+                "read=/etc/passwd",
+            ),
+            (
+                permissions.check_write(Path::new("/project/src")),
+                "write=./src",
+            ),
+            (permissions.check_run("npm"), "run=npm"),
+            (permissions.check_run("./tools/git"), "run=./tools/git"),
+            (permissions.check_run("/usr/bin/git"), "run=/usr/bin/git"),
+            (
+                permissions.check_net("https://example.com/a"),
+                "net=example.com",
+            ),
+            (permissions.check_env("HOME"), "env=HOME"),
+            (permissions.check_native("docker"), "native=docker"),
+        ];
+        for (result, flag) in denials {
+            let message = result.unwrap_err().to_string();
+            assert!(
+                message.contains(&format!("needs --allow-{}", flag)),
+                "{}",
+                message
+            );
+        }
+    }
+
+    #[test]
+    fn grants_programs_by_name_or_path() {
+        let dir = tempfile::tempdir().unwrap();
+        let tool = dir.path().join("tools/git");
+        std::fs::create_dir_all(tool.parent().unwrap()).unwrap();
+        std::fs::write(&tool, "").unwrap();
+        let shell = find_program("sh").unwrap();
+        let allow = Allow {
+            run: only(&[&tool.to_string_lossy(), &shell.to_string_lossy()]),
+            ..Allow::default()
+        };
+        let permissions = Permissions::new(&allow, dir.path());
+
+        assert!(permissions.check_run(&tool.to_string_lossy()).is_ok());
+        let roundabout = dir.path().join("tools/../tools/git");
+        assert!(permissions.check_run(&roundabout.to_string_lossy()).is_ok());
+        assert!(permissions.check_run("sh").is_ok());
+        assert!(permissions.check_run("git").is_err());
+        let other = dir.path().join("git");
+        assert!(permissions.check_run(&other.to_string_lossy()).is_err());
+    }
+
+    #[test]
+    fn lists_missing_requested_permissions() {
+        let project_dir = Path::new("/project");
+        let allow = Allow {
+            run: only(&["git"]),
+            ..Allow::default()
+        };
+        let permissions = Permissions::new(&allow, project_dir);
+
+        let requested = Allow {
+            write: only(&["/project/target"]),
+            net: Grant::Any(true),
+            run: only(&["git", "npm"]),
+            native: only(&["docker"]),
+            ..Allow::default()
+        };
+        assert_eq!(
+            permissions.missing(&requested),
+            [
+                "--allow-write=./target",
+                "--allow-net",
+                "--allow-run=npm",
+                "--allow-native=docker"
+            ]
+        );
+
+        let everything = Allow {
+            all: true,
+            ..Allow::default()
+        };
+        assert_eq!(permissions.missing(&everything), ["--allow-all"]);
+        assert!(Permissions::new(&everything, project_dir)
+            .missing(&requested)
+            .is_empty());
+    }
+}
@@ -0,0 +1,720 @@
+//! Archive plugin for Lake
+//!
+//! Creates, lists and extracts `.tar`, `.tar.gz`, `.tar.xz` and `.zip` archives.
+
+use crate::permissions::{check_read, check_write};
+use crate::plugins::fs_plugin::{glob_matches, patterns, real_path, slash_path, symlink};
+use crate::plugins::{env_plugin, log_dry_run, Plugin};
+use glob::Pattern;
+use mlua::{Error as LuaError, Lua, Result as LuaResult, Table};
+use std::fs::{File, Metadata};
+use std::io::{self, BufReader, BufWriter, ErrorKind, Read, Write};
+use std::path::{Component, Path, PathBuf};
+
+pub struct ArchivePlugin {
+    dry_run: bool,
+}
+
+impl ArchivePlugin {
+    pub fn new(dry_run: bool) -> Self {
+        ArchivePlugin { dry_run }
+    }
+}
+
+fn to_lua_error<E: std::error::Error + Send + Sync + 'static>(e: E, context: &str) -> LuaError {
+    log::error!("{}: {}", context, e);
+    LuaError::RuntimeError(format!("{}: {}", context, e))
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Format {
+    Tar,
+    TarGz,
+    TarXz,
+    Zip,
+}
+
+impl Format {
+    /// Use the `format` option, or else the extension of the archive
+    fn detect(path: &str, format: Option<&str>) -> LuaResult<Self> {
+        let name = match format {
+            Some(format) => format!(".{}", format),
+            None => path.to_lowercase(),
+        };
+        if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Ok(Format::TarGz)
+        } else if name.ends_with(".tar.xz") || name.ends_with(".txz") {
+            Ok(Format::TarXz)
+        } else if name.ends_with(".tar") {
+            Ok(Format::Tar)
+        } else if name.ends_with(".zip") {
+            Ok(Format::Zip)
+        } else {
+            Err(LuaError::RuntimeError(format!(
+                "Unknown archive format of {}: expected .tar, .tar.gz, .tar.xz or .zip, or a `format` option",
+                path
+            )))
+        }
+    }
+
+    /// Read the entries of a tar archive, decompressing them
+    fn tar_reader(self, file: File) -> tar::Archive<Box<dyn Read>> {
+        let reader = BufReader::new(file);
+        let reader: Box<dyn Read> = match self {
+            Format::TarGz => Box::new(flate2::read::GzDecoder::new(reader)),
+            Format::TarXz => Box::new(xz2::read::XzDecoder::new(reader)),
+            Format::Tar | Format::Zip => Box::new(reader),
+        };
+        tar::Archive::new(reader)
+    }
+}
+
+/// Options of `create`, `extract` and `list`
+struct ArchiveOptions {
+    format: Option<String>,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    /// Directory the files are stored under when creating an archive
+    prefix: Option<String>,
+    /// Timestamp given to every entry when creating an archive, or `None` to
+    /// keep the modification times of the files
+    mtime: Option<u64>,
+    /// Leading path components removed from the entries when extracting
+    strip_components: usize,
+}
+
+impl ArchiveOptions {
+    fn from_lua(lua: &Lua, options: Option<Table>) -> LuaResult<Self> {
+        // Reproducible archives do not depend on when their files were checked out
+        let default_mtime = env_plugin::var(lua, "SOURCE_DATE_EPOCH")
+            .and_then(|epoch| epoch.trim().parse().ok())
+            .unwrap_or(0);
+
+        let Some(options) = options else {
+            return Ok(ArchiveOptions {
+                format: None,
+                include: Vec::new(),
+                exclude: Vec::new(),
+                prefix: None,
+                mtime: Some(default_mtime),
+                strip_components: 0,
+            });
+        };
+
+        let preserve_times = options
+            .get::<Option<bool>>("preserve_times")?
+            .unwrap_or(false);
+        let mtime = if preserve_times {
+            None
+        } else {
+            Some(
+                options
+                    .get::<Option<u64>>("mtime")?
+                    .unwrap_or(default_mtime),
+            )
+        };
+
+        Ok(ArchiveOptions {
+            format: options.get("format")?,
+            include: patterns(&options, "include")?,
+            exclude: patterns(&options, "exclude")?,
+            prefix: options
+                .get::<Option<String>>("prefix")?
+                .map(|prefix| prefix.trim_matches('/').to_string())
+                .filter(|prefix| !prefix.is_empty()),
+            mtime,
+            strip_components: options
+                .get::<Option<usize>>("strip_components")?
+                .unwrap_or(0),
+        })
+    }
+
+    /// Excluded directories are skipped along with their content
+    fn excludes(&self, name: &str) -> bool {
+        self.exclude.iter().any(|p| glob_matches(p, name))
+    }
+
+    /// Includes select files, directories are kept when there are none
+    fn includes(&self, name: &str, is_dir: bool) -> bool {
+        self.include.is_empty() || (!is_dir && self.include.iter().any(|p| glob_matches(p, name)))
+    }
+}
+
+/// A file, directory or symlink to archive
+struct Source {
+    /// Path relative to the archived directory, with `/` separators
+    relative: String,
+    path: PathBuf,
+    metadata: Metadata,
+}
+
+impl Source {
+    /// Name of the entry, under the prefix of the archive
+    fn name(&self, options: &ArchiveOptions) -> String {
+        match &options.prefix {
+            Some(prefix) => format!("{}/{}", prefix, self.relative),
+            None => self.relative.clone(),
+        }
+    }
+
+    fn mtime(&self, options: &ArchiveOptions) -> u64 {
+        options.mtime.unwrap_or_else(|| {
+            self.metadata
+                .modified()
+                .ok()
+                .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+                .map_or(0, |elapsed| elapsed.as_secs())
+        })
+    }
+}
+
+/// Collect the entries below a directory, sorted by name so that archives
+/// do not depend on the order of the filesystem
+fn collect_sources(
+    root: &Path,
+    relative: &Path,
+    options: &ArchiveOptions,
+    sources: &mut Vec<Source>,
+) -> io::Result<()> {
+    let mut entries = std::fs::read_dir(root.join(relative))?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    for entry in entries {
+        let path = relative.join(entry.file_name());
+        let name = slash_path(&path);
+        if options.excludes(&name) {
+            continue;
+        }
+        let metadata = std::fs::symlink_metadata(entry.path())?;
+        let is_dir = metadata.is_dir();
+        if options.includes(&name, is_dir) {
+            sources.push(Source {
+                relative: name,
+                path: entry.path(),
+                metadata,
+            });
+        }
+        if is_dir {
+            collect_sources(root, &path, options, sources)?;
+        }
+    }
+    Ok(())
+}
+
+/// Write a tar archive with normalized ownership and permissions
+fn write_tar<W: Write>(writer: W, sources: &[Source], options: &ArchiveOptions) -> io::Result<W> {
+    let mut builder = tar::Builder::new(writer);
+    for source in sources {
+        let mut header = tar::Header::new_gnu();
+        header.set_metadata_in_mode(&source.metadata, tar::HeaderMode::Deterministic);
+        header.set_mtime(source.mtime(options));
+        let name = source.name(options);
+
+        if source.metadata.is_symlink() {
+            let target = std::fs::read_link(&source.path)?;
+            builder.append_link(&mut header, &name, target)?;
+        } else if source.metadata.is_dir() {
+            builder.append_data(&mut header, &name, io::empty())?;
+        } else {
+            builder.append_data(&mut header, &name, File::open(&source.path)?)?;
+        }
+    }
+    builder.into_inner()
+}
+
+/// Convert seconds since the Unix epoch to a zip timestamp, which starts in 1980
+fn zip_time(seconds: u64) -> zip::DateTime {
+    // Civil date from a day count, see http://howardhinnant.github.io/date_algorithms.html
+    let days = (seconds / 86400) as i64 + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    let time = seconds % 86400;
+    zip::DateTime::from_date_and_time(
+        year.clamp(0, u16::MAX as i64) as u16,
+        month as u8,
+        day as u8,
+        (time / 3600) as u8,
+        (time % 3600 / 60) as u8,
+        (time % 60) as u8,
+    )
+    .unwrap_or_default()
+}
+
+/// Write a zip archive with normalized permissions
+fn write_zip(file: File, sources: &[Source], options: &ArchiveOptions) -> io::Result<()> {
+    let mut zip = zip::ZipWriter::new(BufWriter::new(file));
+    for source in sources {
+        let executable = mode(&source.metadata) & 0o111 != 0;
+        let entry_options = zip::write::SimpleFileOptions::default()
+            .compression_method(zip::CompressionMethod::Deflated)
+            .last_modified_time(zip_time(source.mtime(options)))
+            .unix_permissions(if source.metadata.is_dir() || executable {
+                0o755
+            } else {
+                0o644
+            });
+        let name = source.name(options);
+
+        if source.metadata.is_symlink() {
+            let target = std::fs::read_link(&source.path)?;
+            zip.add_symlink(name, slash_path(&target), entry_options)
+                .map_err(io::Error::other)?;
+        } else if source.metadata.is_dir() {
+            zip.add_directory(name, entry_options)
+                .map_err(io::Error::other)?;
+        } else {
+            zip.start_file(name, entry_options)
+                .map_err(io::Error::other)?;
+            io::copy(&mut File::open(&source.path)?, &mut zip)?;
+        }
+    }
+    zip.finish().map_err(io::Error::other)?.flush()
+}
+
+fn mode(metadata: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        0o644
+    }
+}
+
+/// Write an archive of the content of a directory
+fn create(
+    path: &str,
+    sources: &[Source],
+    format: Format,
+    options: &ArchiveOptions,
+) -> io::Result<()> {
+    let file = File::create(path)?;
+    match format {
+        Format::Tar => write_tar(BufWriter::new(file), sources, options)?.flush(),
+        Format::TarGz => {
+            let encoder = flate2::write::GzEncoder::new(BufWriter::new(file), Default::default());
+            write_tar(encoder, sources, options)?.finish()?.flush()
+        }
+        Format::TarXz => {
+            let encoder = xz2::write::XzEncoder::new(BufWriter::new(file), 6);
+            write_tar(encoder, sources, options)?.finish()?.flush()
+        }
+        Format::Zip => write_zip(file, sources, options),
+    }
+}
+
+fn unsafe_path(name: &Path) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidData,
+        format!("refusing to extract {:?} outside of the destination", name),
+    )
+}
+
+/// Resolve the name of an entry below the destination, without its stripped
+/// components. Absolute names and names with `..` are rejected.
+fn entry_path(name: &Path, strip_components: usize) -> io::Result<Option<PathBuf>> {
+    let mut parts = Vec::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => parts.push(part),
+            Component::CurDir => {}
+            _ => return Err(unsafe_path(name)),
+        }
+    }
+    Ok(parts
+        .get(strip_components..)
+        .filter(|parts| !parts.is_empty())
+        .map(|parts| parts.iter().collect()))
+}
+
+/// Check that a symlink extracted at `relative` points inside the destination
+fn check_link_target(relative: &Path, target: &Path) -> io::Result<()> {
+    let mut depth = relative.components().count() as i64 - 1;
+    for component in target.components() {
+        match component {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return Err(unsafe_path(&relative.join(target))),
+        }
+    }
+    Ok(())
+}
+
+/// Check that no parent of an entry is a symlink, which could lead outside of the destination
+fn check_parents(dest: &Path, relative: &Path) -> io::Result<()> {
+    let mut parent = dest.to_path_buf();
+    let components: Vec<Component> = relative.components().collect();
+    for component in &components[..components.len().saturating_sub(1)] {
+        parent.push(component);
+        if std::fs::symlink_metadata(&parent).is_ok_and(|m| m.file_type().is_symlink()) {
+            return Err(unsafe_path(relative));
+        }
+    }
+    Ok(())
+}
+
+/// Create the parents of an entry and remove a symlink in its place, so it
+/// is never written through
+fn prepare_target(dest: &Path, relative: &Path) -> io::Result<PathBuf> {
+    check_parents(dest, relative)?;
+    let target = dest.join(relative);
+    if let Some(parent) = target.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    if std::fs::symlink_metadata(&target).is_ok_and(|m| m.file_type().is_symlink()) {
+        std::fs::remove_file(&target)?;
+    }
+    Ok(target)
+}
+
+/// Create the symlinks of an archive once its other entries are extracted,
+/// so that none of them is written through a link
+fn create_links(dest: &Path, links: &[(PathBuf, PathBuf)]) -> io::Result<()> {
+    for (relative, link) in links {
+        check_link_target(relative, link)?;
+        let target = prepare_target(dest, relative)?;
+        match std::fs::symlink_metadata(&target) {
+            Ok(metadata) if metadata.is_dir() => {
+                return Err(io::Error::new(
+                    ErrorKind::AlreadyExists,
+                    format!("refusing to replace directory {:?} with a link", relative),
+                ))
+            }
+            Ok(_) => std::fs::remove_file(&target)?,
+            Err(_) => {}
+        }
+        symlink(link, &target)?;
+    }
+
+    // Links through other links can lead elsewhere than their names suggest
+    let real_dest = dest.canonicalize()?;
+    let mut escaping = None;
+    for (relative, link) in links {
+        let target = dest.join(relative);
+        let parent = target.parent().unwrap_or(dest).canonicalize()?;
+        if !real_path(&parent.join(link)).starts_with(&real_dest) {
+            std::fs::remove_file(&target)?;
+            escaping.get_or_insert(relative);
+        }
+    }
+    match escaping {
+        Some(relative) => Err(unsafe_path(relative)),
+        None => Ok(()),
+    }
+}
+
+/// Extract, or in dry runs only list, the entries of an archive and return
+/// their paths relative to the destination
+fn extract(
+    path: &str,
+    dest: &Path,
+    format: Format,
+    options: &ArchiveOptions,
+    dry_run: bool,
+) -> io::Result<Vec<String>> {
+    let file = File::open(path)?;
+    let mut extracted = Vec::new();
+    let mut select = |name: &Path, is_dir: bool| -> io::Result<Option<PathBuf>> {
+        let Some(relative) = entry_path(name, options.strip_components)? else {
+            return Ok(None);
+        };
+        let name = slash_path(&relative);
+        let ancestors_excluded = relative.ancestors().any(|ancestor| {
+            !ancestor.as_os_str().is_empty() && options.excludes(&slash_path(ancestor))
+        });
+        if ancestors_excluded || !options.includes(&name, is_dir) {
+            return Ok(None);
+        }
+        extracted.push(name);
+        Ok(Some(relative).filter(|_| !dry_run))
+    };
+
+    // Symlinks are created last, each one (relative path, link target)
+    let mut links = Vec::new();
+
+    if format == Format::Zip {
+        let mut archive = zip::ZipArchive::new(BufReader::new(file)).map_err(io::Error::other)?;
+        for index in 0..archive.len() {
+            let mut entry = archive.by_index(index).map_err(io::Error::other)?;
+            let name = entry
+                .enclosed_name()
+                .ok_or_else(|| unsafe_path(Path::new(entry.name())))?;
+            let Some(relative) = select(&name, entry.is_dir())? else {
+                continue;
+            };
+
+            if entry.is_symlink() {
+                let mut link = String::new();
+                entry.read_to_string(&mut link)?;
+                check_link_target(&relative, Path::new(&link))?;
+                links.push((relative, PathBuf::from(link)));
+                continue;
+            }
+            let target = prepare_target(dest, &relative)?;
+            if entry.is_dir() {
+                std::fs::create_dir_all(&target)?;
+            } else {
+                io::copy(&mut entry, &mut File::create(&target)?)?;
+                #[cfg(unix)]
+                if let Some(mode) = entry.unix_mode() {
+                    use std::os::unix::fs::PermissionsExt;
+                    std::fs::set_permissions(
+                        &target,
+                        std::fs::Permissions::from_mode(mode & 0o777),
+                    )?;
+                }
+            }
+        }
+        create_links(dest, &links)?;
+        return Ok(extracted);
+    }
+
+    let mut archive = format.tar_reader(file);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_type = entry.header().entry_type();
+        let name = entry.path()?.into_owned();
+        let Some(relative) = select(&name, entry_type.is_dir())? else {
+            continue;
+        };
+
+        if let Some(link) = entry.link_name()? {
+            if entry_type.is_hard_link() {
+                // Hard links name another entry of the archive
+                let source = entry_path(&link, options.strip_components)?
+                    .ok_or_else(|| unsafe_path(&link))?;
+                check_parents(dest, &source)?;
+                let target = prepare_target(dest, &relative)?;
+                std::fs::hard_link(dest.join(source), &target)?;
+            } else {
+                check_link_target(&relative, &link)?;
+                links.push((relative, link.into_owned()));
+            }
+            continue;
+        }
+        let target = prepare_target(dest, &relative)?;
+        entry.unpack(&target)?;
+    }
+    create_links(dest, &links)?;
+    Ok(extracted)
+}
+
+/// List the names of the entries of an archive
+fn list(path: &str, format: Format) -> io::Result<Vec<String>> {
+    let file = File::open(path)?;
+    if format == Format::Zip {
+        let archive = zip::ZipArchive::new(BufReader::new(file)).map_err(io::Error::other)?;
+        return Ok(archive.file_names().map(str::to_string).collect());
+    }
+
+    let mut archive = format.tar_reader(file);
+    let mut names = Vec::new();
+    for entry in archive.entries()? {
+        let entry = entry?;
+        names.push(slash_path(&entry.path()?));
+    }
+    Ok(names)
+}
+
+impl Plugin for ArchivePlugin {
+    fn register(&self, lua: &Lua) -> LuaResult<()> {
+        let dry_run = self.dry_run;
+        let globals = lua.globals();
+        let archive = lua.create_table()?;
+
+        // create function (archives the content of a directory)
+        archive.set(
+            "create",
+            lua.create_function(
+                move |lua, (path, root, options): (String, String, Option<Table>)| {
+                    check_read(lua, &root)?;
+                    check_write(lua, &path)?;
+                    let options = ArchiveOptions::from_lua(lua, options)?;
+                    let format = Format::detect(&path, options.format.as_deref())?;
+
+                    let mut sources = Vec::new();
+                    collect_sources(Path::new(&root), Path::new(""), &options, &mut sources)
+                        .map_err(|e| {
+                            to_lua_error(e, &format!("Error reading directory {}", root))
+                        })?;
+                    let names: Vec<String> = sources.iter().map(|s| s.name(&options)).collect();
+                    if dry_run {
+                        log_dry_run(&format!("archive {} ({} entries)", path, names.len()));
+                        return Ok(names);
+                    }
+
+                    if let Err(e) = create(&path, &sources, format, &options) {
+                        // Never leave a truncated archive behind
+                        let _ = std::fs::remove_file(&path);
+                        return Err(to_lua_error(e, &format!("Error creating archive {}", path)));
+                    }
+                    Ok(names)
+                },
+            )?,
+        )?;
+
+        // extract function
+        archive.set(
+            "extract",
+            lua.create_function(
+                move |lua, (path, dest, options): (String, String, Option<Table>)| {
+                    check_read(lua, &path)?;
+                    check_write(lua, &dest)?;
+                    let options = ArchiveOptions::from_lua(lua, options)?;
+                    let format = Format::detect(&path, options.format.as_deref())?;
+                    if dry_run {
+                        log_dry_run(&format!("extract {} {}", path, dest));
+                    }
+
+                    extract(&path, Path::new(&dest), format, &options, dry_run)
+                        .map_err(|e| to_lua_error(e, &format!("Error extracting archive {}", path)))
+                },
+            )?,
+        )?;
+
+        // list function
+        archive.set(
+            "list",
+            lua.create_function(|lua, (path, options): (String, Option<Table>)| {
+                check_read(lua, &path)?;
+                let options = ArchiveOptions::from_lua(lua, options)?;
+                let format = Format::detect(&path, options.format.as_deref())?;
+                list(&path, format)
+                    .map_err(|e| to_lua_error(e, &format!("Error reading archive {}", path)))
+            })?,
+        )?;
+
+        globals.set("lake.archive", archive)?;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "archive"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    enum Entry<'a> {
+        File(&'a str, &'a str),
+        Link(&'a str, &'a str),
+    }
+
+    fn write_tar_entries(path: &Path, entries: &[Entry]) {
+        let mut builder = tar::Builder::new(File::create(path).unwrap());
+        for entry in entries {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(0o644);
+            match entry {
+                Entry::File(name, content) => {
+                    header.set_size(content.len() as u64);
+                    builder
+                        .append_data(&mut header, name, content.as_bytes())
+                        .unwrap();
+                }
+                Entry::Link(name, target) => {
+                    header.set_entry_type(tar::EntryType::Symlink);
+                    header.set_size(0);
+                    builder.append_link(&mut header, name, target).unwrap();
+                }
+            }
+        }
+        builder.finish().unwrap();
+    }
+
+    fn write_zip_entries(path: &Path, entries: &[Entry]) {
+        let mut writer = zip::ZipWriter::new(File::create(path).unwrap());
+        let options = zip::write::SimpleFileOptions::default();
+        for entry in entries {
+            match entry {
+                Entry::File(name, content) => {
+                    writer.start_file(*name, options).unwrap();
+                    writer.write_all(content.as_bytes()).unwrap();
+                }
+                Entry::Link(name, target) => writer.add_symlink(*name, *target, options).unwrap(),
+            }
+        }
+        writer.finish().unwrap();
+    }
+
+    fn extract_into(archive: &Path, dest: &Path, format: Format) -> io::Result<Vec<String>> {
+        let options = ArchiveOptions::from_lua(&Lua::new(), None).unwrap();
+        extract(&archive.to_string_lossy(), dest, format, &options, false)
+    }
+
+    #[test]
+    fn extracts_links_inside_the_destination() {
+        let dir = tempfile::tempdir().unwrap();
+        let entries = [
+            Entry::Link("docs/latest", "v1"),
+            Entry::File("docs/v1/index.txt", "index"),
+        ];
+        for (name, format) in [("a.tar", Format::Tar), ("a.zip", Format::Zip)] {
+            let archive = dir.path().join(name);
+            let dest = dir.path().join(format!("{}-out", name));
+            match format {
+                Format::Zip => write_zip_entries(&archive, &entries),
+                _ => write_tar_entries(&archive, &entries),
+            }
+
+            extract_into(&archive, &dest, format).unwrap();
+            let content = std::fs::read_to_string(dest.join("docs/latest/index.txt")).unwrap();
+            assert_eq!(content, "index");
+        }
+    }
+
+    #[test]
+    fn refuses_entries_escaping_through_links() {
+        let malicious: [&[Entry]; 3] = [
+            // A file written through links created earlier in the archive
+            &[
+                Entry::Link("a", "."),
+                Entry::Link("a/x", ".."),
+                Entry::File("a/x/pwned.txt", "pwned"),
+            ],
+            // A link resolved through another link
+            &[
+                Entry::Link("x/y", ".."),
+                Entry::Link("pwned.txt", "x/y/../../pwned.txt"),
+            ],
+            // A link leaving the destination
+            &[Entry::Link("pwned.txt", "../pwned.txt")],
+        ];
+
+        for entries in malicious {
+            for (name, format) in [("a.tar", Format::Tar), ("a.zip", Format::Zip)] {
+                let dir = tempfile::tempdir().unwrap();
+                let archive = dir.path().join(name);
+                let dest = dir.path().join("out");
+                match format {
+                    Format::Zip => write_zip_entries(&archive, entries),
+                    _ => write_tar_entries(&archive, entries),
+                }
+                std::fs::write(dir.path().join("pwned.txt"), "original").unwrap();
+
+                assert!(extract_into(&archive, &dest, format).is_err());
+                let outside = std::fs::read_to_string(dir.path().join("pwned.txt")).unwrap();
+                assert_eq!(outside, "original");
+                assert!(std::fs::symlink_metadata(dest.join("pwned.txt")).is_err());
+            }
+        }
+    }
+}
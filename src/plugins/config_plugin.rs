@@ -0,0 +1,79 @@
+//! Configuration plugin for Lake
+//!
+//! Exposes the merged lake.toml and user configuration to scripts, read-only.
+
+use crate::config::Config;
+use crate::plugins::Plugin;
+use mlua::{
+    Error as LuaError, Function, Lua, LuaSerdeExt, Result as LuaResult, SerializeOptions, Value,
+};
+
+pub struct ConfigPlugin {
+    config: Config,
+}
+
+impl ConfigPlugin {
+    pub fn new(config: Config) -> Self {
+        ConfigPlugin { config }
+    }
+}
+
+// Wrap tables in proxies that reject assignments, recursively
+fn read_only(lua: &Lua, value: Value) -> LuaResult<Value> {
+    let Value::Table(table) = value else {
+        return Ok(value);
+    };
+
+    let data = lua.create_table()?;
+    for pair in table.pairs::<Value, Value>() {
+        let (key, value) = pair?;
+        data.raw_set(key, read_only(lua, value)?)?;
+    }
+
+    let next: Function = lua.globals().get("next")?;
+    let metatable = lua.create_table()?;
+    metatable.set("__index", data.clone())?;
+    metatable.set(
+        "__newindex",
+        lua.create_function(|_, _: mlua::MultiValue| -> LuaResult<()> {
+            Err(LuaError::RuntimeError(
+                "lake.config is read-only".to_string(),
+            ))
+        })?,
+    )?;
+    metatable.set(
+        "__len",
+        lua.create_function({
+            let data = data.clone();
+            move |_, _: Value| Ok(data.raw_len())
+        })?,
+    )?;
+    metatable.set(
+        "__pairs",
+        lua.create_function(move |_, _: Value| Ok((next.clone(), data.clone(), Value::Nil)))?,
+    )?;
+    metatable.set("__metatable", false)?;
+
+    let proxy = lua.create_table()?;
+    proxy.set_metatable(Some(metatable));
+    Ok(Value::Table(proxy))
+}
+
+impl Plugin for ConfigPlugin {
+    fn register(&self, lua: &Lua) -> LuaResult<()> {
+        let globals = lua.globals();
+
+        // Unset options become nil instead of a null placeholder
+        let options = SerializeOptions::new()
+            .serialize_none_to_null(false)
+            .set_array_metatable(false);
+        let config = lua.to_value_with(&self.config, options)?;
+
+        globals.set("lake.config", read_only(lua, config)?)?;
+        Ok(())
+    }
+
+    fn name(&self) -> &str {
+        "config"
+    }
+}
@@ -31,7 +31,7 @@ impl Plugin for CryptoPlugin {
         crypto.set(
             "hash_sha256",
             lua.create_function(|_, data: String| {
-                return Ok(format!("{:x}", Sha256::digest(data.as_bytes())));
+                Ok(format!("{:x}", Sha256::digest(data.as_bytes())))
             })?,
         )?;
 
@@ -39,7 +39,7 @@ impl Plugin for CryptoPlugin {
         crypto.set(
             "hash_sha512",
             lua.create_function(|_, data: String| {
-                return Ok(format!("{:x}", Sha512::digest(data.as_bytes())));
+                Ok(format!("{:x}", Sha512::digest(data.as_bytes())))
             })?,
         )?;
 
@@ -47,7 +47,7 @@ impl Plugin for CryptoPlugin {
         crypto.set(
             "hash_md5",
             lua.create_function(|_, data: String| {
-                return Ok(format!("{:x}", md5_compute(data.as_bytes())));
+                Ok(format!("{:x}", md5_compute(data.as_bytes())))
             })?,
         )?;
 
@@ -61,9 +61,9 @@ impl Plugin for CryptoPlugin {
         crypto.set(
             "from_base64",
             lua.create_function(|_, data: String| {
-                Ok(BASE64_STANDARD
+                BASE64_STANDARD
                     .decode(data.as_bytes())
-                    .map_err(|e| to_lua_error(e, "Error decoding base64"))?)
+                    .map_err(|e| to_lua_error(e, "Error decoding base64"))
             })?,
         )?;
 
@@ -1,37 +1,141 @@
 //! Environment plugin for Lake
 //!
 //! Provides access to environment variables and system information.
+//!
+//! `lake.env.set` never changes Lake's own environment, which every thread
+//! shares. Variables set at the top level of a build file apply to the whole
+//! build, those set by a task to that task and, once it finished, to the
+//! tasks that depend on it. Tasks running at the same time do not see each
+//! other's variables. They are seen by `lake.env.get` and passed to the
+//! commands the build runs.
+
+use std::collections::{BTreeMap, HashMap};
 
-use crate::plugins::Plugin;
+use crate::permissions::check_env;
+use crate::plugins::{log_dry_run, Plugin};
+use crate::sandbox::CurrentTask;
 use mlua::{Lua, Result as LuaResult};
 
-pub struct EnvPlugin;
+pub struct EnvPlugin {
+    dry_run: bool,
+}
 
 impl EnvPlugin {
-    pub fn new() -> Self {
-        EnvPlugin
+    pub fn new(dry_run: bool) -> Self {
+        EnvPlugin { dry_run }
+    }
+}
+
+/// Variables set by `lake.env.set`
+#[derive(Default)]
+struct ScriptEnv {
+    /// Set outside of tasks
+    build: BTreeMap<String, String>,
+    /// Set by each task
+    tasks: HashMap<String, BTreeMap<String, String>>,
+}
+
+/// Get a variable as the running task sees it
+pub fn var(lua: &Lua, name: &str) -> Option<String> {
+    let task = lua.app_data_ref::<CurrentTask>();
+    if let Some(script_env) = lua.app_data_ref::<ScriptEnv>() {
+        let task_value = task
+            .and_then(|task| script_env.tasks.get(&task.0))
+            .and_then(|vars| vars.get(name));
+        if let Some(value) = task_value.or_else(|| script_env.build.get(name)) {
+            return Some(value.clone());
+        }
+    }
+    std::env::var(name).ok()
+}
+
+/// Get the variables set by the build for the running task, to pass to commands
+pub fn overrides(lua: &Lua) -> Vec<(String, String)> {
+    let Some(script_env) = lua.app_data_ref::<ScriptEnv>() else {
+        return Vec::new();
+    };
+    let mut vars = script_env.build.clone();
+    if let Some(task_vars) = lua
+        .app_data_ref::<CurrentTask>()
+        .and_then(|task| script_env.tasks.get(&task.0))
+    {
+        vars.extend(task_vars.clone());
+    }
+    vars.into_iter().collect()
+}
+
+/// Set the variables of the project configuration for the whole build,
+/// unless the environment already defines them. Variables that are not
+/// granted are left out, a checked out project could otherwise choose what
+/// granted programs run through variables such as `PATH` or `LD_PRELOAD`.
+pub fn set_project_vars(lua: &Lua, vars: &BTreeMap<String, String>) {
+    for (name, value) in vars {
+        if std::env::var_os(name).is_some() {
+            continue;
+        }
+        match check_env(lua, name) {
+            Ok(()) => set_var(lua, name.clone(), value.clone()),
+            Err(e) => log::warn!("Not setting {} from lake.toml: {}", name, e),
+        }
+    }
+}
+
+/// Start the variables of a task from those of the finished tasks it waited
+/// for, later ones taking precedence
+pub fn inherit_env(lua: &Lua, task: &str, finished: &[&str]) {
+    let Some(mut script_env) = lua.app_data_mut::<ScriptEnv>() else {
+        return;
+    };
+    let mut vars = BTreeMap::new();
+    for name in finished {
+        if let Some(task_vars) = script_env.tasks.get(*name) {
+            vars.extend(task_vars.clone());
+        }
+    }
+    script_env.tasks.insert(task.to_string(), vars);
+}
+
+/// Set a variable for the running task, or for the whole build outside of tasks
+fn set_var(lua: &Lua, name: String, value: String) {
+    let task = lua.app_data_ref::<CurrentTask>().map(|task| task.0.clone());
+    if lua.app_data_ref::<ScriptEnv>().is_none() {
+        lua.set_app_data(ScriptEnv::default());
+    }
+    if let Some(mut script_env) = lua.app_data_mut::<ScriptEnv>() {
+        let vars = match task {
+            Some(task) => script_env.tasks.entry(task).or_default(),
+            None => &mut script_env.build,
+        };
+        vars.insert(name, value);
     }
 }
 
 impl Plugin for EnvPlugin {
     fn register(&self, lua: &Lua) -> LuaResult<()> {
+        let dry_run = self.dry_run;
         let globals = lua.globals();
         let env = lua.create_table()?;
 
         // get function
         env.set(
             "get",
-            lua.create_function(|_, name: String| match std::env::var(&name) {
-                Ok(value) => Ok(Some(value)),
-                Err(_) => Ok(None),
+            lua.create_function(|lua, name: String| {
+                check_env(lua, &name)?;
+                Ok(var(lua, &name))
             })?,
         )?;
 
         // set function
         env.set(
             "set",
-            lua.create_function(|_, (name, value): (String, String)| {
-                std::env::set_var(name, value);
+            lua.create_function(move |lua, (name, value): (String, String)| {
+                check_env(lua, &name)?;
+                if dry_run {
+                    log_dry_run(&format!("set {}={}", name, value));
+                    return Ok(());
+                }
+
+                set_var(lua, name, value);
                 Ok(())
             })?,
         )?;
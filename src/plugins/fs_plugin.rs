@@ -2,15 +2,22 @@
 //!
 //! Provides file and directory operations.
 
-use crate::plugins::Plugin;
-use mlua::{Error as LuaError, Lua, Result as LuaResult};
-use std::path::Path;
+use crate::permissions::{check_read, check_write};
+use crate::plugins::{log_dry_run, Plugin};
+use glob::{MatchOptions, Pattern};
+use mlua::{Error as LuaError, Lua, Result as LuaResult, Table, Value};
+use std::fs::{FileType, Metadata};
+use std::io::{BufRead, BufReader, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-pub struct FsPlugin;
+pub struct FsPlugin {
+    dry_run: bool,
+}
 
 impl FsPlugin {
-    pub fn new() -> Self {
-        FsPlugin
+    pub fn new(dry_run: bool) -> Self {
+        FsPlugin { dry_run }
     }
 }
 
@@ -19,15 +26,769 @@ fn to_lua_error<E: std::error::Error + Send + Sync + 'static>(e: E, context: &st
     LuaError::RuntimeError(format!("{}: {}", context, e))
 }
 
+fn file_type_name(file_type: FileType) -> &'static str {
+    if file_type.is_file() {
+        "file"
+    } else if file_type.is_dir() {
+        "dir"
+    } else if file_type.is_symlink() {
+        "symlink"
+    } else {
+        "other"
+    }
+}
+
+/// Permission bits of a file, derived from its read-only flag outside Unix
+fn mode(metadata: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        metadata.permissions().mode() & 0o7777
+    }
+    #[cfg(not(unix))]
+    {
+        if metadata.permissions().readonly() {
+            0o444
+        } else {
+            0o666
+        }
+    }
+}
+
+/// Describe a file, following symlinks unless they are broken
+fn stat(lua: &Lua, path: &str) -> LuaResult<Table> {
+    let link = std::fs::symlink_metadata(path)
+        .map_err(|e| to_lua_error(e, &format!("Error reading metadata of {}", path)))?;
+    let metadata = std::fs::metadata(path).unwrap_or_else(|_| link.clone());
+
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+        .map(|elapsed| elapsed.as_secs_f64());
+    let mode = mode(&metadata);
+
+    let stat = lua.create_table()?;
+    stat.set("type", file_type_name(metadata.file_type()))?;
+    stat.set("size", metadata.len())?;
+    stat.set("mtime", mtime)?;
+    stat.set("permissions", mode)?;
+    stat.set("mode", format!("{:o}", mode))?;
+    stat.set("readonly", metadata.permissions().readonly())?;
+    stat.set("is_symlink", link.file_type().is_symlink())?;
+    Ok(stat)
+}
+
+/// Apply a mode to the current permission bits: a number, octal digits such
+/// as `"755"`, or symbolic clauses such as `"+x"` and `"u+rw,go-w"`
+fn parse_mode(spec: &Value, current: u32) -> LuaResult<u32> {
+    let invalid = |spec: &str| {
+        LuaError::RuntimeError(format!(
+            "Invalid mode {:?}: expected octal digits such as \"755\" or clauses such as \"u+x\"",
+            spec
+        ))
+    };
+    let spec = match spec {
+        Value::Integer(mode) if (0..=0o7777).contains(mode) => return Ok(*mode as u32),
+        Value::Integer(mode) => {
+            return Err(LuaError::RuntimeError(format!(
+                "Invalid mode {}: expected permission bits between 0 and 0o7777",
+                mode
+            )))
+        }
+        Value::String(spec) => spec.to_str()?.to_string(),
+        other => {
+            return Err(LuaError::RuntimeError(format!(
+                "Invalid mode: expected a number or a string, got {}",
+                other.type_name()
+            )))
+        }
+    };
+    if !spec.is_empty() && spec.chars().all(|c| c.is_digit(8)) {
+        return u32::from_str_radix(&spec, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| invalid(&spec));
+    }
+
+    let mut mode = current;
+    for clause in spec.split(',') {
+        let op = clause.find(['+', '-', '=']).ok_or_else(|| invalid(&spec))?;
+        let mut who = 0;
+        for c in clause[..op].chars() {
+            who |= match c {
+                'u' => 0o700,
+                'g' => 0o070,
+                'o' => 0o007,
+                'a' => 0o777,
+                _ => return Err(invalid(&spec)),
+            };
+        }
+        if who == 0 {
+            who = 0o777;
+        }
+        let mut bits = 0;
+        for c in clause[op + 1..].chars() {
+            bits |= match c {
+                'r' => 0o444,
+                'w' => 0o222,
+                'x' => 0o111,
+                _ => return Err(invalid(&spec)),
+            };
+        }
+        bits &= who;
+        match &clause[op..op + 1] {
+            "+" => mode |= bits,
+            "-" => mode &= !bits,
+            _ => mode = (mode & !who) | bits,
+        }
+    }
+    Ok(mode)
+}
+
+/// Set the permission bits of a file, only its read-only flag outside Unix
+fn set_mode(path: &str, mode: u32) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))
+    }
+    #[cfg(not(unix))]
+    {
+        let mut permissions = std::fs::metadata(path)?.permissions();
+        permissions.set_readonly(mode & 0o222 == 0);
+        std::fs::set_permissions(path, permissions)
+    }
+}
+
+/// Rename a file or directory, copying files that move to another filesystem
+fn move_path(src: &str, dst: &str) -> std::io::Result<()> {
+    match std::fs::rename(src, dst) {
+        Err(e) if e.kind() == ErrorKind::CrossesDevices && Path::new(src).is_file() => {
+            std::fs::copy(src, dst)?;
+            std::fs::remove_file(src)
+        }
+        result => result,
+    }
+}
+
+pub fn symlink(target: impl AsRef<Path>, link: impl AsRef<Path>) -> std::io::Result<()> {
+    let (target, link) = (target.as_ref(), link.as_ref());
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink(target, link)
+    }
+    #[cfg(windows)]
+    {
+        // Relative targets are resolved from the directory of the link
+        let resolved = link.parent().unwrap_or(Path::new("")).join(target);
+        if resolved.is_dir() {
+            std::os::windows::fs::symlink_dir(target, link)
+        } else {
+            std::os::windows::fs::symlink_file(target, link)
+        }
+    }
+}
+
+/// Read a list of glob patterns, given as a string or an array of strings
+pub fn patterns(options: &Table, key: &str) -> LuaResult<Vec<Pattern>> {
+    let globs = match options.get::<Value>(key)? {
+        Value::Nil => Vec::new(),
+        Value::String(glob) => vec![glob.to_str()?.to_string()],
+        Value::Table(globs) => globs
+            .sequence_values::<String>()
+            .collect::<LuaResult<_>>()?,
+        other => {
+            return Err(LuaError::RuntimeError(format!(
+                "Invalid {}: expected a glob or a list of globs, got {}",
+                key,
+                other.type_name()
+            )))
+        }
+    };
+    globs
+        .iter()
+        .map(|glob| {
+            Pattern::new(glob)
+                .map_err(|e| to_lua_error(e, &format!("Invalid glob pattern: {:?}", glob)))
+        })
+        .collect()
+}
+
+/// Match a glob against a path relative to the root of a tree, with `/`
+/// separators. Globs without a `/` match the file name at any depth.
+pub fn glob_matches(pattern: &Pattern, relative: &str) -> bool {
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    if pattern.as_str().contains('/') {
+        pattern.matches_with(relative, options)
+    } else {
+        let name = relative.rsplit('/').next().unwrap_or(relative);
+        pattern.matches_with(name, options)
+    }
+}
+
+/// Render a relative path with `/` separators, for globs and summaries
+pub fn slash_path(path: &Path) -> String {
+    let parts: Vec<_> = path
+        .components()
+        .map(|part| part.as_os_str().to_string_lossy())
+        .collect();
+    parts.join("/")
+}
+
+/// How a tree copy treats files that already exist in the destination
+#[derive(Clone, Copy, PartialEq)]
+enum Overwrite {
+    Always,
+    Never,
+    /// Replace files older than the source
+    Newer,
+    /// Replace files that differ from the source in size or modification time
+    Changed,
+    /// Fail on the first existing file
+    Error,
+}
+
+/// Options of `copy_dir` and `sync`
+struct TreeOptions {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    overwrite: Overwrite,
+    /// Keep the modification times of the files, their permissions are always kept
+    preserve_times: bool,
+    /// Remove the destination files missing from the source
+    delete: bool,
+}
+
+impl TreeOptions {
+    fn from_lua(options: Option<Table>, overwrite: Overwrite) -> LuaResult<Self> {
+        let Some(options) = options else {
+            return Ok(TreeOptions {
+                include: Vec::new(),
+                exclude: Vec::new(),
+                overwrite,
+                preserve_times: true,
+                delete: false,
+            });
+        };
+
+        let overwrite = match options.get::<Option<String>>("overwrite")?.as_deref() {
+            None => overwrite,
+            Some("always") => Overwrite::Always,
+            Some("never") => Overwrite::Never,
+            Some("newer") => Overwrite::Newer,
+            Some("changed") => Overwrite::Changed,
+            Some("error") => Overwrite::Error,
+            Some(other) => {
+                return Err(LuaError::RuntimeError(format!(
+                    "Invalid overwrite policy {:?}: expected \"always\", \"never\", \"newer\", \"changed\" or \"error\"",
+                    other
+                )))
+            }
+        };
+
+        Ok(TreeOptions {
+            include: patterns(&options, "include")?,
+            exclude: patterns(&options, "exclude")?,
+            overwrite,
+            preserve_times: options
+                .get::<Option<bool>>("preserve_times")?
+                .unwrap_or(true),
+            delete: options.get::<Option<bool>>("delete")?.unwrap_or(false),
+        })
+    }
+
+    /// Excluded directories are skipped along with their content
+    fn excludes(&self, relative: &str) -> bool {
+        self.exclude.iter().any(|p| glob_matches(p, relative))
+    }
+
+    fn includes(&self, relative: &str) -> bool {
+        self.include.is_empty() || self.include.iter().any(|p| glob_matches(p, relative))
+    }
+}
+
+/// Files copied, skipped and deleted by a tree copy, relative to its roots
+#[derive(Default)]
+struct CopySummary {
+    copied: Vec<String>,
+    skipped: Vec<String>,
+    deleted: Vec<String>,
+}
+
+impl CopySummary {
+    fn to_lua(&self, lua: &Lua) -> LuaResult<Table> {
+        let summary = lua.create_table()?;
+        summary.set("copied", self.copied.clone())?;
+        summary.set("skipped", self.skipped.clone())?;
+        summary.set("deleted", self.deleted.clone())?;
+        Ok(summary)
+    }
+}
+
+/// A tree copy from `src` to `dst`, which only records its actions in dry runs
+struct TreeCopy<'a> {
+    lua: &'a Lua,
+    src: &'a Path,
+    dst: &'a Path,
+    options: TreeOptions,
+    dry_run: bool,
+    summary: CopySummary,
+}
+
+impl TreeCopy<'_> {
+    /// Check that a destination entry may be written. Links already in the
+    /// destination are resolved, so none of them can lead the copy outside of
+    /// the writable paths. An entry replaced or removed rather than written
+    /// through is checked in its directory.
+    fn check_target(&self, path: &Path, written_through: bool) -> LuaResult<()> {
+        let target = self.dst.join(path);
+        match target.parent() {
+            Some(parent) if !written_through => check_write(self.lua, parent),
+            _ => check_write(self.lua, &target),
+        }
+    }
+
+    /// Copy the content of a directory, given relative to the roots
+    fn copy_dir(&mut self, relative: &Path) -> LuaResult<()> {
+        let dir = self.src.join(relative);
+        let mut entries = std::fs::read_dir(&dir)
+            .and_then(|entries| entries.collect::<std::io::Result<Vec<_>>>())
+            .map_err(|e| to_lua_error(e, &format!("Error listing directory {}", dir.display())))?;
+        // Sorted, so summaries and errors do not depend on the filesystem
+        entries.sort_by_key(|entry| entry.file_name());
+
+        for entry in &entries {
+            let path = relative.join(entry.file_name());
+            let name = slash_path(&path);
+            if self.options.excludes(&name) {
+                continue;
+            }
+            let file_type = entry
+                .file_type()
+                .map_err(|e| to_lua_error(e, &format!("Error reading metadata of {}", name)))?;
+
+            if file_type.is_dir() {
+                // Without includes the directory structure is copied, empty directories too
+                if self.options.include.is_empty() && !self.dry_run {
+                    self.check_target(&path, true)?;
+                    let target = self.dst.join(&path);
+                    std::fs::create_dir_all(&target).map_err(|e| {
+                        to_lua_error(e, &format!("Error creating directory {}", target.display()))
+                    })?;
+                }
+                self.copy_dir(&path)?;
+            } else if self.options.includes(&name) {
+                if self.should_copy(&path)? {
+                    self.check_target(&path, !file_type.is_symlink())?;
+                    if !self.dry_run {
+                        self.copy_file(&path, file_type).map_err(|e| {
+                            to_lua_error(
+                                e,
+                                &format!("Error copying {}", self.src.join(&path).display()),
+                            )
+                        })?;
+                    }
+                    self.summary.copied.push(name);
+                } else {
+                    self.summary.skipped.push(name);
+                }
+            }
+        }
+
+        if self.options.delete {
+            self.delete_extras(relative)?;
+        }
+        Ok(())
+    }
+
+    /// Check whether a file must be copied over the destination
+    fn should_copy(&self, path: &Path) -> LuaResult<bool> {
+        let (src, dst) = (self.src.join(path), self.dst.join(path));
+        let Ok(existing) = std::fs::symlink_metadata(&dst) else {
+            return Ok(true);
+        };
+        let source = std::fs::symlink_metadata(&src).map_err(|e| {
+            to_lua_error(e, &format!("Error reading metadata of {}", src.display()))
+        })?;
+        let modified = |metadata: &Metadata| metadata.modified().ok();
+
+        Ok(match self.options.overwrite {
+            Overwrite::Always => true,
+            Overwrite::Never => false,
+            Overwrite::Newer => modified(&source) > modified(&existing),
+            Overwrite::Changed if source.file_type().is_symlink() => {
+                std::fs::read_link(&src).ok() != std::fs::read_link(&dst).ok()
+            }
+            Overwrite::Changed => {
+                source.len() != existing.len() || modified(&source) != modified(&existing)
+            }
+            Overwrite::Error => {
+                return Err(LuaError::RuntimeError(format!(
+                    "Error copying {}: {} already exists",
+                    src.display(),
+                    dst.display()
+                )))
+            }
+        })
+    }
+
+    /// Copy a file, recreating symlinks as links
+    fn copy_file(&self, path: &Path, file_type: FileType) -> std::io::Result<()> {
+        let (src, dst) = (self.src.join(path), self.dst.join(path));
+        if let Some(parent) = dst.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        if file_type.is_symlink() {
+            let target = std::fs::read_link(&src)?;
+            if std::fs::symlink_metadata(&dst).is_ok() {
+                std::fs::remove_file(&dst)?;
+            }
+            return symlink(target, &dst);
+        }
+
+        std::fs::copy(&src, &dst)?;
+        if self.options.preserve_times {
+            let modified = std::fs::metadata(&src)?.modified()?;
+            std::fs::OpenOptions::new()
+                .write(true)
+                .open(&dst)?
+                .set_modified(modified)?;
+        }
+        Ok(())
+    }
+
+    /// Remove the entries of a destination directory missing from the source.
+    /// Excluded files are left alone.
+    fn delete_extras(&mut self, relative: &Path) -> LuaResult<()> {
+        let mut deleted = Vec::new();
+        for (path, is_dir) in self.destination_entries(relative)? {
+            if std::fs::symlink_metadata(self.src.join(&path)).is_err() {
+                self.delete_extra(&path, is_dir, &mut deleted)?;
+            }
+        }
+        self.summary.deleted.extend(deleted);
+        Ok(())
+    }
+
+    /// Remove a destination entry missing from the source, applying the
+    /// filters to the files of directories, which are only removed once
+    /// emptied. Returns whether the entry is gone, adding the removed paths
+    /// to `deleted`, or only the directory when all of it is removed.
+    fn delete_extra(
+        &mut self,
+        path: &Path,
+        is_dir: bool,
+        deleted: &mut Vec<String>,
+    ) -> LuaResult<bool> {
+        let name = slash_path(path);
+        if self.options.excludes(&name) || (!is_dir && !self.options.includes(&name)) {
+            return Ok(false);
+        }
+
+        self.check_target(path, false)?;
+        let target = self.dst.join(path);
+        if is_dir {
+            let mut nested = Vec::new();
+            let mut emptied = true;
+            for (child, child_is_dir) in self.destination_entries(path)? {
+                emptied &= self.delete_extra(&child, child_is_dir, &mut nested)?;
+            }
+            if !emptied {
+                deleted.extend(nested);
+                return Ok(false);
+            }
+        }
+
+        if !self.dry_run {
+            let removed = if is_dir {
+                std::fs::remove_dir(&target)
+            } else {
+                std::fs::remove_file(&target)
+            };
+            removed
+                .map_err(|e| to_lua_error(e, &format!("Error removing {}", target.display())))?;
+        }
+        deleted.push(name);
+        Ok(true)
+    }
+
+    /// Entries of a destination directory, sorted, and whether they are directories
+    fn destination_entries(&self, relative: &Path) -> LuaResult<Vec<(PathBuf, bool)>> {
+        let dir = self.dst.join(relative);
+        let Ok(entries) = std::fs::read_dir(&dir) else {
+            return Ok(Vec::new());
+        };
+        let mut entries = entries
+            .collect::<std::io::Result<Vec<_>>>()
+            .map_err(|e| to_lua_error(e, &format!("Error listing directory {}", dir.display())))?;
+        entries.sort_by_key(|entry| entry.file_name());
+
+        Ok(entries
+            .iter()
+            .map(|entry| {
+                let is_dir = entry.file_type().is_ok_and(|file_type| file_type.is_dir());
+                (relative.join(entry.file_name()), is_dir)
+            })
+            .collect())
+    }
+}
+
+/// Resolve the links of a path that may not exist yet, through its closest existing ancestor
+pub fn real_path(path: &Path) -> PathBuf {
+    let absolute = std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+    let mut missing = Vec::new();
+    let mut existing = absolute.as_path();
+    loop {
+        if let Ok(real) = std::fs::canonicalize(existing) {
+            return missing
+                .iter()
+                .rev()
+                .fold(real, |real, name| real.join(name));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return absolute,
+        }
+    }
+}
+
+/// Write a file through a temporary file renamed over it, so that an
+/// interrupted build never leaves it half-written
+fn write_atomic(path: &Path, content: &[u8]) -> std::io::Result<()> {
+    // Replace the target of a symlink rather than the link
+    let path = match std::fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_symlink() => std::fs::canonicalize(path)?,
+        _ => path.to_path_buf(),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| std::io::Error::new(ErrorKind::InvalidInput, "not a file path"))?;
+    let temp = path.with_file_name(format!(
+        ".{}.{}.tmp",
+        name.to_string_lossy(),
+        uuid::Uuid::new_v4().simple()
+    ));
+
+    let written = std::fs::File::create(&temp).and_then(|mut file| {
+        file.write_all(content)?;
+        // A replaced file keeps its permissions
+        if let Ok(metadata) = std::fs::metadata(&path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.sync_all()?;
+        std::fs::rename(&temp, &path)
+    });
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
+}
+
+/// Options of `walk`
+struct WalkOptions {
+    max_depth: Option<usize>,
+    /// Only yield entries of this type: "file", "dir" or "symlink"
+    file_type: Option<String>,
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>,
+    follow_symlinks: bool,
+    /// Skip the files ignored by `.gitignore`, `.ignore` and `.git/info/exclude`
+    gitignore: bool,
+    /// Return a list of paths instead of an iterator
+    collect: bool,
+}
+
+impl WalkOptions {
+    fn from_lua(options: Option<Table>) -> LuaResult<Self> {
+        let Some(options) = options else {
+            return Ok(WalkOptions {
+                max_depth: None,
+                file_type: None,
+                include: Vec::new(),
+                exclude: Vec::new(),
+                follow_symlinks: false,
+                gitignore: false,
+                collect: false,
+            });
+        };
+
+        let file_type = options.get::<Option<String>>("type")?;
+        if let Some(other) = file_type
+            .as_deref()
+            .filter(|t| !["file", "dir", "symlink"].contains(t))
+        {
+            return Err(LuaError::RuntimeError(format!(
+                "Invalid type {:?}: expected \"file\", \"dir\" or \"symlink\"",
+                other
+            )));
+        }
+
+        Ok(WalkOptions {
+            max_depth: options.get("max_depth")?,
+            file_type,
+            include: patterns(&options, "include")?,
+            exclude: patterns(&options, "exclude")?,
+            follow_symlinks: options
+                .get::<Option<bool>>("follow_symlinks")?
+                .unwrap_or(false),
+            gitignore: options.get::<Option<bool>>("gitignore")?.unwrap_or(false),
+            collect: options.get::<Option<bool>>("collect")?.unwrap_or(false),
+        })
+    }
+}
+
+/// Entries of a tree in depth-first order, sorted by name in each directory,
+/// with excluded directories skipped along with their content
+struct Walk {
+    entries: ignore::Walk,
+    root: PathBuf,
+    file_type: Option<String>,
+    include: Vec<Pattern>,
+}
+
+impl Walk {
+    fn new(root: &str, options: WalkOptions) -> Self {
+        let root = PathBuf::from(root);
+        let exclude = options.exclude;
+        let filter_root = root.clone();
+        let gitignore = options.gitignore;
+
+        let mut builder = ignore::WalkBuilder::new(&root);
+        builder
+            .standard_filters(false)
+            .git_ignore(options.gitignore)
+            .git_exclude(options.gitignore)
+            .ignore(options.gitignore)
+            .parents(options.gitignore)
+            // Ignore files apply outside of git repositories too
+            .require_git(false)
+            .follow_links(options.follow_symlinks)
+            .max_depth(options.max_depth)
+            .sort_by_file_name(|a, b| a.cmp(b))
+            .filter_entry(move |entry| {
+                let relative = entry
+                    .path()
+                    .strip_prefix(&filter_root)
+                    .unwrap_or(entry.path());
+                let name = slash_path(relative);
+                // Repositories do not list their own metadata in their ignore files
+                if gitignore && entry.file_name() == ".git" {
+                    return false;
+                }
+                name.is_empty() || !exclude.iter().any(|p| glob_matches(p, &name))
+            });
+
+        Walk {
+            entries: builder.build(),
+            root,
+            file_type: options.file_type,
+            include: options.include,
+        }
+    }
+
+    /// Next entry as its path, type and depth below the root
+    fn next_entry(&mut self, lua: &Lua) -> LuaResult<Option<(String, &'static str, usize)>> {
+        for entry in self.entries.by_ref() {
+            let entry = entry.map_err(|e| {
+                to_lua_error(
+                    e,
+                    &format!("Error walking directory {}", self.root.display()),
+                )
+            })?;
+            if entry.depth() == 0 {
+                continue;
+            }
+
+            let file_type = entry.file_type().map_or("other", file_type_name);
+            if self.file_type.as_deref().is_some_and(|t| t != file_type) {
+                continue;
+            }
+            let relative = entry
+                .path()
+                .strip_prefix(&self.root)
+                .unwrap_or(entry.path());
+            let name = slash_path(relative);
+            if !self.include.is_empty() && !self.include.iter().any(|p| glob_matches(p, &name)) {
+                continue;
+            }
+
+            // Followed links may lead outside the readable paths
+            check_read(lua, entry.path())?;
+            return Ok(Some((
+                entry.path().to_string_lossy().to_string(),
+                file_type,
+                entry.depth(),
+            )));
+        }
+        Ok(None)
+    }
+}
+
+/// Copy a directory tree and return the summary of the copy, checked by the caller
+fn copy_tree(
+    lua: &Lua,
+    src: &str,
+    dst: &str,
+    options: TreeOptions,
+    dry_run: bool,
+) -> LuaResult<Table> {
+    if !Path::new(src).is_dir() {
+        return Err(LuaError::RuntimeError(format!(
+            "Error copying directory {}: not a directory",
+            src
+        )));
+    }
+
+    // A destination inside the source would be copied into itself, and a
+    // source inside the destination would be deleted as an extra
+    let (real_src, real_dst) = (real_path(Path::new(src)), real_path(Path::new(dst)));
+    if real_dst.starts_with(&real_src) || (options.delete && real_src.starts_with(&real_dst)) {
+        return Err(LuaError::RuntimeError(format!(
+            "Error copying directory {} to {}: the directories overlap",
+            src, dst
+        )));
+    }
+
+    let mut copy = TreeCopy {
+        lua,
+        src: Path::new(src),
+        dst: Path::new(dst),
+        options,
+        dry_run,
+        summary: CopySummary::default(),
+    };
+    if !dry_run {
+        std::fs::create_dir_all(dst)
+            .map_err(|e| to_lua_error(e, &format!("Error creating directory {}", dst)))?;
+    }
+    copy.copy_dir(Path::new(""))?;
+    copy.summary.to_lua(lua)
+}
+
 impl Plugin for FsPlugin {
     fn register(&self, lua: &Lua) -> LuaResult<()> {
+        let dry_run = self.dry_run;
         let globals = lua.globals();
         let fs = lua.create_table()?;
 
         // mkdir function
         fs.set(
             "mkdir",
-            lua.create_function(|_, path: String| {
+            lua.create_function(move |lua, path: String| {
+                check_write(lua, &path)?;
+                if dry_run {
+                    log_dry_run(&format!("mkdir {}", path));
+                    return Ok(true);
+                }
+
                 std::fs::create_dir_all(&path)
                     .map(|_| true)
                     .map_err(|e| to_lua_error(e, &format!("Error creating directory {}", path)))
@@ -37,7 +798,13 @@ impl Plugin for FsPlugin {
         // rmdir function
         fs.set(
             "rmdir",
-            lua.create_function(|_, path: String| {
+            lua.create_function(move |lua, path: String| {
+                check_write(lua, &path)?;
+                if dry_run {
+                    log_dry_run(&format!("rmdir {}", path));
+                    return Ok(true);
+                }
+
                 let path = Path::new(&path);
                 if path.exists() {
                     std::fs::remove_dir_all(path).map(|_| true).map_err(|e| {
@@ -52,7 +819,13 @@ impl Plugin for FsPlugin {
         // rm function
         fs.set(
             "rm",
-            lua.create_function(|_, path: String| {
+            lua.create_function(move |lua, path: String| {
+                check_write(lua, &path)?;
+                if dry_run {
+                    log_dry_run(&format!("rm {}", path));
+                    return Ok(true);
+                }
+
                 std::fs::remove_file(&path)
                     .map(|_| true)
                     .map_err(|e| to_lua_error(e, &format!("Error removing file {}", path)))
@@ -62,29 +835,102 @@ impl Plugin for FsPlugin {
         // copy function
         fs.set(
             "copy",
-            lua.create_function(|_, (src, dst): (String, String)| {
+            lua.create_function(move |lua, (src, dst): (String, String)| {
+                check_read(lua, &src)?;
+                check_write(lua, &dst)?;
+                if dry_run {
+                    log_dry_run(&format!("copy {} {}", src, dst));
+                    return Ok(true);
+                }
+
                 std::fs::copy(&src, &dst)
                     .map(|_| true)
                     .map_err(|e| to_lua_error(e, &format!("Error copying {} to {}", src, dst)))
             })?,
         )?;
 
+        // copy_dir function
+        fs.set(
+            "copy_dir",
+            lua.create_function(
+                move |lua, (src, dst, options): (String, String, Option<Table>)| {
+                    let options = TreeOptions::from_lua(options, Overwrite::Always)?;
+                    check_read(lua, &src)?;
+                    check_write(lua, &dst)?;
+                    if dry_run {
+                        log_dry_run(&format!("copy_dir {} {}", src, dst));
+                    }
+                    copy_tree(lua, &src, &dst, options, dry_run)
+                },
+            )?,
+        )?;
+
+        // sync function (copies only the changed files)
+        fs.set(
+            "sync",
+            lua.create_function(
+                move |lua, (src, dst, options): (String, String, Option<Table>)| {
+                    let options = TreeOptions::from_lua(options, Overwrite::Changed)?;
+                    check_read(lua, &src)?;
+                    check_write(lua, &dst)?;
+                    if dry_run {
+                        log_dry_run(&format!("sync {} {}", src, dst));
+                    }
+                    copy_tree(lua, &src, &dst, options, dry_run)
+                },
+            )?,
+        )?;
+
+        // move function, also available as rename
+        let move_fn = lua.create_function(move |lua, (src, dst): (String, String)| {
+            check_write(lua, &src)?;
+            check_write(lua, &dst)?;
+            if dry_run {
+                log_dry_run(&format!("move {} {}", src, dst));
+                return Ok(true);
+            }
+
+            move_path(&src, &dst)
+                .map(|_| true)
+                .map_err(|e| to_lua_error(e, &format!("Error moving {} to {}", src, dst)))
+        })?;
+        fs.set("move", move_fn.clone())?;
+        fs.set("rename", move_fn)?;
+
         // exists function (no errors to propagate)
         fs.set(
             "exists",
-            lua.create_function(|_, path: String| Ok(Path::new(&path).exists()))?,
+            lua.create_function(|lua, path: String| {
+                check_read(lua, &path)?;
+                Ok(Path::new(&path).exists())
+            })?,
         )?;
 
         // is_file function (no errors to propagate)
         fs.set(
             "is_file",
-            lua.create_function(|_, path: String| Ok(Path::new(&path).is_file()))?,
+            lua.create_function(|lua, path: String| {
+                check_read(lua, &path)?;
+                Ok(Path::new(&path).is_file())
+            })?,
         )?;
 
         // is_dir function (no errors to propagate)
         fs.set(
             "is_dir",
-            lua.create_function(|_, path: String| Ok(Path::new(&path).is_dir()))?,
+            lua.create_function(|lua, path: String| {
+                check_read(lua, &path)?;
+                Ok(Path::new(&path).is_dir())
+            })?,
+        )?;
+
+        // stat function
+        fs.set(
+            "stat",
+            lua.create_function(|lua, path: String| {
+                check_read(lua, &path)?;
+                stat(lua, &path)
+            })?,
         )?;
 
         // glob function
@@ -99,6 +945,7 @@ impl Plugin for FsPlugin {
                 for (i, entry) in entries.enumerate() {
                     match entry {
                         Ok(path) => {
+                            check_read(lua, &path)?;
                             result_table.set(i + 1, path.to_string_lossy().to_string())?;
                         }
                         Err(e) => {
@@ -113,19 +960,98 @@ impl Plugin for FsPlugin {
         // read_file function
         fs.set(
             "read_file",
-            lua.create_function(|_, path: String| {
+            lua.create_function(|lua, path: String| {
+                check_read(lua, &path)?;
                 std::fs::read_to_string(&path)
                     .map_err(|e| to_lua_error(e, &format!("Error reading file {}", path)))
             })?,
         )?;
 
-        // write_file function
+        // read_bytes function (returns the content as a Lua string of bytes)
         fs.set(
-            "write_file",
-            lua.create_function(|_, (path, content): (String, String)| {
-                std::fs::write(&path, content)
+            "read_bytes",
+            lua.create_function(|lua, path: String| {
+                check_read(lua, &path)?;
+                let content = std::fs::read(&path)
+                    .map_err(|e| to_lua_error(e, &format!("Error reading file {}", path)))?;
+                lua.create_string(content)
+            })?,
+        )?;
+
+        // write_file function, also available as write_bytes since Lua strings hold bytes.
+        // With `{ atomic = true }` the file is replaced by a complete new one.
+        let write_fn = lua.create_function(
+            move |lua, (path, content, options): (String, mlua::String, Option<Table>)| {
+                check_write(lua, &path)?;
+                let content = content.as_bytes();
+                let atomic = match &options {
+                    Some(options) => options.get::<Option<bool>>("atomic")?.unwrap_or(false),
+                    None => false,
+                };
+                if dry_run {
+                    log_dry_run(&format!("write {} ({} bytes)", path, content.len()));
+                    return Ok(true);
+                }
+
+                let written = if atomic {
+                    write_atomic(Path::new(&path), &content)
+                } else {
+                    std::fs::write(&path, &content)
+                };
+                written
                     .map(|_| true)
                     .map_err(|e| to_lua_error(e, &format!("Error writing file {}", path)))
+            },
+        )?;
+        fs.set("write_file", write_fn.clone())?;
+        fs.set("write_bytes", write_fn)?;
+
+        // append_file function
+        fs.set(
+            "append_file",
+            lua.create_function(move |lua, (path, content): (String, mlua::String)| {
+                check_write(lua, &path)?;
+                let content = content.as_bytes();
+                if dry_run {
+                    log_dry_run(&format!("append {} ({} bytes)", path, content.len()));
+                    return Ok(true);
+                }
+
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .and_then(|mut file| file.write_all(&content))
+                    .map(|_| true)
+                    .map_err(|e| to_lua_error(e, &format!("Error appending to file {}", path)))
+            })?,
+        )?;
+
+        // lines function (iterates over the lines of a file without reading it whole)
+        fs.set(
+            "lines",
+            lua.create_function(|lua, path: String| {
+                check_read(lua, &path)?;
+                let file = std::fs::File::open(&path)
+                    .map_err(|e| to_lua_error(e, &format!("Error reading file {}", path)))?;
+                let mut reader = BufReader::new(file);
+
+                lua.create_function_mut(move |lua, ()| {
+                    let mut line = Vec::new();
+                    let read = reader
+                        .read_until(b'\n', &mut line)
+                        .map_err(|e| to_lua_error(e, &format!("Error reading file {}", path)))?;
+                    if read == 0 {
+                        return Ok(None);
+                    }
+                    if line.ends_with(b"\n") {
+                        line.pop();
+                        if line.ends_with(b"\r") {
+                            line.pop();
+                        }
+                    }
+                    lua.create_string(line).map(Some)
+                })
             })?,
         )?;
 
@@ -133,6 +1059,7 @@ impl Plugin for FsPlugin {
         fs.set(
             "list_dir",
             lua.create_function(|lua, path: String| {
+                check_read(lua, &path)?;
                 let entries = std::fs::read_dir(&path)
                     .map_err(|e| to_lua_error(e, &format!("Error listing directory {}", path)))?;
 
@@ -151,6 +1078,118 @@ impl Plugin for FsPlugin {
             })?,
         )?;
 
+        // walk function (iterates over a tree, or lists it with `collect`)
+        fs.set(
+            "walk",
+            lua.create_function(|lua, (root, options): (String, Option<Table>)| {
+                check_read(lua, &root)?;
+                let options = WalkOptions::from_lua(options)?;
+                let collect = options.collect;
+                let mut walk = Walk::new(&root, options);
+
+                if collect {
+                    let paths = lua.create_table()?;
+                    while let Some((path, ..)) = walk.next_entry(lua)? {
+                        paths.push(path)?;
+                    }
+                    return Ok(Value::Table(paths));
+                }
+
+                // Entries are read lazily, so large trees are not held in memory
+                let iterator =
+                    lua.create_function_mut(move |lua, ()| match walk.next_entry(lua)? {
+                        Some((path, file_type, depth)) => {
+                            Ok((Some(path), Some(file_type), Some(depth)))
+                        }
+                        None => Ok((None, None, None)),
+                    })?;
+                Ok(Value::Function(iterator))
+            })?,
+        )?;
+
+        // chmod function
+        fs.set(
+            "chmod",
+            lua.create_function(move |lua, (path, spec): (String, Value)| {
+                check_write(lua, &path)?;
+                let metadata = std::fs::metadata(&path)
+                    .map_err(|e| to_lua_error(e, &format!("Error reading metadata of {}", path)))?;
+                let new_mode = parse_mode(&spec, mode(&metadata))?;
+                if dry_run {
+                    log_dry_run(&format!("chmod {:o} {}", new_mode, path));
+                    return Ok(true);
+                }
+
+                set_mode(&path, new_mode)
+                    .map(|_| true)
+                    .map_err(|e| to_lua_error(e, &format!("Error changing mode of {}", path)))
+            })?,
+        )?;
+
+        // touch function (creates the file or updates its modification time)
+        fs.set(
+            "touch",
+            lua.create_function(move |lua, (path, mtime): (String, Option<f64>)| {
+                check_write(lua, &path)?;
+                if dry_run {
+                    log_dry_run(&format!("touch {}", path));
+                    return Ok(true);
+                }
+
+                let time = match mtime {
+                    Some(seconds) => UNIX_EPOCH + Duration::from_secs_f64(seconds.max(0.0)),
+                    None => SystemTime::now(),
+                };
+                std::fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&path)
+                    .and_then(|file| file.set_modified(time))
+                    .map(|_| true)
+                    .map_err(|e| to_lua_error(e, &format!("Error touching file {}", path)))
+            })?,
+        )?;
+
+        // symlink function
+        fs.set(
+            "symlink",
+            lua.create_function(move |lua, (target, link): (String, String)| {
+                check_write(lua, &link)?;
+                if dry_run {
+                    log_dry_run(&format!("symlink {} -> {}", link, target));
+                    return Ok(true);
+                }
+
+                symlink(&target, &link).map(|_| true).map_err(|e| {
+                    to_lua_error(e, &format!("Error creating symlink {} to {}", link, target))
+                })
+            })?,
+        )?;
+
+        // read_link function
+        fs.set(
+            "read_link",
+            lua.create_function(|lua, path: String| {
+                check_read(lua, &path)?;
+                std::fs::read_link(&path)
+                    .map(|target| target.to_string_lossy().to_string())
+                    .map_err(|e| to_lua_error(e, &format!("Error reading symlink {}", path)))
+            })?,
+        )?;
+
+        // realpath function
+        fs.set(
+            "realpath",
+            lua.create_function(|lua, path: String| {
+                check_read(lua, &path)?;
+                let resolved = std::fs::canonicalize(&path)
+                    .map_err(|e| to_lua_error(e, &format!("Error resolving path {}", path)))?;
+                // Links may lead outside the readable paths
+                check_read(lua, &resolved)?;
+                Ok(resolved.to_string_lossy().to_string())
+            })?,
+        )?;
+
         globals.set("lake.fs", fs)?;
         Ok(())
     }
@@ -159,3 +1198,311 @@ impl Plugin for FsPlugin {
         "filesystem"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+    use crate::permissions::{Allow, Grant, Permissions};
+
+    fn tree_options(include: &[&str], delete: bool) -> TreeOptions {
+        TreeOptions {
+            include: include.iter().map(|p| Pattern::new(p).unwrap()).collect(),
+            exclude: Vec::new(),
+            overwrite: Overwrite::Changed,
+            preserve_times: true,
+            delete,
+        }
+    }
+
+    /// Lua state allowed to write below `dir` only
+    fn writable(dir: &Path) -> Lua {
+        let lua = Lua::new();
+        let allow = Allow {
+            write: Grant::Only(vec![dir.to_string_lossy().to_string()]),
+            ..Allow::default()
+        };
+        lua.set_app_data(Permissions::new(&allow, dir));
+        lua
+    }
+
+    fn write(path: &Path, content: &str) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, content).unwrap();
+    }
+
+    #[test]
+    fn applies_octal_and_symbolic_modes() {
+        let mode = |spec: &str, current| {
+            parse_mode(
+                &Value::String(Lua::new().create_string(spec).unwrap()),
+                current,
+            )
+        };
+        assert_eq!(mode("755", 0o644).unwrap(), 0o755);
+        assert_eq!(mode("+x", 0o644).unwrap(), 0o755);
+        assert_eq!(mode("u+x,go-r", 0o644).unwrap(), 0o700);
+        assert_eq!(mode("g=rw", 0o700).unwrap(), 0o760);
+        assert_eq!(parse_mode(&Value::Integer(0o600), 0o644).unwrap(), 0o600);
+        assert!(parse_mode(&Value::Integer(-1), 0o644).is_err());
+        assert!(parse_mode(&Value::Integer(0o10000), 0o644).is_err());
+        assert!(mode("17777", 0o644).is_err());
+        assert!(mode("u+z", 0o644).is_err());
+        assert!(mode("", 0o644).is_err());
+    }
+
+    #[test]
+    fn describes_and_moves_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("a.txt"), dir.path().join("b/a.txt"));
+        write(&src, "hello");
+        fs::create_dir(dir.path().join("b")).unwrap();
+        let lua = Lua::new();
+        let path = |path: &Path| path.to_string_lossy().to_string();
+
+        move_path(&path(&src), &path(&dst)).unwrap();
+        assert!(!src.exists());
+        let info = stat(&lua, &path(&dst)).unwrap();
+        assert_eq!(info.get::<String>("type").unwrap(), "file");
+        assert_eq!(info.get::<u64>("size").unwrap(), 5);
+        assert!(!info.get::<bool>("is_symlink").unwrap());
+        assert!(info.get::<f64>("mtime").unwrap() > 0.0);
+
+        let link = dir.path().join("link");
+        symlink(&dst, &link).unwrap();
+        let info = stat(&lua, &path(&link)).unwrap();
+        assert_eq!(info.get::<String>("type").unwrap(), "file");
+        assert!(info.get::<bool>("is_symlink").unwrap());
+
+        #[cfg(unix)]
+        {
+            set_mode(&path(&dst), 0o640).unwrap();
+            let info = stat(&lua, &path(&dst)).unwrap();
+            assert_eq!(info.get::<String>("mode").unwrap(), "640");
+        }
+        assert!(stat(&lua, &path(&src)).is_err());
+    }
+
+    #[test]
+    fn walks_trees_with_filters() {
+        let dir = tempfile::tempdir().unwrap();
+        write(&dir.path().join(".gitignore"), "target/\n*.log\n");
+        write(&dir.path().join("src/main.rs"), "");
+        write(&dir.path().join("src/util/mod.rs"), "");
+        write(&dir.path().join("src/notes.txt"), "");
+        write(&dir.path().join("build.log"), "");
+        write(&dir.path().join("target/debug/lake"), "");
+        let lua = Lua::new();
+        lua.set_app_data(Permissions::new(&Allow::default(), dir.path()));
+
+        let walk = |options: &str| {
+            let options: Table = lua.load(options).eval().unwrap();
+            let options = WalkOptions::from_lua(Some(options)).unwrap();
+            let mut walk = Walk::new(&dir.path().to_string_lossy(), options);
+            let mut paths = Vec::new();
+            while let Some((path, _, _)) = walk.next_entry(&lua).unwrap() {
+                let relative = Path::new(&path).strip_prefix(dir.path()).unwrap();
+                paths.push(slash_path(relative));
+            }
+            paths
+        };
+
+        assert_eq!(
+            walk("{ type = 'file', gitignore = true }"),
+            [
+                ".gitignore",
+                "src/main.rs",
+                "src/notes.txt",
+                "src/util/mod.rs"
+            ]
+        );
+        assert_eq!(
+            walk("{ include = { '**/*.rs' }, exclude = { 'src/util' } }"),
+            ["src/main.rs"]
+        );
+        assert_eq!(walk("{ max_depth = 1, type = 'dir' }"), ["src", "target"]);
+        assert!(
+            WalkOptions::from_lua(Some(lua.load("{ type = 'fifo' }").eval().unwrap())).is_err()
+        );
+    }
+
+    #[test]
+    fn passes_bytes_through_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let lua = Lua::new();
+        let allow = Allow {
+            all: true,
+            ..Allow::default()
+        };
+        lua.set_app_data(Permissions::new(&allow, dir.path()));
+        FsPlugin::new(false).register(&lua).unwrap();
+        lua.globals()
+            .set("dir", dir.path().to_string_lossy().to_string())
+            .unwrap();
+        write(&dir.path().join("original.txt"), "old");
+        fs::hard_link(
+            dir.path().join("original.txt"),
+            dir.path().join("linked.txt"),
+        )
+        .unwrap();
+
+        let lines: Vec<String> = lua
+            .load(
+                r#"
+                local fs = _G["lake.fs"]
+                local bytes = "\0\255\r\nbinary"
+                fs.write_bytes(dir .. "/data.bin", bytes)
+                assert(fs.read_bytes(dir .. "/data.bin") == bytes)
+                fs.write_bytes(dir .. "/data.bin", "atomic", { atomic = true })
+                assert(fs.read_bytes(dir .. "/data.bin") == "atomic")
+                fs.write_file(dir .. "/linked.txt", "written in place")
+                fs.write_file(dir .. "/log.txt", "first\r\n")
+                fs.append_file(dir .. "/log.txt", "second\nthird")
+                local lines = {}
+                for line in fs.lines(dir .. "/log.txt") do
+                    lines[#lines + 1] = line
+                end
+                return lines
+                "#,
+            )
+            .eval()
+            .unwrap();
+        assert_eq!(lines, ["first", "second", "third"]);
+        let original = fs::read_to_string(dir.path().join("original.txt")).unwrap();
+        assert_eq!(original, "written in place");
+    }
+
+    #[test]
+    fn writes_files_atomically() {
+        let dir = tempfile::tempdir().unwrap();
+        let (target, link) = (dir.path().join("out.txt"), dir.path().join("link.txt"));
+        write(&target, "old");
+        symlink(&target, &link).unwrap();
+        #[cfg(unix)]
+        set_mode(&target.to_string_lossy(), 0o640).unwrap();
+
+        write_atomic(&link, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&target).unwrap(), "new");
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        #[cfg(unix)]
+        assert_eq!(mode(&fs::metadata(&target).unwrap()), 0o640);
+
+        // No temporary file is left behind, even when the write fails
+        assert!(write_atomic(&dir.path().join("missing/out.txt"), b"x").is_err());
+        let names: Vec<_> = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name())
+            .collect();
+        assert_eq!(names.len(), 2, "{:?}", names);
+    }
+
+    #[test]
+    fn refuses_overlapping_directories() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        write(&src.join("a.txt"), "a");
+        let lua = writable(dir.path());
+        let path = |path: &Path| path.to_string_lossy().to_string();
+
+        let into_itself = copy_tree(
+            &lua,
+            &path(&src),
+            &path(&src.join("backup")),
+            tree_options(&[], false),
+            false,
+        );
+        let message = into_itself.unwrap_err().to_string();
+        assert!(message.contains("the directories overlap"), "{}", message);
+        assert!(!src.join("backup").exists());
+
+        let deleting_source = copy_tree(
+            &lua,
+            &path(&src),
+            &path(dir.path()),
+            tree_options(&[], true),
+            false,
+        );
+        assert!(deleting_source.is_err());
+        assert!(src.join("a.txt").exists());
+    }
+
+    #[test]
+    fn deletes_only_included_extras() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("src"), dir.path().join("dst"));
+        write(&src.join("kept.txt"), "kept");
+        write(&dst.join("stale.txt"), "stale");
+        write(&dst.join("notes.md"), "notes");
+        write(&dst.join("extra/old.txt"), "old");
+        write(&dst.join("extra/keep.md"), "keep");
+        write(&dst.join("gone/old.txt"), "old");
+
+        let lua = writable(dir.path());
+        let summary = copy_tree(
+            &lua,
+            &src.to_string_lossy(),
+            &dst.to_string_lossy(),
+            tree_options(&["*.txt"], true),
+            false,
+        )
+        .unwrap();
+
+        let deleted: Vec<String> = summary.get("deleted").unwrap();
+        assert_eq!(deleted, ["extra/old.txt", "gone", "stale.txt"]);
+        assert!(dst.join("kept.txt").exists());
+        assert!(dst.join("notes.md").exists());
+        assert!(dst.join("extra/keep.md").exists());
+        assert!(!dst.join("extra/old.txt").exists());
+        assert!(!dst.join("gone").exists());
+    }
+
+    #[test]
+    fn does_not_write_through_links_in_the_destination() {
+        let root = tempfile::tempdir().unwrap();
+        let (project, outside) = (root.path().join("project"), root.path().join("outside"));
+        let (src, dst) = (project.join("payload"), project.join("target"));
+        write(&src.join("sub/evil.txt"), "evil");
+        write(&src.join("sub/link"), "");
+        fs::create_dir_all(&dst).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        symlink(&outside, dst.join("sub")).unwrap();
+        let lua = writable(&dst);
+        let path = |path: &Path| path.to_string_lossy().to_string();
+
+        for include in [&[][..], &["**/*.txt"][..]] {
+            let copied = copy_tree(
+                &lua,
+                &path(&src),
+                &path(&dst),
+                tree_options(include, false),
+                false,
+            );
+            let message = copied.unwrap_err().to_string();
+            assert!(message.contains("permission denied"), "{}", message);
+            assert!(!outside.join("evil.txt").exists());
+        }
+
+        // Links replaced in the destination are checked where they are, not where they lead
+        fs::remove_file(dst.join("sub")).unwrap();
+        symlink(&outside, dst.join("link")).unwrap();
+        fs::remove_dir_all(src.join("sub")).unwrap();
+        symlink("elsewhere", src.join("link")).unwrap();
+        copy_tree(
+            &lua,
+            &path(&src),
+            &path(&dst),
+            tree_options(&[], true),
+            false,
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_link(dst.join("link")).unwrap(),
+            Path::new("elsewhere")
+        );
+        assert!(outside.exists());
+    }
+}
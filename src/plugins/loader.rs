@@ -0,0 +1,226 @@
+//! Plugin loader for Lake
+//!
+//! Resolves `plugin(name)` to a core plugin or to a Lua plugin on the search
+//! path. Dotted names map to subdirectories, so `tools.docker` is looked up
+//! as `tools/docker.lua` and `tools/docker/init.lua` in every directory,
+//! followed by `tools/docker.wasm` and the native libraries `tools/docker.so`
+//! and `tools/libdocker.so`, which need `--allow-native`.
+//! Loaded plugins are cached, every call with the same plugin returns the same table.
+
+use std::collections::HashMap;
+use std::env::consts::{DLL_EXTENSION, DLL_PREFIX, DLL_SUFFIX};
+use std::path::PathBuf;
+
+use mlua::{Error as LuaError, Lua, Result as LuaResult, Table, Value};
+
+use crate::packages;
+use crate::permissions;
+use crate::plugins::{native, wasm, PluginOptions};
+
+/// Directories searched for Lua plugins, in order
+struct SearchPath(Vec<PathBuf>);
+
+/// Root directory of the project, holding the installed plugin packages
+struct ProjectDir(PathBuf);
+
+/// Whether WebAssembly plugins log their side effects instead of performing them
+struct DryRun(bool);
+
+/// Plugins loaded into a Lua state, by path
+#[derive(Default)]
+struct PluginCache {
+    loaded: HashMap<PathBuf, Table>,
+    loading: Vec<PathBuf>,
+}
+
+/// Set up plugin loading for a Lua state
+pub fn init(lua: &Lua, options: &PluginOptions) {
+    lua.set_app_data(SearchPath(options.search_path.clone()));
+    lua.set_app_data(ProjectDir(options.project_dir.clone()));
+    lua.set_app_data(DryRun(options.dry_run));
+    lua.set_app_data(PluginCache::default());
+}
+
+/// Load a plugin by name
+pub fn load_plugin(lua: &Lua, name: &str) -> LuaResult<Table> {
+    let globals = lua.globals();
+
+    // Core plugins are registered as `lake.<name>`, and may be named without the prefix
+    if name.starts_with("lake.") {
+        return match globals.get::<Value>(name)? {
+            Value::Table(table) => Ok(table),
+            _ => Err(LuaError::RuntimeError(format!(
+                "Core plugin '{}' not found",
+                name
+            ))),
+        };
+    }
+    if let Value::Table(table) = globals.get::<Value>(format!("lake.{}", name))? {
+        return Ok(table);
+    }
+
+    let path = find_plugin(lua, name)?;
+
+    if let Some(cache) = lua.app_data_ref::<PluginCache>() {
+        if let Some(table) = cache.loaded.get(&path) {
+            return Ok(table.clone());
+        }
+        if let Some(start) = cache.loading.iter().position(|loading| *loading == path) {
+            let mut cycle: Vec<String> = cache.loading[start..]
+                .iter()
+                .map(|path| path.display().to_string())
+                .collect();
+            cycle.push(path.display().to_string());
+            return Err(LuaError::RuntimeError(format!(
+                "Plugin cycle detected: {}",
+                cycle.join(" -> ")
+            )));
+        }
+    }
+
+    // Installed packages must match lake.lock
+    if let Some(project_dir) = lua.app_data_ref::<ProjectDir>() {
+        let packages_dir = project_dir.0.join(packages::PACKAGES_DIR);
+        if let Ok(relative) = path.strip_prefix(&packages_dir) {
+            if let Some(package) = relative.iter().next() {
+                packages::verify(&project_dir.0, &package.to_string_lossy())
+                    .map_err(|e| LuaError::RuntimeError(format!("{:#}", e)))?;
+            }
+        }
+    }
+
+    let extension = path.extension().unwrap_or_default();
+    if extension == "wasm" || extension == DLL_EXTENSION {
+        let table = if extension == "wasm" {
+            let dry_run = lua
+                .app_data_ref::<DryRun>()
+                .is_some_and(|dry_run| dry_run.0);
+            wasm::load(lua, name, &path, dry_run)?
+        } else {
+            // Native code runs outside of the sandbox
+            permissions::check_native(lua, name)?;
+            native::load(lua, name, &path)?
+        };
+        if let Some(mut cache) = lua.app_data_mut::<PluginCache>() {
+            cache.loaded.insert(path.clone(), table.clone());
+        }
+        log::debug!("Loaded binary plugin: {} from {}", name, path.display());
+        return Ok(table);
+    }
+
+    let content = std::fs::read_to_string(&path).map_err(|e| {
+        LuaError::RuntimeError(format!("Error reading plugin {}: {}", path.display(), e))
+    })?;
+
+    if let Some(mut cache) = lua.app_data_mut::<PluginCache>() {
+        cache.loading.push(path.clone());
+    }
+    let result = lua
+        .load(content)
+        .set_name(format!("@{}", path.display()))
+        .eval::<Value>();
+    if let Some(mut cache) = lua.app_data_mut::<PluginCache>() {
+        cache.loading.pop();
+    }
+
+    let table = match result? {
+        Value::Table(table) => table,
+        other => {
+            return Err(LuaError::RuntimeError(format!(
+                "Plugin '{}' at {} must return a table, got {}",
+                name,
+                path.display(),
+                other.type_name()
+            )))
+        }
+    };
+
+    if let Some(mut cache) = lua.app_data_mut::<PluginCache>() {
+        cache.loaded.insert(path.clone(), table.clone());
+    }
+    log::debug!("Loaded external plugin: {} from {}", name, path.display());
+    Ok(table)
+}
+
+/// Find the file of a plugin on the search path
+fn find_plugin(lua: &Lua, name: &str) -> LuaResult<PathBuf> {
+    let parts: Vec<&str> = name.split('.').collect();
+    if parts
+        .iter()
+        .any(|part| part.is_empty() || part.contains(['/', '\\']))
+    {
+        return Err(LuaError::RuntimeError(format!(
+            "Invalid plugin name '{}': expected names separated by dots, like 'tools.docker'",
+            name
+        )));
+    }
+    let relative: PathBuf = parts.iter().collect();
+    let file_name = parts[parts.len() - 1];
+
+    let search_path = lua.app_data_ref::<SearchPath>();
+    let dirs = search_path
+        .as_ref()
+        .map(|search_path| search_path.0.as_slice())
+        .unwrap_or_default();
+
+    let mut tried = Vec::new();
+    for dir in dirs {
+        let candidates = [
+            dir.join(&relative).with_extension("lua"),
+            dir.join(&relative).join("init.lua"),
+            dir.join(&relative).with_extension("wasm"),
+            dir.join(&relative).with_extension(DLL_EXTENSION),
+            dir.join(&relative)
+                .with_file_name(format!("{}{}{}", DLL_PREFIX, file_name, DLL_SUFFIX)),
+        ];
+        for candidate in candidates {
+            if candidate.is_file() {
+                return Ok(candidate);
+            }
+            tried.push(candidate);
+        }
+    }
+
+    let tried: Vec<String> = tried
+        .iter()
+        .map(|path| format!("\n    {}", path.display()))
+        .collect();
+    Err(LuaError::RuntimeError(format!(
+        "Plugin '{}' not found, tried:{}",
+        name,
+        tried.concat()
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::lake::Project;
+    use crate::permissions::Allow;
+    use crate::test_support::{lock_cwd, options};
+
+    use super::*;
+
+    #[test]
+    fn native_plugins_need_native_permission() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let plugins_dir = dir.path().join("plugins");
+        fs::create_dir(&plugins_dir).unwrap();
+        // Never loaded, the permission is checked first
+        fs::write(plugins_dir.join(format!("docker.{}", DLL_EXTENSION)), "").unwrap();
+        let build_file = dir.path().join("build.lake");
+        fs::write(&build_file, "plugin('docker')").unwrap();
+
+        let mut options = options(dir.path(), &Allow::default());
+        options.search_path = vec![plugins_dir];
+        let denied = Project::load(&build_file, &options);
+        let message = format!("{:#}", denied.err().unwrap());
+        assert!(
+            message.contains("permission denied: needs --allow-native=docker"),
+            "{}",
+            message
+        );
+    }
+}
@@ -2,36 +2,64 @@
 //!
 //! Handles registration and management of core plugins.
 
+use std::path::PathBuf;
+
 use mlua::{Lua, Result as LuaResult};
 
+use crate::config::Config;
+use crate::permissions::Permissions;
+
+mod archive_plugin;
+mod config_plugin;
 mod crypto_plugin;
 mod env_plugin;
 mod fs_plugin;
+mod loader;
 mod logger_plugin;
+mod native;
 mod net_plugin;
 mod process_plugin;
 mod random_plugin;
+mod wasm;
 
-/// API for registering plugins
-pub trait Plugin {
-    /// Register the plugin with the Lua state
-    fn register(&self, lua: &Lua) -> LuaResult<()>;
+pub use env_plugin::inherit_env;
+pub use lake_plugin_api::Plugin;
+pub use loader::load_plugin;
+pub use process_plugin::reap_children;
 
-    /// Get the plugin name
-    fn name(&self) -> &str;
+/// Options shared by the core plugins
+#[derive(Clone, Default)]
+pub struct PluginOptions {
+    /// Log side effects and return stub results instead of performing them
+    pub dry_run: bool,
+    /// Merged project and user configuration, exposed as `lake.config`
+    pub config: Config,
+    /// Capabilities granted to scripts
+    pub permissions: Permissions,
+    /// Directories searched for Lua plugins, in order
+    pub search_path: Vec<PathBuf>,
+    /// Root directory of the project, holding the installed plugin packages
+    pub project_dir: PathBuf,
 }
 
 /// Register all core plugins
-pub fn register_all(lua: &Lua) -> LuaResult<()> {
+pub fn register_all(lua: &Lua, options: &PluginOptions) -> LuaResult<()> {
+    // Plugins check the permissions of the calling script before acting
+    lua.set_app_data(options.permissions.clone());
+    loader::init(lua, options);
+    env_plugin::set_project_vars(lua, &options.config.project_env);
+
     // Create instances of all core plugins
     let plugins: Vec<Box<dyn Plugin>> = vec![
         Box::new(crypto_plugin::CryptoPlugin::new()),
-        Box::new(fs_plugin::FsPlugin::new()),
-        Box::new(process_plugin::ProcessPlugin::new()),
-        Box::new(env_plugin::EnvPlugin::new()),
-        Box::new(net_plugin::NetPlugin::new()),
+        Box::new(fs_plugin::FsPlugin::new(options.dry_run)),
+        Box::new(process_plugin::ProcessPlugin::new(options.dry_run)),
+        Box::new(env_plugin::EnvPlugin::new(options.dry_run)),
+        Box::new(net_plugin::NetPlugin::new(options.dry_run)),
         Box::new(logger_plugin::LoggerPlugin::new()),
         Box::new(random_plugin::RandomPlugin::new()),
+        Box::new(config_plugin::ConfigPlugin::new(options.config.clone())),
+        Box::new(archive_plugin::ArchivePlugin::new(options.dry_run)),
     ];
 
     // Register each plugin
@@ -42,3 +70,8 @@ pub fn register_all(lua: &Lua) -> LuaResult<()> {
 
     Ok(())
 }
+
+/// Report a side effect that was skipped because of a dry run
+pub fn log_dry_run(action: &str) {
+    log::info!("[dry-run] {}", action);
+}
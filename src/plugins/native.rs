@@ -0,0 +1,127 @@
+//! Native plugins for Lake
+//!
+//! Loads plugins compiled as shared libraries against `lake-plugin-api`.
+//! The plugin API, Lua and mlua versions declared by the library are checked
+//! before its entry point is called. Loading the library already runs its
+//! initializers, so native plugins need `--allow-native`.
+
+use std::path::Path;
+
+use lake_plugin_api::{
+    PluginDeclaration, API_VERSION, DECLARATION_SYMBOL, LUA_VERSION, MLUA_VERSION,
+};
+use libloading::Library;
+use mlua::{Error as LuaError, Lua, Result as LuaResult, Table, Value};
+
+/// Load a native plugin and return the table it registered
+pub fn load(lua: &Lua, name: &str, path: &Path) -> LuaResult<Table> {
+    let error = |message: String| {
+        LuaError::RuntimeError(format!(
+            "Native plugin '{}' at {} {}",
+            name,
+            path.display(),
+            message
+        ))
+    };
+
+    // Safety: loading a library runs its initializers, which is the point of a native plugin
+    let library =
+        unsafe { Library::new(path) }.map_err(|e| error(format!("could not be loaded: {}", e)))?;
+
+    // Safety: the symbol is a `PluginDeclaration`, whose layout is fixed by `repr(C)`
+    let declaration = unsafe {
+        library
+            .get::<*const PluginDeclaration>(DECLARATION_SYMBOL.as_bytes())
+            .map(|symbol| &**symbol)
+            .map_err(|_| {
+                error(format!(
+                    "is not a Lake plugin: {} not found, export it with `declare_plugin!`",
+                    DECLARATION_SYMBOL
+                ))
+            })?
+    };
+
+    if declaration.api_version != API_VERSION {
+        return Err(error(format!(
+            "was built for plugin API version {}, this Lake supports version {}",
+            declaration.api_version, API_VERSION
+        )));
+    }
+    // The fields after the API version are only known to exist when it matches
+    if declaration.lua_version != LUA_VERSION || declaration.mlua_version != MLUA_VERSION {
+        return Err(error(format!(
+            "was built for Lua {} and mlua {}, this Lake uses Lua {} and mlua {}",
+            declaration.lua_version,
+            version_string(declaration.mlua_version),
+            LUA_VERSION,
+            version_string(MLUA_VERSION)
+        )));
+    }
+
+    // Safety: the versions match, so `register` follows the Lua C function protocol
+    let register = unsafe { lua.create_c_function(declaration.register)? };
+    let table = register.call::<Value>(())?;
+
+    // Functions registered by the plugin live in the library, it is never unloaded
+    std::mem::forget(library);
+
+    match table {
+        Value::Table(table) => Ok(table),
+        _ => Err(error("did not register a `lake.<name>` table".to_string())),
+    }
+}
+
+fn version_string([major, minor, patch]: [u32; 3]) -> String {
+    format!("{}.{}.{}", major, minor, patch)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+    use std::fs;
+    use std::path::PathBuf;
+    use std::process::Command;
+
+    use crate::lake::Project;
+    use crate::permissions::{Allow, Grant};
+    use crate::test_support::{lock_cwd, options};
+
+    /// Build the plugin in tests/fixtures/native-plugin and return the library
+    fn build_fixture() -> PathBuf {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        let target_dir = root.join("target").join("fixtures");
+        let status = Command::new(std::env::var("CARGO").unwrap_or("cargo".to_string()))
+            .args(["build", "--quiet", "--manifest-path"])
+            .arg(root.join("tests/fixtures/native-plugin/Cargo.toml"))
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .status()
+            .unwrap();
+        assert!(status.success(), "failed to build the fixture plugin");
+
+        let library = format!("{}lake_fixture_plugin{}", DLL_PREFIX, DLL_SUFFIX);
+        target_dir.join("debug").join(library)
+    }
+
+    #[test]
+    fn loads_native_plugins() {
+        let library = build_fixture();
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let plugins_dir = dir.path().join("plugins");
+        fs::create_dir(&plugins_dir).unwrap();
+        fs::copy(&library, plugins_dir.join(format!("fixture{}", DLL_SUFFIX))).unwrap();
+        let build_file = dir.path().join("build.lake");
+        fs::write(&build_file, "greeting = plugin('fixture').greet('Lake')").unwrap();
+
+        let allow = Allow {
+            native: Grant::Only(vec!["fixture".to_string()]),
+            ..Allow::default()
+        };
+        let mut options = options(dir.path(), &allow);
+        options.search_path = vec![plugins_dir];
+        let project = Project::load(&build_file, &options).unwrap();
+        let greeting: String = project.lua.globals().get("greeting").unwrap();
+        assert_eq!(greeting, "Hello, Lake!");
+    }
+}
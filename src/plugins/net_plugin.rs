@@ -2,15 +2,18 @@
 //!
 //! Provides network functionality such as downloading files.
 
-use crate::plugins::Plugin;
+use crate::permissions::{check_net, check_write};
+use crate::plugins::{log_dry_run, Plugin};
 use mlua::{Error as LuaError, Lua, Result as LuaResult, Table, Value};
 use reqwest::blocking::{Client, RequestBuilder, Response};
 
-pub struct NetPlugin;
+pub struct NetPlugin {
+    dry_run: bool,
+}
 
 impl NetPlugin {
-    pub fn new() -> Self {
-        NetPlugin
+    pub fn new(dry_run: bool) -> Self {
+        NetPlugin { dry_run }
     }
 }
 
@@ -29,10 +32,7 @@ fn extract_url(args: &mlua::MultiValue, arg_pos: usize) -> LuaResult<String> {
 }
 
 // Helper function to extract optional headers table
-fn extract_headers_table<'lua>(
-    args: &mlua::MultiValue,
-    arg_pos: usize,
-) -> LuaResult<Option<&Table>> {
+fn extract_headers_table(args: &mlua::MultiValue, arg_pos: usize) -> LuaResult<Option<&Table>> {
     match args.get(arg_pos) {
         Some(Value::Table(t)) => Ok(Some(t)),
         None => Ok(None),
@@ -56,7 +56,7 @@ fn add_headers_from_table(
 }
 
 // Helper function to create a response table from an HTTP response
-fn create_response_table<'lua>(lua: &'lua Lua, response: Response) -> LuaResult<Table> {
+fn create_response_table(lua: &Lua, response: Response) -> LuaResult<Table> {
     let status = response.status().as_u16();
     let headers = response.headers().clone();
 
@@ -80,15 +80,32 @@ fn create_response_table<'lua>(lua: &'lua Lua, response: Response) -> LuaResult<
     Ok(response_table)
 }
 
+// Helper function to create the response returned by requests skipped in a dry run
+fn create_dry_run_response(lua: &Lua) -> LuaResult<Table> {
+    let response_table = lua.create_table()?;
+    response_table.set("status", 200)?;
+    response_table.set("body", "")?;
+    response_table.set("headers", lua.create_table()?)?;
+    Ok(response_table)
+}
+
 impl Plugin for NetPlugin {
     fn register(&self, lua: &Lua) -> LuaResult<()> {
+        let dry_run = self.dry_run;
         let globals = lua.globals();
         let net = lua.create_table()?;
 
         // download function
         net.set(
             "download",
-            lua.create_function(|_, (url, path): (String, String)| {
+            lua.create_function(move |lua, (url, path): (String, String)| {
+                check_net(lua, &url)?;
+                check_write(lua, &path)?;
+                if dry_run {
+                    log_dry_run(&format!("download {} to {}", url, path));
+                    return Ok(true);
+                }
+
                 let response = reqwest::blocking::get(&url)
                     .map_err(|e| to_lua_error(e, &format!("Error downloading from {}", url)))?;
 
@@ -113,10 +130,16 @@ impl Plugin for NetPlugin {
         // http_get function
         net.set(
             "http_get",
-            lua.create_function(|lua, args: mlua::MultiValue| {
+            lua.create_function(move |lua, args: mlua::MultiValue| {
                 let url = extract_url(&args, 0)?;
                 let headers_table = extract_headers_table(&args, 1)?;
 
+                check_net(lua, &url)?;
+                if dry_run {
+                    log_dry_run(&format!("GET {}", url));
+                    return create_dry_run_response(lua);
+                }
+
                 // Build the request
                 let client = Client::new();
                 let mut req_builder = client.get(&url);
@@ -138,7 +161,7 @@ impl Plugin for NetPlugin {
         // http_post function
         net.set(
             "http_post",
-            lua.create_function(|lua, args: mlua::MultiValue| {
+            lua.create_function(move |lua, args: mlua::MultiValue| {
                 let url = extract_url(&args, 0)?;
 
                 // Extract body
@@ -149,6 +172,12 @@ impl Plugin for NetPlugin {
 
                 let headers_table = extract_headers_table(&args, 2)?;
 
+                check_net(lua, &url)?;
+                if dry_run {
+                    log_dry_run(&format!("POST {} ({} bytes)", url, body.len()));
+                    return create_dry_run_response(lua);
+                }
+
                 // Build the request
                 let client = Client::new();
                 let mut req_builder = client.post(&url).body(body);
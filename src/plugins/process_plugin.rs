@@ -1,100 +1,1064 @@
 //! Process plugin for Lake
 //!
 //! Provides functionality to execute external processes.
+//!
+//! When tasks run alongside others, a task waiting for a command started by
+//! `exec`, `sh` or `pipeline` lets the other tasks run until it exits.
+
+use crate::permissions::{check_env, check_read, check_run};
+use crate::plugins::{env_plugin, log_dry_run, Plugin};
+use crate::sandbox::{CurrentTask, OutputPrefix, TaskThread};
+use mlua::{
+    Error as LuaError, Function, Lua, Result as LuaResult, Table, UserData, UserDataMethods, Value,
+};
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, Command, ExitStatus, Stdio};
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Shell running the command lines of `sh`
+const SHELL: &str = "/bin/sh";
+
+/// Lines of stderr quoted when a checked command fails
+const STDERR_TAIL_LINES: usize = 10;
+
+/// Options of `exec` that also apply to `spawn`, whose output is read through its handle
+const SPAWN_OPTIONS: [&str; 4] = ["stdio", "cwd", "env", "clear_env"];
+
+/// How long to wait for output or for commands to exit between checks
+const POLL_INTERVAL: Duration = Duration::from_millis(10);
+
+/// How long output is still read after the commands exited, when commands
+/// they started in the background keep the pipes open
+const OUTPUT_GRACE: Duration = Duration::from_millis(100);
 
-use crate::plugins::Plugin;
-use mlua::{Lua, Result as LuaResult, Table};
-use std::process::{Command, Stdio};
+/// Wraps a function starting commands, named after it for tracebacks, to
+/// wait for them in Lua where a task can yield to the others
+const WAIT_SOURCE: &str = r#"
+local NAME, in_task = ...
+local isyieldable, yield = coroutine.isyieldable, coroutine.yield
+return function(...)
+    local execution = NAME(...)
+    if type(execution) ~= "userdata" then
+        return execution
+    end
+    local block = not (isyieldable() and in_task())
+    while true do
+        local result = execution:poll(block)
+        if result ~= nil then
+            return result
+        end
+        yield()
+    end
+end
+"#;
 
-pub struct ProcessPlugin;
+pub struct ProcessPlugin {
+    dry_run: bool,
+}
 
 impl ProcessPlugin {
-    pub fn new() -> Self {
-        ProcessPlugin
+    pub fn new(dry_run: bool) -> Self {
+        ProcessPlugin { dry_run }
+    }
+}
+
+/// Render a command line for logs and errors, quoting arguments with spaces
+fn format_command(cmd: &str, args: &[String]) -> String {
+    let mut line = cmd.to_string();
+    for arg in args {
+        if arg.is_empty() || arg.contains(char::is_whitespace) {
+            line.push_str(&format!(" {:?}", arg));
+        } else {
+            line.push(' ');
+            line.push_str(arg);
+        }
+    }
+    line
+}
+
+/// Collect the arguments of a command from a Lua array
+fn args_to_vec(args: Option<Table>) -> LuaResult<Vec<String>> {
+    let mut result = Vec::new();
+    if let Some(args_table) = args {
+        for i in 1..=args_table.len()? {
+            if let Ok(arg) = args_table.get(i) {
+                result.push(arg);
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Where the input and output of a command go
+#[derive(Clone, Copy, PartialEq)]
+enum StdioMode {
+    /// Read by Lake, to be captured, streamed or passed to callbacks
+    Pipe,
+    /// Shared with Lake, for interactive commands
+    Inherit,
+    /// Discarded
+    Null,
+}
+
+impl StdioMode {
+    fn output(self) -> Stdio {
+        match self {
+            StdioMode::Pipe => Stdio::piped(),
+            StdioMode::Inherit => Stdio::inherit(),
+            StdioMode::Null => Stdio::null(),
+        }
+    }
+
+    fn input(self) -> Stdio {
+        match self {
+            StdioMode::Inherit => Stdio::inherit(),
+            StdioMode::Pipe | StdioMode::Null => Stdio::null(),
+        }
+    }
+}
+
+/// Options of `exec`
+struct ExecOptions {
+    stdio: StdioMode,
+    /// Forward output live, line by line
+    stream: bool,
+    /// Keep the output in the result, by default unless it is streamed, passed
+    /// to callbacks or not piped
+    capture: bool,
+    /// Prefix of streamed lines
+    prefix: Option<String>,
+    /// Input written to the command
+    stdin: Option<Vec<u8>>,
+    on_stdout: Option<Function>,
+    on_stderr: Option<Function>,
+    /// Working directory of the command
+    cwd: Option<String>,
+    /// Variables to set, or to remove when `None`
+    env: Vec<(String, Option<String>)>,
+    /// Start from an empty environment instead of Lake's
+    clear_env: bool,
+    /// Variables set by the build with `lake.env.set`
+    script_env: Vec<(String, String)>,
+    /// Kill the command when it runs longer
+    timeout: Option<Duration>,
+    /// Raise an error when the command fails instead of returning its status
+    check: bool,
+}
+
+impl ExecOptions {
+    fn from_lua(lua: &Lua, options: Option<Table>) -> LuaResult<Self> {
+        // Streamed lines are prefixed like `print` when tasks run in parallel
+        let output_prefix = lua.app_data_ref::<OutputPrefix>().map(|p| p.0.clone());
+
+        let options = match options {
+            Some(options) => options,
+            None => lua.create_table()?,
+        };
+
+        let stdio = match options.get::<Option<String>>("stdio")?.as_deref() {
+            None | Some("pipe") => StdioMode::Pipe,
+            Some("inherit") => StdioMode::Inherit,
+            Some("null") => StdioMode::Null,
+            Some(other) => {
+                return Err(LuaError::RuntimeError(format!(
+                    "Invalid stdio {:?}: expected \"pipe\", \"inherit\" or \"null\"",
+                    other
+                )))
+            }
+        };
+        let stream = options.get::<Option<bool>>("stream")?.unwrap_or(false);
+        let on_stdout: Option<Function> = options.get("on_stdout")?;
+        let on_stderr: Option<Function> = options.get("on_stderr")?;
+        let capture = options.get::<Option<bool>>("capture")?.unwrap_or(
+            stdio == StdioMode::Pipe && !stream && on_stdout.is_none() && on_stderr.is_none(),
+        );
+        let prefix = match options.get::<Value>("prefix")? {
+            Value::Nil => output_prefix,
+            Value::Boolean(false) => None,
+            Value::Boolean(true) => lua.app_data_ref::<CurrentTask>().map(|task| task.0.clone()),
+            Value::String(prefix) => Some(prefix.to_str()?.to_string()),
+            other => {
+                return Err(LuaError::RuntimeError(format!(
+                    "Invalid prefix: expected a string or a boolean, got {}",
+                    other.type_name()
+                )))
+            }
+        };
+        let stdin = options
+            .get::<Option<mlua::String>>("stdin")?
+            .map(|input| input.as_bytes().to_vec());
+
+        let mut env = Vec::new();
+        if let Some(vars) = options.get::<Option<Table>>("env")? {
+            for pair in vars.pairs::<String, Value>() {
+                let (name, value) = pair?;
+                // Variables such as LD_PRELOAD or PATH decide what a granted program runs
+                check_env(lua, &name)?;
+                let value = match value {
+                    Value::Boolean(false) => None,
+                    Value::String(value) => Some(value.to_str()?.to_string()),
+                    Value::Integer(value) => Some(value.to_string()),
+                    Value::Number(value) => Some(value.to_string()),
+                    other => {
+                        return Err(LuaError::RuntimeError(format!(
+                            "Invalid value for environment variable {}: expected a string, a number or false, got {}",
+                            name,
+                            other.type_name()
+                        )))
+                    }
+                };
+                env.push((name, value));
+            }
+        }
+        env.sort();
+
+        let cwd: Option<String> = options.get("cwd")?;
+        if let Some(cwd) = &cwd {
+            check_read(lua, cwd)?;
+        }
+
+        Ok(ExecOptions {
+            stdio,
+            stream,
+            capture,
+            prefix,
+            stdin,
+            on_stdout,
+            on_stderr,
+            cwd,
+            env,
+            clear_env: options.get::<Option<bool>>("clear_env")?.unwrap_or(false),
+            script_env: env_plugin::overrides(lua),
+            timeout: options
+                .get::<Option<u64>>("timeout_ms")?
+                .map(Duration::from_millis),
+            check: options.get::<Option<bool>>("check")?.unwrap_or(false),
+        })
+    }
+
+    /// Set the working directory and environment of a command
+    fn configure(&self, command: &mut Command) {
+        if let Some(cwd) = &self.cwd {
+            command.current_dir(cwd);
+        }
+        if self.clear_env {
+            command.env_clear();
+        } else {
+            command.envs(self.script_env.iter().cloned());
+        }
+        for (name, value) in &self.env {
+            match value {
+                Some(value) => command.env(name, value),
+                None => command.env_remove(name),
+            };
+        }
+    }
+}
+
+/// Output stream of a command
+#[derive(Clone, Copy, PartialEq)]
+enum Stream {
+    Stdout,
+    Stderr,
+}
+
+/// Send the lines of a pipe, with their line endings, until it is closed
+fn read_lines(
+    pipe: impl Read + Send + 'static,
+    stream: Stream,
+    line_tx: mpsc::Sender<(Stream, Vec<u8>)>,
+) {
+    thread::spawn(move || {
+        let mut reader = BufReader::new(pipe);
+        loop {
+            let mut line = Vec::new();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) | Err(_) => break,
+                Ok(_) => {
+                    if line_tx.send((stream, line)).is_err() {
+                        break;
+                    }
+                }
+            }
+        }
+    });
+}
+
+/// Kill commands that ran out of time, without waiting for their output
+fn kill_all(children: &mut [Child]) {
+    for child in children {
+        let _ = child.kill();
+        let _ = child.wait();
+    }
+}
+
+/// Error raised by a checked command, quoting the end of its stderr
+fn check_error(command: &str, failure: &str, stderr_tail: &VecDeque<String>) -> LuaError {
+    let mut message = format!("{} {}", command, failure);
+    if !stderr_tail.is_empty() {
+        message.push_str("\nstderr:");
+        for line in stderr_tail {
+            message.push_str("\n  ");
+            message.push_str(line);
+        }
+    }
+    LuaError::RuntimeError(message)
+}
+
+/// A command of a pipeline, with its command line for logs and errors
+struct Stage {
+    command: Command,
+    line: String,
+}
+
+impl Stage {
+    fn new(program: &str, args: &[String]) -> Self {
+        let mut command = Command::new(program_path(program));
+        command.args(args);
+        Stage {
+            command,
+            line: format_command(program, args),
+        }
+    }
+}
+
+/// Resolve a program given by a relative path against Lake's directory,
+/// where its permission was checked, rather than the `cwd` of the command
+fn program_path(program: &str) -> PathBuf {
+    let path = Path::new(program);
+    match std::env::current_dir() {
+        Ok(dir) if path.is_relative() && path.components().count() > 1 => dir.join(path),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Render the command line of a pipeline
+fn pipeline_line(stages: &[Stage]) -> String {
+    let lines: Vec<&str> = stages.iter().map(|stage| stage.line.as_str()).collect();
+    lines.join(" | ")
+}
+
+/// Start a command, or a pipeline of commands each reading the output of the
+/// previous one, and return the [`Execution`] to wait for, or the result when
+/// a command could not be started
+fn start(lua: &Lua, stages: Vec<Stage>, options: ExecOptions) -> LuaResult<Value> {
+    let command_line = pipeline_line(&stages);
+    let count = stages.len();
+
+    let mut children: Vec<Child> = Vec::with_capacity(count);
+    let mut lines = Vec::with_capacity(count);
+    for (index, Stage { mut command, line }) in stages.into_iter().enumerate() {
+        options.configure(&mut command);
+        let stdin = match children.last_mut() {
+            Some(previous) => previous.stdout.take().map_or(Stdio::null(), Stdio::from),
+            None if options.stdin.is_some() => Stdio::piped(),
+            None => options.stdio.input(),
+        };
+        let stdout = if index + 1 < count {
+            Stdio::piped()
+        } else {
+            options.stdio.output()
+        };
+        command
+            .stdin(stdin)
+            .stdout(stdout)
+            .stderr(options.stdio.output());
+
+        match command.spawn() {
+            Ok(child) => children.push(child),
+            Err(e) => {
+                kill_all(&mut children);
+                if options.check {
+                    return Err(LuaError::RuntimeError(format!(
+                        "Failed to execute `{}`: {}",
+                        line, e
+                    )));
+                }
+                log::error!("Error executing process: {}", e);
+                let result = lua.create_table()?;
+                result.set("status", -1)?;
+                result.set("stdout", "")?;
+                result.set("stderr", format!("Failed to execute process: {}", e))?;
+                return Ok(Value::Table(result));
+            }
+        }
+        lines.push(line);
+    }
+
+    // Feed the input from a thread, so a command writing before reading cannot block
+    if let (Some(input), Some(mut pipe)) = (options.stdin.clone(), children[0].stdin.take()) {
+        thread::spawn(move || {
+            let _ = pipe.write_all(&input);
+        });
+    }
+
+    // Lines are read on threads and handled when polled, where Lua can be called
+    let (line_tx, line_rx) = mpsc::channel();
+    if let Some(pipe) = children[count - 1].stdout.take() {
+        read_lines(pipe, Stream::Stdout, line_tx.clone());
+    }
+    for child in &mut children {
+        if let Some(pipe) = child.stderr.take() {
+            read_lines(pipe, Stream::Stderr, line_tx.clone());
+        }
+    }
+    drop(line_tx);
+
+    let execution = Execution {
+        command_line,
+        lines,
+        children,
+        line_rx,
+        deadline: options.timeout.map(|timeout| Instant::now() + timeout),
+        options,
+        stdout: Vec::new(),
+        stderr: Vec::new(),
+        stderr_tail: VecDeque::new(),
+        statuses: None,
+        last_output: Instant::now(),
+        closed: false,
+    };
+    Ok(Value::UserData(lua.create_userdata(execution)?))
+}
+
+/// Commands started by `exec`, `sh` or `pipeline`, polled until they exit
+struct Execution {
+    command_line: String,
+    /// Command line of each command of the pipeline
+    lines: Vec<String>,
+    children: Vec<Child>,
+    line_rx: Receiver<(Stream, Vec<u8>)>,
+    options: ExecOptions,
+    deadline: Option<Instant>,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+    stderr_tail: VecDeque<String>,
+    /// Exit statuses, once every command exited
+    statuses: Option<Vec<ExitStatus>>,
+    /// When output was last read, or the commands exited
+    last_output: Instant,
+    /// Every pipe was closed
+    closed: bool,
+}
+
+impl Execution {
+    /// Handle the output until the commands exit and return the result, or
+    /// return `None` when they are still running and `block` is false
+    fn poll(&mut self, lua: &Lua, block: bool) -> LuaResult<Option<Table>> {
+        let started = Instant::now();
+        loop {
+            let wait = if block && !self.closed {
+                POLL_INTERVAL
+            } else {
+                Duration::ZERO
+            };
+            let received = match self.line_rx.recv_timeout(wait) {
+                Ok((stream, line)) => {
+                    self.last_output = Instant::now();
+                    self.handle_line(stream, &line)?;
+                    true
+                }
+                Err(RecvTimeoutError::Disconnected) => {
+                    self.closed = true;
+                    false
+                }
+                Err(RecvTimeoutError::Timeout) => false,
+            };
+
+            if let Some(result) = self.finish(lua)? {
+                return Ok(Some(result));
+            }
+            if !block && (!received || started.elapsed() >= POLL_INTERVAL) {
+                return Ok(None);
+            }
+            if block && self.closed {
+                thread::sleep(POLL_INTERVAL);
+            }
+        }
+    }
+
+    /// Capture, stream or pass a line of output to its callback
+    fn handle_line(&mut self, stream: Stream, line: &[u8]) -> LuaResult<()> {
+        if self.options.capture {
+            match stream {
+                Stream::Stdout => self.stdout.extend_from_slice(line),
+                Stream::Stderr => self.stderr.extend_from_slice(line),
+            }
+        }
+
+        let text = String::from_utf8_lossy(line);
+        let text = text.trim_end_matches(['\n', '\r']);
+        if stream == Stream::Stderr {
+            if self.stderr_tail.len() == STDERR_TAIL_LINES {
+                self.stderr_tail.pop_front();
+            }
+            self.stderr_tail.push_back(text.to_string());
+        }
+        if self.options.stream {
+            let text = match &self.options.prefix {
+                Some(prefix) => format!("[{}] {}", prefix, text),
+                None => text.to_string(),
+            };
+            match stream {
+                Stream::Stdout => println!("{}", text),
+                Stream::Stderr => eprintln!("{}", text),
+            }
+        }
+
+        let callback = match stream {
+            Stream::Stdout => &self.options.on_stdout,
+            Stream::Stderr => &self.options.on_stderr,
+        };
+        if let Some(callback) = callback {
+            // A failing callback stops the command
+            if let Err(e) = callback.call::<()>(text) {
+                kill_all(&mut self.children);
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Return the result once every command exited and its output was read,
+    /// or kill them all once the deadline passes
+    fn finish(&mut self, lua: &Lua) -> LuaResult<Option<Table>> {
+        if self.statuses.is_none() {
+            let mut statuses = Vec::new();
+            for child in &mut self.children {
+                match child.try_wait()? {
+                    Some(status) => statuses.push(status),
+                    None => break,
+                }
+            }
+            if statuses.len() == self.children.len() {
+                self.statuses = Some(statuses);
+                self.last_output = Instant::now();
+            } else if self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+            {
+                // Output of a killed command may be held up by its own children
+                kill_all(&mut self.children);
+                return self.result(lua, None).map(Some);
+            } else {
+                return Ok(None);
+            }
+        }
+
+        // Commands started in the background may keep the pipes open for good
+        if self.closed || self.last_output.elapsed() >= OUTPUT_GRACE {
+            let statuses = self.statuses.clone();
+            return self.result(lua, statuses).map(Some);
+        }
+        Ok(None)
+    }
+
+    /// Build the result of the commands, `None` when they timed out
+    fn result(&self, lua: &Lua, statuses: Option<Vec<ExitStatus>>) -> LuaResult<Table> {
+        let options = &self.options;
+        let command_line = &self.command_line;
+        let result = lua.create_table()?;
+        match statuses {
+            Some(statuses) => {
+                // Like `set -o pipefail`, a pipeline fails with its last failed command
+                let failed = statuses.iter().rposition(|status| !status.success());
+                if let (true, Some(index)) = (options.check, failed) {
+                    let status = statuses[index];
+                    let command = match self.lines.len() {
+                        1 => format!("Command `{}`", command_line),
+                        _ => format!(
+                            "Command `{}` of pipeline `{}`",
+                            self.lines[index], command_line
+                        ),
+                    };
+                    let failure = match status.code() {
+                        Some(code) => format!("failed with exit code {}", code),
+                        None => format!("failed ({})", status),
+                    };
+                    return Err(check_error(&command, &failure, &self.stderr_tail));
+                }
+                let code = |status: &ExitStatus| status.code().unwrap_or(-1);
+                result.set("status", failed.map_or(0, |index| code(&statuses[index])))?;
+                result.set("statuses", statuses.iter().map(code).collect::<Vec<_>>())?;
+            }
+            None => {
+                let timeout = options.timeout.unwrap_or_default().as_millis();
+                if options.check {
+                    let command = format!("Command `{}`", command_line);
+                    let failure = format!("timed out after {} ms", timeout);
+                    return Err(check_error(&command, &failure, &self.stderr_tail));
+                }
+                log::warn!("Command `{}` timed out after {} ms", command_line, timeout);
+                result.set("status", -1)?;
+                result.set("timed_out", true)?;
+            }
+        }
+        if options.capture {
+            result.set("stdout", String::from_utf8_lossy(&self.stdout).to_string())?;
+            result.set("stderr", String::from_utf8_lossy(&self.stderr).to_string())?;
+        }
+        Ok(result)
+    }
+}
+
+impl Drop for Execution {
+    fn drop(&mut self) {
+        // Commands of a task that stopped waiting for them do not outlive it
+        if self.statuses.is_none() {
+            kill_all(&mut self.children);
+        }
+    }
+}
+
+impl UserData for Execution {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method_mut("poll", |lua, this, block: bool| this.poll(lua, block));
+    }
+}
+
+/// Check if the code running is a task that may yield to the others
+fn in_task(lua: &Lua) -> bool {
+    lua.app_data_ref::<TaskThread>()
+        .is_some_and(|task| task.0 == lua.current_thread())
+}
+
+/// Wrap a function returning the result of [`start`] into one returning the
+/// result of the commands
+fn waiting(lua: &Lua, name: &str, start: Function) -> LuaResult<Function> {
+    let in_task = lua.create_function(|lua, ()| Ok(in_task(lua)))?;
+    lua.load(WAIT_SOURCE.replace("NAME", name))
+        .set_name("=[lake.process]")
+        .call((start, in_task))
+}
+
+/// A command started by `spawn`, whose output is read in the background so
+/// that it never blocks on a full pipe
+struct SpawnedProcess {
+    command_line: String,
+    /// `None` in dry runs
+    child: Option<Child>,
+    status: Option<ExitStatus>,
+    stdin: Option<ChildStdin>,
+    stdout: Option<Receiver<(Stream, Vec<u8>)>>,
+    stderr: Option<Receiver<(Stream, Vec<u8>)>>,
+}
+
+impl SpawnedProcess {
+    fn is_running(&mut self) -> LuaResult<bool> {
+        if self.status.is_some() {
+            return Ok(false);
+        }
+        let Some(child) = &mut self.child else {
+            return Ok(false);
+        };
+        self.status = child.try_wait()?;
+        Ok(self.status.is_none())
+    }
+
+    /// Wait for the command to exit, closing its input first
+    fn wait(&mut self) -> LuaResult<Option<ExitStatus>> {
+        self.stdin = None;
+        if let (None, Some(child)) = (self.status, &mut self.child) {
+            self.status = Some(child.wait()?);
+        }
+        Ok(self.status)
+    }
+
+    /// Kill the command if it is still running, and reap it
+    fn kill(&mut self) -> LuaResult<()> {
+        if self.is_running()? {
+            if let Some(child) = &mut self.child {
+                let _ = child.kill();
+            }
+        }
+        self.wait()?;
+        Ok(())
+    }
+
+    fn pipe(&self, stream: Stream) -> Option<&Receiver<(Stream, Vec<u8>)>> {
+        match stream {
+            Stream::Stdout => self.stdout.as_ref(),
+            Stream::Stderr => self.stderr.as_ref(),
+        }
+    }
+
+    /// Next line of a pipe, `None` once it is closed.
+    ///
+    /// Commands started in the background by the spawned one may keep the
+    /// pipe open, so after it exited lines are only awaited for a grace period.
+    fn next_line(&mut self, stream: Stream) -> LuaResult<Option<Vec<u8>>> {
+        loop {
+            let Some(pipe) = self.pipe(stream) else {
+                return Ok(None);
+            };
+            match pipe.recv_timeout(POLL_INTERVAL) {
+                Ok((_, line)) => return Ok(Some(line)),
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+                Err(RecvTimeoutError::Timeout) => {}
+            }
+            if !self.is_running()? {
+                return Ok(self.next_line_after_exit(stream));
+            }
+        }
+    }
+
+    fn next_line_after_exit(&self, stream: Stream) -> Option<Vec<u8>> {
+        let pipe = self.pipe(stream)?;
+        pipe.recv_timeout(OUTPUT_GRACE).ok().map(|(_, line)| line)
+    }
+
+    /// Read everything left in a pipe once the command exited
+    fn read_rest(&self, stream: Stream) -> String {
+        let mut output = Vec::new();
+        while let Some(line) = self.next_line_after_exit(stream) {
+            output.extend_from_slice(&line);
+        }
+        String::from_utf8_lossy(&output).to_string()
     }
 }
 
+impl Drop for SpawnedProcess {
+    fn drop(&mut self) {
+        let _ = self.kill();
+    }
+}
+
+/// Handle returned by `spawn`
+struct ProcessHandle(Rc<RefCell<SpawnedProcess>>);
+
+impl UserData for ProcessHandle {
+    fn add_methods<M: UserDataMethods<Self>>(methods: &mut M) {
+        methods.add_method("pid", |_, this, ()| {
+            Ok(this.0.borrow().child.as_ref().map_or(0, Child::id))
+        });
+
+        methods.add_method("is_running", |_, this, ()| this.0.borrow_mut().is_running());
+
+        // Wait for the command to exit and collect the output not read yet
+        methods.add_method("wait", |lua, this, ()| {
+            let mut process = this.0.borrow_mut();
+            let status = process.wait()?;
+            let result = lua.create_table()?;
+            result.set(
+                "status",
+                status.map_or(0, |status| status.code().unwrap_or(-1)),
+            )?;
+            result.set("stdout", process.read_rest(Stream::Stdout))?;
+            result.set("stderr", process.read_rest(Stream::Stderr))?;
+            Ok(result)
+        });
+
+        methods.add_method("kill", |_, this, ()| this.0.borrow_mut().kill());
+
+        // Next line of stdout, or of stderr when asked, nil once the command closed it
+        methods.add_method("read_line", |_, this, stream: Option<String>| {
+            let stream = match stream.as_deref() {
+                None | Some("stdout") => Stream::Stdout,
+                Some("stderr") => Stream::Stderr,
+                Some(other) => {
+                    return Err(LuaError::RuntimeError(format!(
+                        "Invalid stream {:?}: expected \"stdout\" or \"stderr\"",
+                        other
+                    )))
+                }
+            };
+            let Some(line) = this.0.borrow_mut().next_line(stream)? else {
+                return Ok(None);
+            };
+            let line = String::from_utf8_lossy(&line);
+            Ok(Some(line.trim_end_matches(['\n', '\r']).to_string()))
+        });
+
+        methods.add_method("stdin_write", |_, this, data: mlua::String| {
+            let mut process = this.0.borrow_mut();
+            if process.child.is_none() {
+                return Ok(());
+            }
+            let command_line = process.command_line.clone();
+            let Some(stdin) = &mut process.stdin else {
+                return Err(LuaError::RuntimeError(format!(
+                    "Input of `{}` is closed",
+                    command_line
+                )));
+            };
+            stdin
+                .write_all(&data.as_bytes())
+                .and_then(|()| stdin.flush())
+                .map_err(|e| {
+                    LuaError::RuntimeError(format!("Failed to write to `{}`: {}", command_line, e))
+                })
+        });
+
+        // Signal the end of the input
+        methods.add_method("stdin_close", |_, this, ()| {
+            this.0.borrow_mut().stdin = None;
+            Ok(())
+        });
+    }
+}
+
+/// Commands started by `spawn` in a Lua state
+#[derive(Default)]
+struct SpawnedProcesses(Vec<Rc<RefCell<SpawnedProcess>>>);
+
+/// Kill the commands started by `spawn` that are still running, and reap the others
+pub fn reap_children(lua: &Lua) {
+    let Some(spawned) = lua.remove_app_data::<SpawnedProcesses>() else {
+        return;
+    };
+    for process in spawned.0 {
+        let mut process = process.borrow_mut();
+        if process.is_running().unwrap_or(false) {
+            log::warn!("Killing `{}`, still running", process.command_line);
+        }
+        if let Err(e) = process.kill() {
+            log::error!("Error killing `{}`: {}", process.command_line, e);
+        }
+    }
+}
+
+/// Start a command, keeping track of it until the build ends
+fn spawn(lua: &Lua, stage: Stage, options: ExecOptions) -> LuaResult<ProcessHandle> {
+    let Stage {
+        mut command,
+        line: command_line,
+    } = stage;
+    options.configure(&mut command);
+    let stdin = match options.stdio {
+        StdioMode::Pipe => Stdio::piped(),
+        mode => mode.input(),
+    };
+    command
+        .stdin(stdin)
+        .stdout(options.stdio.output())
+        .stderr(options.stdio.output());
+
+    let mut child = command.spawn().map_err(|e| {
+        LuaError::RuntimeError(format!("Failed to spawn `{}`: {}", command_line, e))
+    })?;
+
+    let process = SpawnedProcess {
+        command_line,
+        status: None,
+        stdin: child.stdin.take(),
+        stdout: child
+            .stdout
+            .take()
+            .map(|pipe| buffer_lines(pipe, Stream::Stdout)),
+        stderr: child
+            .stderr
+            .take()
+            .map(|pipe| buffer_lines(pipe, Stream::Stderr)),
+        child: Some(child),
+    };
+    Ok(track(lua, process))
+}
+
+/// Refuse the options of `exec` that `spawn` does not support, rather than ignoring them
+fn check_spawn_options(options: Option<&Table>) -> LuaResult<()> {
+    let Some(options) = options else {
+        return Ok(());
+    };
+    for pair in options.pairs::<String, Value>() {
+        let (name, _) = pair?;
+        if !SPAWN_OPTIONS.contains(&name.as_str()) {
+            return Err(LuaError::RuntimeError(format!(
+                "Invalid option {:?} for spawn: expected one of {}",
+                name,
+                SPAWN_OPTIONS.join(", ")
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Read the lines of a pipe in the background, until they are received
+fn buffer_lines(pipe: impl Read + Send + 'static, stream: Stream) -> Receiver<(Stream, Vec<u8>)> {
+    let (line_tx, line_rx) = mpsc::channel();
+    read_lines(pipe, stream, line_tx);
+    line_rx
+}
+
+/// Register a spawned command, to be reaped when the build ends
+fn track(lua: &Lua, process: SpawnedProcess) -> ProcessHandle {
+    let process = Rc::new(RefCell::new(process));
+    if lua.app_data_ref::<SpawnedProcesses>().is_none() {
+        lua.set_app_data(SpawnedProcesses::default());
+    }
+    if let Some(mut spawned) = lua.app_data_mut::<SpawnedProcesses>() {
+        spawned.0.push(Rc::clone(&process));
+    }
+    ProcessHandle(process)
+}
+
+/// Quote an argument for a POSIX shell, leaving plain words as they are
+fn shell_quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "_-+=/.,:@%".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Result of a command that was not run because of a dry run
+fn dry_run_result(lua: &Lua, stages: usize) -> LuaResult<Table> {
+    let result = lua.create_table()?;
+    result.set("status", 0)?;
+    result.set("statuses", vec![0; stages])?;
+    result.set("stdout", "")?;
+    result.set("stderr", "")?;
+    Ok(result)
+}
+
 impl Plugin for ProcessPlugin {
     fn register(&self, lua: &Lua) -> LuaResult<()> {
+        let dry_run = self.dry_run;
         let globals = lua.globals();
         let process = lua.create_table()?;
 
         // exec function
         process.set(
             "exec",
-            lua.create_function(|lua, (cmd, args): (String, Option<Table>)| {
-                let args_vec: Vec<String> = match args {
-                    Some(args_table) => {
-                        let mut result = Vec::new();
-                        for i in 1..=args_table.len()? {
-                            if let Ok(arg) = args_table.get(i) {
-                                result.push(arg);
-                            }
+            waiting(
+                lua,
+                "exec",
+                lua.create_function(
+                    move |lua, (cmd, args, options): (String, Option<Table>, Option<Table>)| {
+                        let args_vec = args_to_vec(args)?;
+                        let options = ExecOptions::from_lua(lua, options)?;
+
+                        check_run(lua, &cmd)?;
+                        let stage = Stage::new(&cmd, &args_vec);
+                        if dry_run {
+                            log_dry_run(&format!("exec {}", stage.line));
+                            return dry_run_result(lua, 1).map(Value::Table);
                         }
-                        result
-                    }
-                    None => Vec::new(),
-                };
 
-                let output = Command::new(&cmd).args(&args_vec).output();
-
-                match output {
-                    Ok(output) => {
-                        let result = lua.create_table()?;
-                        result.set("status", output.status.code().unwrap_or(-1))?;
-                        result.set(
-                            "stdout",
-                            String::from_utf8_lossy(&output.stdout).to_string(),
-                        )?;
-                        result.set(
-                            "stderr",
-                            String::from_utf8_lossy(&output.stderr).to_string(),
-                        )?;
-                        Ok(result)
+                        start(lua, vec![stage], options)
+                    },
+                )?,
+            )?,
+        )?;
+
+        // sh function (runs a command line through the shell)
+        process.set(
+            "sh",
+            waiting(
+                lua,
+                "sh",
+                lua.create_function(
+                    move |lua, (command_line, options): (String, Option<Table>)| {
+                        let options = ExecOptions::from_lua(lua, options)?;
+
+                        // The system shell is granted by its name
+                        check_run(lua, "sh")?;
+                        if dry_run {
+                            log_dry_run(&format!("sh {}", command_line));
+                            return dry_run_result(lua, 1).map(Value::Table);
+                        }
+
+                        let mut command = Command::new(SHELL);
+                        command.arg("-c").arg(&command_line);
+                        let stage = Stage {
+                            command,
+                            line: command_line,
+                        };
+                        start(lua, vec![stage], options)
+                    },
+                )?,
+            )?,
+        )?;
+
+        // pipeline function (connects commands without a shell)
+        process.set(
+            "pipeline",
+            waiting(
+                lua,
+                "pipeline",
+                lua.create_function(move |lua, (commands, options): (Table, Option<Table>)| {
+                    let options = ExecOptions::from_lua(lua, options)?;
+
+                    let mut stages = Vec::new();
+                    for command in commands.sequence_values::<Table>() {
+                        let mut argv = args_to_vec(Some(command?))?;
+                        if argv.is_empty() {
+                            return Err(LuaError::RuntimeError(
+                                "Invalid pipeline: every command needs a program".to_string(),
+                            ));
+                        }
+                        let program = argv.remove(0);
+                        check_run(lua, &program)?;
+                        stages.push(Stage::new(&program, &argv));
                     }
-                    Err(e) => {
-                        log::error!("Error executing process: {}", e);
-                        let result = lua.create_table()?;
-                        result.set("status", -1)?;
-                        result.set("stdout", "")?;
-                        result.set("stderr", format!("Failed to execute process: {}", e))?;
-                        Ok(result)
+                    if stages.is_empty() {
+                        return Err(LuaError::RuntimeError(
+                            "Invalid pipeline: expected at least one command".to_string(),
+                        ));
                     }
-                }
+
+                    if dry_run {
+                        log_dry_run(&format!("pipeline {}", pipeline_line(&stages)));
+                        return dry_run_result(lua, stages.len()).map(Value::Table);
+                    }
+
+                    start(lua, stages, options)
+                })?,
+            )?,
+        )?;
+
+        // quote function (quotes an argument for `sh`)
+        process.set(
+            "quote",
+            lua.create_function(|_, arg: String| Ok(shell_quote(&arg)))?,
+        )?;
+
+        // join function (quotes and joins arguments for `sh`)
+        process.set(
+            "join",
+            lua.create_function(|_, args: Table| {
+                let args: Vec<String> = args_to_vec(Some(args))?
+                    .iter()
+                    .map(|arg| shell_quote(arg))
+                    .collect();
+                Ok(args.join(" "))
             })?,
         )?;
 
-        // spawn function (returns pid)
+        // spawn function (returns a handle to the running command)
         process.set(
             "spawn",
-            lua.create_function(|_, (cmd, args): (String, Option<Table>)| {
-                let args_vec: Vec<String> = match args {
-                    Some(args_table) => {
-                        let mut result = Vec::new();
-                        for i in 1..=args_table.len()? {
-                            if let Ok(arg) = args_table.get(i) {
-                                result.push(arg);
-                            }
-                        }
-                        result
-                    }
-                    None => Vec::new(),
-                };
+            lua.create_function(
+                move |lua, (cmd, args, options): (String, Option<Table>, Option<Table>)| {
+                    let args_vec = args_to_vec(args)?;
+                    check_spawn_options(options.as_ref())?;
+                    let options = ExecOptions::from_lua(lua, options)?;
 
-                let output = Command::new(&cmd)
-                    .args(&args_vec)
-                    .stdout(Stdio::piped())
-                    .stderr(Stdio::piped())
-                    .spawn();
-
-                match output {
-                    Ok(output) => Ok(output.id() as i32),
-                    Err(e) => {
-                        log::error!("Error executing process: {}", e);
-                        Ok(-1)
+                    check_run(lua, &cmd)?;
+                    let stage = Stage::new(&cmd, &args_vec);
+                    if dry_run {
+                        log_dry_run(&format!("spawn {}", stage.line));
+                        return Ok(track(
+                            lua,
+                            SpawnedProcess {
+                                command_line: stage.line,
+                                child: None,
+                                status: None,
+                                stdin: None,
+                                stdout: None,
+                                stderr: None,
+                            },
+                        ));
                     }
-                }
-            })?,
+
+                    spawn(lua, stage, options)
+                },
+            )?,
         )?;
 
         globals.set("lake.process", process)?;
@@ -105,3 +1069,201 @@ impl Plugin for ProcessPlugin {
         "process"
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use crate::lake::Project;
+    use crate::permissions::{Allow, Grant, Permissions};
+    use crate::test_support::{lock_cwd, options};
+
+    use super::*;
+
+    /// Run a script allowed to run `sh` and set `VALUE` and return its Lua state
+    fn run(script: &str) -> Lua {
+        let _cwd = lock_cwd();
+        let lua = Lua::new();
+        let allow = Allow {
+            run: Grant::Only(vec!["sh".to_string()]),
+            env: Grant::Only(vec!["VALUE".to_string()]),
+            ..Allow::default()
+        };
+        lua.set_app_data(Permissions::new(&allow, Path::new(".")));
+        ProcessPlugin::new(false).register(&lua).unwrap();
+        lua.load(script).exec().unwrap();
+        lua
+    }
+
+    /// Evaluate a build file allowed to run `sh` and set `VALUE` and return its Lua state
+    fn evaluate(script: &str) -> Lua {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let build_file = dir.path().join("build.lake");
+        fs::write(&build_file, script).unwrap();
+        let allow = Allow {
+            run: Grant::Only(vec!["sh".to_string()]),
+            env: Grant::Only(vec!["VALUE".to_string()]),
+            ..Allow::default()
+        };
+        let project = Project::load(&build_file, &options(dir.path(), &allow)).unwrap();
+        project.lua
+    }
+
+    #[test]
+    fn streams_lines_to_callbacks() {
+        let lua = run(r#"
+local process = _G["lake.process"]
+out, err = {}, {}
+streamed = process.exec("sh", { "-c", "while read line; do echo \"got $line\"; done; echo oops >&2" }, {
+    stdin = "a\nb\n",
+    on_stdout = function(line) out[#out + 1] = line end,
+    on_stderr = function(line) err[#err + 1] = line end,
+})
+captured = process.exec("sh", { "-c", "echo a; echo b" }, { stream = true, capture = true })
+"#);
+        let globals = lua.globals();
+        assert_eq!(
+            globals.get::<Vec<String>>("out").unwrap(),
+            ["got a", "got b"]
+        );
+        assert_eq!(globals.get::<Vec<String>>("err").unwrap(), ["oops"]);
+        let streamed: Table = globals.get("streamed").unwrap();
+        assert_eq!(streamed.get::<i32>("status").unwrap(), 0);
+        assert_eq!(streamed.get::<Option<String>>("stdout").unwrap(), None);
+        let captured: Table = globals.get("captured").unwrap();
+        assert_eq!(captured.get::<String>("stdout").unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn applies_exec_options() {
+        let lua = run(r#"
+local process = _G["lake.process"]
+env = process.exec("sh", { "-c", "echo \"$VALUE-$HOME\"" }, { env = { VALUE = 1 }, clear_env = true })
+cwd = process.exec("sh", { "-c", "pwd" }, { cwd = "src" })
+timed_out = process.exec("sh", { "-c", "sleep 5" }, { timeout_ms = 50 })
+local ok, message = pcall(process.exec, "sh", { "-c", "echo first >&2; echo last >&2; exit 3" }, { check = true })
+failure = tostring(message)
+"#);
+        let globals = lua.globals();
+        let env: Table = globals.get("env").unwrap();
+        assert_eq!(env.get::<String>("stdout").unwrap(), "1-\n");
+        let cwd: Table = globals.get("cwd").unwrap();
+        assert!(cwd.get::<String>("stdout").unwrap().ends_with("/src\n"));
+        let timed_out: Table = globals.get("timed_out").unwrap();
+        assert_eq!(timed_out.get::<i32>("status").unwrap(), -1);
+        assert!(timed_out.get::<bool>("timed_out").unwrap());
+
+        let failure: String = globals.get("failure").unwrap();
+        assert!(failure.contains("Command `sh -c "), "{}", failure);
+        assert!(failure.contains("failed with exit code 3"), "{}", failure);
+        assert!(failure.contains("stderr:\n  first\n  last"), "{}", failure);
+    }
+
+    #[test]
+    fn runs_shell_commands_and_pipelines() {
+        let lua = run(r#"
+local process = _G["lake.process"]
+quoted = process.join({ "plain", "two words", "it's" })
+shell = process.sh("printf '%s\\n' " .. quoted .. " | wc -l")
+pipeline = process.pipeline({ { "sh", "-c", "printf 'a.rs\\nb.txt\\nc.rs\\n'" }, { "sh", "-c", "grep rs" } })
+failed = process.pipeline({ { "sh", "-c", "exit 2" }, { "sh", "-c", "cat" } })
+"#);
+        let globals = lua.globals();
+        assert_eq!(
+            globals.get::<String>("quoted").unwrap(),
+            r#"plain 'two words' 'it'\''s'"#
+        );
+        let shell: Table = globals.get("shell").unwrap();
+        assert_eq!(shell.get::<String>("stdout").unwrap().trim(), "3");
+        let pipeline: Table = globals.get("pipeline").unwrap();
+        assert_eq!(pipeline.get::<String>("stdout").unwrap(), "a.rs\nc.rs\n");
+        assert_eq!(pipeline.get::<Vec<i32>>("statuses").unwrap(), [0, 0]);
+        let failed: Table = globals.get("failed").unwrap();
+        assert_eq!(failed.get::<i32>("status").unwrap(), 2);
+        assert_eq!(failed.get::<Vec<i32>>("statuses").unwrap(), [2, 0]);
+    }
+
+    #[test]
+    fn spawned_commands_do_not_wait_for_background_ones() {
+        let started = Instant::now();
+        let lua = evaluate(
+            r#"
+local process = plugin("lake.process")
+local reader = process.spawn("sh", { "-c", "sleep 5 & echo first; echo second" })
+lines = { reader:read_line(), reader:read_line(), tostring(reader:read_line()) }
+result = process.spawn("sh", { "-c", "sleep 5 & echo done" }):wait()
+"#,
+        );
+        assert!(started.elapsed() < Duration::from_secs(4));
+
+        let lines: Vec<String> = lua.globals().get("lines").unwrap();
+        assert_eq!(lines, ["first", "second", "nil"]);
+        let result: Table = lua.globals().get("result").unwrap();
+        assert_eq!(result.get::<i32>("status").unwrap(), 0);
+        assert_eq!(result.get::<String>("stdout").unwrap(), "done\n");
+    }
+
+    #[test]
+    fn spawn_refuses_unsupported_options() {
+        let lua = evaluate(
+            r#"
+local process = plugin("lake.process")
+local ok, message = pcall(process.spawn, "sh", { "-c", "true" }, { timeout_ms = 10 })
+err = tostring(message)
+local child = process.spawn("sh", { "-c", "echo $VALUE" }, { env = { VALUE = "set" }, stdio = "pipe" })
+result = child:wait()
+"#,
+        );
+        let err: String = lua.globals().get("err").unwrap();
+        assert!(
+            err.contains("Invalid option \"timeout_ms\" for spawn"),
+            "{}",
+            err
+        );
+        let result: Table = lua.globals().get("result").unwrap();
+        assert_eq!(result.get::<String>("stdout").unwrap(), "set\n");
+    }
+
+    #[test]
+    fn runs_only_the_granted_programs() {
+        let lua = run(r#"
+local process = _G["lake.process"]
+local _, message = pcall(process.exec, "./tools/sh", { "-c", "echo ARBITRARY CODE" })
+denied = tostring(message)
+"#);
+        let denied: String = lua.globals().get("denied").unwrap();
+        assert!(
+            denied.contains("needs --allow-run=./tools/sh"),
+            "{}",
+            denied
+        );
+    }
+
+    #[test]
+    fn checks_the_environment_and_directory_of_commands() {
+        let lua = run(r#"
+local process = _G["lake.process"]
+local _, message = pcall(process.exec, "sh", { "-c", "true" }, { env = { LD_PRELOAD = "evil.so" } })
+env = tostring(message)
+local _, message = pcall(process.spawn, "sh", { "-c", "true" }, { env = { PATH = "." } })
+spawn_env = tostring(message)
+local _, message = pcall(process.exec, "sh", { "-c", "true" }, { cwd = "/" })
+cwd = tostring(message)
+"#);
+        let globals = lua.globals();
+        for (name, flag) in [
+            ("env", "env=LD_PRELOAD"),
+            ("spawn_env", "env=PATH"),
+            ("cwd", "read=/"),
+        ] {
+            let message: String = globals.get(name).unwrap();
+            assert!(
+                message.contains(&format!("needs --allow-{}", flag)),
+                "{}",
+                message
+            );
+        }
+    }
+}
@@ -69,7 +69,7 @@ impl Plugin for RandomPlugin {
         random.set(
             "rnd_float",
             lua.create_function(
-                |_, args: mlua::MultiValue| match (args.get(0), args.get(1)) {
+                |_, args: mlua::MultiValue| match (args.front(), args.get(1)) {
                     (None, None) => Ok(rand::random::<f64>()),
                     (Some(mlua::Value::Number(min)), Some(mlua::Value::Number(max))) => {
                         let min = *min;
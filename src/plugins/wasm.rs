@@ -0,0 +1,549 @@
+//! WebAssembly plugins for Lake
+//!
+//! Runs `.wasm` plugins with a bundled interpreter. Every exported function
+//! becomes a function of the plugin table: numbers are passed as is, strings
+//! are copied into the module memory with its exported `alloc(len) -> ptr`
+//! and passed as a pointer and a length.
+//!
+//! Modules can only import the host functions of the `lake` module, which
+//! check the sandbox permissions before acting:
+//!
+//! - `log(level, ptr, len)` logs a message, levels go from 0 (error) to 4 (trace)
+//! - `env_get(name_ptr, name_len, out_ptr, out_cap) -> len` reads a variable, -1 if unset
+//! - `read_file(path_ptr, path_len, out_ptr, out_cap) -> len` reads a file, -1 if missing
+//! - `write_file(path_ptr, path_len, data_ptr, data_len)` writes a file
+//! - `result(ptr, len)` makes the current call return a string
+//!
+//! Every call, and the instantiation of a module, runs on a fixed budget of
+//! fuel, so a plugin stuck in a loop fails instead of hanging the build.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::path::Path;
+use std::rc::Rc;
+
+use mlua::{Error as LuaError, Lua, MultiValue, Result as LuaResult, Table, Value};
+use wasmi::core::{TrapCode, ValType};
+use wasmi::{
+    Caller, Config, Engine, Error, Extern, ExternType, Linker, Memory, Module, Store, Val,
+};
+
+use crate::permissions::Permissions;
+use crate::plugins::{env_plugin, log_dry_run};
+
+/// Fuel given to every call, roughly the number of instructions it may execute
+const FUEL: u64 = 1_000_000_000;
+
+/// State available to the host functions
+struct Host {
+    permissions: Permissions,
+    dry_run: bool,
+    /// Fuel given to every call
+    fuel: u64,
+    /// Variables set by the build for the task making the current call
+    env: HashMap<String, String>,
+    /// String set by `result` during the current call
+    result: Option<Vec<u8>>,
+}
+
+type Instance = Rc<RefCell<(Store<Host>, wasmi::Instance)>>;
+
+/// Load a WebAssembly plugin and return a table of its exported functions
+pub fn load(lua: &Lua, name: &str, path: &Path, dry_run: bool) -> LuaResult<Table> {
+    load_with_fuel(lua, name, path, dry_run, FUEL)
+}
+
+fn load_with_fuel(
+    lua: &Lua,
+    name: &str,
+    path: &Path,
+    dry_run: bool,
+    fuel: u64,
+) -> LuaResult<Table> {
+    let error = |message: String| {
+        LuaError::RuntimeError(format!(
+            "WebAssembly plugin '{}' at {} {}",
+            name,
+            path.display(),
+            message
+        ))
+    };
+
+    let bytes = std::fs::read(path).map_err(|e| error(format!("could not be read: {}", e)))?;
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    let engine = Engine::new(&config);
+    let module =
+        Module::new(&engine, &bytes[..]).map_err(|e| error(format!("is invalid: {}", e)))?;
+
+    let permissions = lua
+        .app_data_ref::<Permissions>()
+        .map(|permissions| permissions.clone())
+        .unwrap_or_default();
+    let mut store = Store::new(
+        &engine,
+        Host {
+            permissions,
+            dry_run,
+            fuel,
+            env: HashMap::new(),
+            result: None,
+        },
+    );
+    refuel(&mut store).map_err(|e| error(e.to_string()))?;
+
+    let linker = host_functions(&engine).map_err(|e| error(e.to_string()))?;
+    let instance = linker
+        .instantiate(&mut store, &module)
+        .and_then(|instance| instance.start(&mut store))
+        .map_err(|e| error(format!("could not be instantiated: {}", describe(e, fuel))))?;
+    let instance: Instance = Rc::new(RefCell::new((store, instance)));
+
+    let plugin = lua.create_table()?;
+    for export in module.exports() {
+        let ExternType::Func(func_type) = export.ty() else {
+            continue;
+        };
+        let params = func_type.params().to_vec();
+        let function_name = export.name().to_string();
+        let instance = instance.clone();
+
+        plugin.set(
+            export.name(),
+            lua.create_function(move |lua, args: MultiValue| {
+                call(lua, &instance, &function_name, &params, args)
+            })?,
+        )?;
+    }
+
+    Ok(plugin)
+}
+
+/// Call an exported function with Lua arguments
+fn call(
+    lua: &Lua,
+    instance: &Instance,
+    name: &str,
+    params: &[ValType],
+    args: MultiValue,
+) -> LuaResult<MultiValue> {
+    let mut guard = instance.borrow_mut();
+    let (store, instance) = &mut *guard;
+    let fuel = store.data().fuel;
+    let to_lua_error =
+        |e: Error| LuaError::RuntimeError(format!("{}: {}", name, describe(e, fuel)));
+
+    // Copying the arguments runs `alloc`, which counts against the call
+    refuel(store).map_err(to_lua_error)?;
+
+    // Strings take two parameters, a pointer and a length
+    let mut values = Vec::new();
+    for arg in args {
+        match arg {
+            Value::String(string) => {
+                let bytes = string.as_bytes();
+                let ptr = write_argument(store, instance, &bytes).map_err(to_lua_error)?;
+                values.push(Val::I32(ptr));
+                values.push(Val::I32(bytes.len() as i32));
+            }
+            Value::Integer(integer) => values.push(Val::I64(integer)),
+            Value::Number(number) => values.push(Val::F64(number.into())),
+            Value::Boolean(boolean) => values.push(Val::I32(boolean as i32)),
+            other => {
+                return Err(LuaError::RuntimeError(format!(
+                    "{}: cannot pass a {} to WebAssembly",
+                    name,
+                    other.type_name()
+                )))
+            }
+        }
+    }
+    if values.len() != params.len() {
+        return Err(LuaError::RuntimeError(format!(
+            "{}: expected {} parameters, got {} (strings count as two)",
+            name,
+            params.len(),
+            values.len()
+        )));
+    }
+    let values: Vec<Val> = values
+        .into_iter()
+        .zip(params)
+        .map(|(value, ty)| convert(value, ty))
+        .collect();
+
+    let func = instance
+        .get_func(&*store, name)
+        .ok_or_else(|| LuaError::RuntimeError(format!("{}: function not found", name)))?;
+    let mut results = vec![Val::I32(0); func.ty(&*store).results().len()];
+
+    store.data_mut().env = env_plugin::overrides(lua).into_iter().collect();
+    store.data_mut().result = None;
+    func.call(&mut *store, &values, &mut results)
+        .map_err(to_lua_error)?;
+
+    if let Some(result) = store.data_mut().result.take() {
+        return Ok(MultiValue::from_iter([Value::String(
+            lua.create_string(result)?,
+        )]));
+    }
+    Ok(results
+        .into_iter()
+        .map(|result| match result {
+            Val::I32(value) => Value::Integer(value as i64),
+            Val::I64(value) => Value::Integer(value),
+            Val::F32(value) => Value::Number(f32::from(value) as f64),
+            Val::F64(value) => Value::Number(f64::from(value)),
+            _ => Value::Nil,
+        })
+        .collect())
+}
+
+/// Give the store the fuel of a new call
+fn refuel(store: &mut Store<Host>) -> Result<(), Error> {
+    let fuel = store.data().fuel;
+    store.set_fuel(fuel).map_err(|e| Error::new(e.to_string()))
+}
+
+/// Describe an error, naming the fuel limit when the module ran out of it
+fn describe(e: Error, fuel: u64) -> String {
+    if e.as_trap_code() == Some(TrapCode::OutOfFuel) {
+        return format!("ran out of fuel after about {} instructions", fuel);
+    }
+    e.to_string()
+}
+
+/// Convert a Lua number to the type of a parameter
+fn convert(value: Val, ty: &ValType) -> Val {
+    let (integer, number) = match value {
+        Val::I32(value) => (value as i64, value as f64),
+        Val::I64(value) => (value, value as f64),
+        Val::F64(value) => (f64::from(value) as i64, f64::from(value)),
+        other => return other,
+    };
+    match ty {
+        ValType::I32 => Val::I32(integer as i32),
+        ValType::I64 => Val::I64(integer),
+        ValType::F32 => Val::F32((number as f32).into()),
+        ValType::F64 => Val::F64(number.into()),
+        _ => value,
+    }
+}
+
+/// Copy a string argument into the module memory, using its `alloc` export
+fn write_argument(
+    store: &mut Store<Host>,
+    instance: &wasmi::Instance,
+    bytes: &[u8],
+) -> Result<i32, Error> {
+    let alloc = instance
+        .get_typed_func::<i32, i32>(&*store, "alloc")
+        .map_err(|_| Error::new("strings need an exported `alloc(len: i32) -> i32` function"))?;
+    let memory = instance
+        .get_memory(&*store, "memory")
+        .ok_or_else(|| Error::new("strings need an exported `memory`"))?;
+
+    let ptr = alloc.call(&mut *store, bytes.len() as i32)?;
+    memory
+        .write(&mut *store, ptr as usize, bytes)
+        .map_err(|e| Error::new(e.to_string()))?;
+    Ok(ptr)
+}
+
+/// Define the host functions of the `lake` import module
+fn host_functions(engine: &Engine) -> Result<Linker<Host>, Error> {
+    let mut linker = Linker::new(engine);
+
+    linker.func_wrap(
+        "lake",
+        "log",
+        |caller: Caller<'_, Host>, level: i32, ptr: i32, len: i32| -> Result<(), Error> {
+            let message = read_string(&caller, ptr, len)?;
+            let level = match level {
+                0 => log::Level::Error,
+                1 => log::Level::Warn,
+                2 => log::Level::Info,
+                3 => log::Level::Debug,
+                _ => log::Level::Trace,
+            };
+            log::log!(level, "{}", message);
+            Ok(())
+        },
+    )?;
+
+    linker.func_wrap(
+        "lake",
+        "env_get",
+        |mut caller: Caller<'_, Host>,
+         name_ptr: i32,
+         name_len: i32,
+         out_ptr: i32,
+         out_cap: i32|
+         -> Result<i32, Error> {
+            let name = read_string(&caller, name_ptr, name_len)?;
+            caller
+                .data()
+                .permissions
+                .check_env(&name)
+                .map_err(to_wasm_error)?;
+            let value = match caller.data().env.get(&name) {
+                Some(value) => Some(value.clone()),
+                None => std::env::var(&name).ok(),
+            };
+            match value {
+                Some(value) => write_output(&mut caller, value.as_bytes(), out_ptr, out_cap),
+                None => Ok(-1),
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "lake",
+        "read_file",
+        |mut caller: Caller<'_, Host>,
+         path_ptr: i32,
+         path_len: i32,
+         out_ptr: i32,
+         out_cap: i32|
+         -> Result<i32, Error> {
+            let path = read_string(&caller, path_ptr, path_len)?;
+            caller
+                .data()
+                .permissions
+                .check_read(Path::new(&path))
+                .map_err(to_wasm_error)?;
+            match std::fs::read(&path) {
+                Ok(content) => write_output(&mut caller, &content, out_ptr, out_cap),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(-1),
+                Err(e) => Err(Error::new(format!("Failed to read {}: {}", path, e))),
+            }
+        },
+    )?;
+
+    linker.func_wrap(
+        "lake",
+        "write_file",
+        |caller: Caller<'_, Host>,
+         path_ptr: i32,
+         path_len: i32,
+         data_ptr: i32,
+         data_len: i32|
+         -> Result<(), Error> {
+            let path = read_string(&caller, path_ptr, path_len)?;
+            caller
+                .data()
+                .permissions
+                .check_write(Path::new(&path))
+                .map_err(to_wasm_error)?;
+            let content = read_bytes(&caller, data_ptr, data_len)?;
+            if caller.data().dry_run {
+                log_dry_run(&format!("write {} ({} bytes)", path, content.len()));
+                return Ok(());
+            }
+            std::fs::write(&path, content)
+                .map_err(|e| Error::new(format!("Failed to write {}: {}", path, e)))
+        },
+    )?;
+
+    linker.func_wrap(
+        "lake",
+        "result",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<(), Error> {
+            let result = read_bytes(&caller, ptr, len)?;
+            caller.data_mut().result = Some(result);
+            Ok(())
+        },
+    )?;
+
+    Ok(linker)
+}
+
+fn memory(caller: &Caller<'_, Host>) -> Result<Memory, Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Error::new("the module does not export its `memory`"))
+}
+
+/// Copy bytes out of the guest memory, checking the range before allocating,
+/// since the guest chooses the length
+fn read_bytes(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Result<Vec<u8>, Error> {
+    let data = memory(caller)?.data(caller);
+    let start = ptr as u32 as usize;
+    usize::try_from(len)
+        .ok()
+        .and_then(|len| start.checked_add(len))
+        .and_then(|end| data.get(start..end))
+        .map(<[u8]>::to_vec)
+        .ok_or_else(|| {
+            Error::new(format!(
+                "out of bounds memory access: {} bytes at {} in {} bytes of memory",
+                len,
+                start,
+                data.len()
+            ))
+        })
+}
+
+fn read_string(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Result<String, Error> {
+    String::from_utf8(read_bytes(caller, ptr, len)?)
+        .map_err(|e| Error::new(format!("invalid UTF-8 string: {}", e)))
+}
+
+/// Copy as much of `content` as fits in the output buffer and return its full length
+fn write_output(
+    caller: &mut Caller<'_, Host>,
+    content: &[u8],
+    ptr: i32,
+    cap: i32,
+) -> Result<i32, Error> {
+    let written = content.len().min(cap.max(0) as usize);
+    memory(caller)?
+        .write(&mut *caller, ptr as u32 as usize, &content[..written])
+        .map_err(|e| Error::new(e.to_string()))?;
+    Ok(content.len() as i32)
+}
+
+fn to_wasm_error(e: LuaError) -> Error {
+    match e {
+        LuaError::RuntimeError(message) => Error::new(message),
+        other => Error::new(other.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mlua::Function;
+
+    use crate::permissions::{Allow, Grant};
+
+    use super::*;
+
+    /// Load the plugin in tests/fixtures/plugin.wat with the given grants
+    fn load_fixture(dir: &Path, allow: &Allow, dry_run: bool, fuel: u64) -> (Lua, Table) {
+        let source = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/plugin.wat");
+        let path = dir.join("plugin.wasm");
+        std::fs::write(&path, wat::parse_file(source).unwrap()).unwrap();
+
+        let lua = Lua::new();
+        lua.set_app_data(Permissions::new(allow, dir));
+        let plugin = load_with_fuel(&lua, "plugin", &path, dry_run, fuel).unwrap();
+        (lua, plugin)
+    }
+
+    fn function(plugin: &Table, name: &str) -> Function {
+        plugin.get(name).unwrap()
+    }
+
+    #[test]
+    fn passes_numbers_and_strings() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_lua, plugin) = load_fixture(dir.path(), &Allow::default(), false, FUEL);
+
+        assert_eq!(function(&plugin, "add").call::<i64>((2, 3)).unwrap(), 5);
+        assert_eq!(function(&plugin, "half").call::<f64>(3).unwrap(), 1.5);
+        assert_eq!(
+            function(&plugin, "echo").call::<String>("héllo").unwrap(),
+            "héllo"
+        );
+
+        let error = function(&plugin, "add")
+            .call::<i64>(2)
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("expected 2 parameters, got 1"), "{}", error);
+    }
+
+    #[test]
+    fn host_functions_check_permissions() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_lua, plugin) = load_fixture(dir.path(), &Allow::default(), false, FUEL);
+
+        let denials = [
+            (
+                function(&plugin, "env").call::<Value>("HOME"),
+                "--allow-env=HOME",
+            ),
+            (
+                function(&plugin, "read").call::<Value>("/etc/hostname"),
+                "--allow-read=/etc/hostname",
+            ),
+            (
+                function(&plugin, "write").call::<Value>(("out.txt", "data")),
+                "--allow-write=",
+            ),
+        ];
+        for (result, flag) in denials {
+            let message = result.unwrap_err().to_string();
+            assert!(
+                message.contains(&format!("permission denied: needs {}", flag)),
+                "{}",
+                message
+            );
+        }
+        assert!(!dir.path().join("out.txt").exists());
+
+        // Files of the project can be read without a grant
+        let file = dir.path().join("in.txt");
+        std::fs::write(&file, "content").unwrap();
+        let content: String = function(&plugin, "read").call(file.to_str()).unwrap();
+        assert_eq!(content, "content");
+    }
+
+    #[test]
+    fn dry_run_skips_writes() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out.txt");
+        let allow = Allow {
+            write: Grant::Any(true),
+            ..Allow::default()
+        };
+
+        let (_lua, plugin) = load_fixture(dir.path(), &allow, true, FUEL);
+        function(&plugin, "write")
+            .call::<()>((out.to_str(), "data"))
+            .unwrap();
+        assert!(!out.exists());
+
+        let (_lua, plugin) = load_fixture(dir.path(), &allow, false, FUEL);
+        function(&plugin, "write")
+            .call::<()>((out.to_str(), "data"))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "data");
+    }
+
+    #[test]
+    fn refuses_reads_outside_of_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_lua, plugin) = load_fixture(dir.path(), &Allow::default(), false, FUEL);
+
+        for (ptr, len) in [(0, i32::MAX), (65536, 1), (-1, 2), (0, -1)] {
+            let error = function(&plugin, "echo")
+                .call::<Value>((ptr, len))
+                .unwrap_err()
+                .to_string();
+            assert!(error.contains("out of bounds memory access"), "{}", error);
+        }
+        let empty: String = function(&plugin, "echo").call((65536, 0)).unwrap();
+        assert_eq!(empty, "");
+    }
+
+    #[test]
+    fn stops_calls_that_run_out_of_fuel() {
+        let dir = tempfile::tempdir().unwrap();
+        let (_lua, plugin) = load_fixture(dir.path(), &Allow::default(), false, 10_000);
+
+        let error = function(&plugin, "spin")
+            .call::<()>(())
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.contains("spin: ran out of fuel after about 10000 instructions"),
+            "{}",
+            error
+        );
+
+        // Every call gets its own fuel
+        assert_eq!(function(&plugin, "add").call::<i64>((2, 3)).unwrap(), 5);
+    }
+}
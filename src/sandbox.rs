@@ -1,8 +1,23 @@
-use mlua::{Lua, Result as LuaResult, Table};
+use mlua::{
+    Error as LuaError, Function, Lua, MultiValue, Result as LuaResult, Table, Thread, Value,
+};
+
+use crate::include;
+use crate::plugins;
+
+/// Prefix for script output, set while a task runs alongside others
+pub struct OutputPrefix(pub String);
+
+/// Name of the task being run
+pub struct CurrentTask(pub String);
+
+/// Coroutine of the task being run, set while tasks run alongside others
+pub struct TaskThread(pub Thread);
 
 /// Create a sand-boxed environment for Lua scripts
 pub fn create_sandbox(lua: &Lua) -> LuaResult<()> {
     let globals = lua.globals();
+    restrict_stdlib(lua)?;
 
     // Set up the task registry
     let task_registry = lua.create_table()?;
@@ -11,8 +26,11 @@ pub fn create_sandbox(lua: &Lua) -> LuaResult<()> {
     // Define print functions
     globals.set(
         "print",
-        lua.create_function(|_, message: String| {
-            println!("{}", message);
+        lua.create_function(|lua, message: String| {
+            match lua.app_data_ref::<OutputPrefix>() {
+                Some(prefix) => println!("[{}] {}", prefix.0, message),
+                None => println!("{}", message),
+            }
             Ok(())
         })?,
     )?;
@@ -20,60 +38,189 @@ pub fn create_sandbox(lua: &Lua) -> LuaResult<()> {
     // Define plugin loader function
     globals.set(
         "plugin",
-        lua.create_function(|lua, name: String| load_plugin(lua, name))?,
+        lua.create_function(|lua, name: String| plugins::load_plugin(lua, &name))?,
+    )?;
+
+    // Define build file include functions
+    globals.set(
+        "include",
+        lua.create_function(|lua, path: String| include::include(lua, &path))?,
+    )?;
+    globals.set(
+        "import",
+        lua.create_function(|lua, (path, namespace): (String, Option<String>)| {
+            include::import(lua, &path, namespace)
+        })?,
     )?;
 
     // Define task registration function
     globals.set(
         "task",
-        lua.create_function(|lua, (name, func): (String, mlua::Function)| {
-            let globals = lua.globals();
-            let task_registry: Table = globals.get("__lake_tasks")?;
-            task_registry.set(name.clone(), func)?;
-            log::debug!("Registered task: {}", name);
-            Ok(())
+        lua.create_function(|lua, (name, definition): (String, MultiValue)| {
+            register_task(lua, name, definition)
         })?,
     )?;
 
     Ok(())
 }
 
-/// Load a plugin by name
-fn load_plugin(lua: &Lua, name: String) -> LuaResult<Table> {
+/// Remove the parts of the standard library that bypass the permission checks.
+///
+/// Files, processes and the environment are only reachable through the core
+/// plugins, so `os` keeps its time functions and `load` only accepts source.
+fn restrict_stdlib(lua: &Lua) -> LuaResult<()> {
     let globals = lua.globals();
 
-    // Check if it's a core plugin with dot notation
-    if name.starts_with("lake.") {
-        let plugin_table: Table = globals.get(&*name)?;
-        return Ok(plugin_table);
+    let os: Table = globals.get("os")?;
+    let restricted_os = lua.create_table()?;
+    for name in ["time", "clock", "date"] {
+        restricted_os.set(name, os.get::<Value>(name)?)?;
+    }
+    globals.set("os", restricted_os)?;
+
+    for name in ["dofile", "loadfile"] {
+        globals.set(name, Value::Nil)?;
+    }
+
+    // Binary chunks can crash the interpreter, so the mode is always text
+    let load: Function = globals.get("load")?;
+    globals.set(
+        "load",
+        lua.create_function(move |lua, args: MultiValue| {
+            let mut args: Vec<Value> = args.into_iter().collect();
+            args.resize(args.len().max(3), Value::Nil);
+            args[2] = Value::String(lua.create_string("t")?);
+            load.call::<MultiValue>(MultiValue::from_iter(args))
+        })?,
+    )?;
+
+    Ok(())
+}
+
+/// Register a task as `task(name, [desc], [options], fn)`
+fn register_task(lua: &Lua, name: String, definition: MultiValue) -> LuaResult<()> {
+    let invalid = || {
+        LuaError::RuntimeError(format!(
+            "Invalid task '{}': expected task(name, [desc], [options], fn)",
+            name
+        ))
+    };
+
+    let mut desc: Option<String> = None;
+    let mut options: Option<Table> = None;
+    let mut func: Option<Function> = None;
+    for value in definition {
+        match value {
+            Value::String(s) if desc.is_none() && options.is_none() && func.is_none() => {
+                desc = Some(s.to_str()?.to_string())
+            }
+            Value::Table(t) if options.is_none() && func.is_none() => options = Some(t),
+            Value::Function(f) if func.is_none() => func = Some(f),
+            _ => return Err(invalid()),
+        }
+    }
+    let func = func.ok_or_else(invalid)?;
+
+    // The description may also be given as `desc` in the options table
+    if desc.is_none() {
+        if let Some(options) = &options {
+            desc = options.get("desc")?;
+        }
     }
 
-    // Try to load from plugins directory
-    // For now, we'll just check if it's a predefined plugin
-    let plugin_name = format!("lake.{}", name);
-    let plugin_table: mlua::Result<Table> = globals.get(&*plugin_name);
-
-    match plugin_table {
-        Ok(table) => Ok(table),
-        Err(_) => {
-            // Could be a user-defined plugin
-            // Try to load plugin from Lua file
-            let plugin_path = format!("plugins/{}.lua", name);
-            match std::fs::read_to_string(&plugin_path) {
-                Ok(content) => {
-                    let result = lua.load(&content).set_name(&plugin_path).eval::<Table>()?;
-                    log::debug!("Loaded external plugin: {}", name);
-                    Ok(result)
-                }
-                Err(_) => {
-                    // Return an empty table as fallback
-                    log::warn!(
-                        "Warning: Plugin '{}' not found, returning empty table",
-                        name
-                    );
-                    Ok(lua.create_table()?)
-                }
+    // Tasks of imported sub-projects live in their namespace
+    let namespace = include::current_namespace(lua);
+    let name = include::qualify(&namespace, &name);
+
+    // Collect declared dependencies, incremental build inputs/outputs and watch patterns
+    let entry = lua.create_table()?;
+    entry.set("run", func)?;
+    entry.set("desc", desc)?;
+    for key in ["deps", "inputs", "outputs", "watch"] {
+        entry.set(key, string_list(lua, options.as_ref(), key)?)?;
+    }
+
+    // Dependencies are relative to the namespace of the declaring file
+    let deps: Vec<String> = entry.get("deps")?;
+    let deps: Vec<String> = deps
+        .iter()
+        .map(|dep| include::qualify_dependency(&namespace, dep))
+        .collect();
+    entry.set("deps", deps)?;
+
+    // Tasks run in the directory of the file that defines them
+    let dir = include::current_dir(lua)?;
+    entry.set("dir", dir.to_string_lossy().to_string())?;
+
+    // Parameter declarations are validated when the task graph is built
+    if let Some(options) = &options {
+        entry.set("params", options.get::<Option<Table>>("params")?)?;
+    }
+
+    let task_registry: Table = lua.globals().get("__lake_tasks")?;
+    task_registry.set(name.clone(), entry)?;
+    log::debug!("Registered task: {}", name);
+    Ok(())
+}
+
+/// Read an optional list of strings from a task options table
+fn string_list(lua: &Lua, options: Option<&Table>, key: &str) -> LuaResult<Table> {
+    let list = lua.create_table()?;
+    if let Some(options) = options {
+        if let Some(declared) = options.get::<Option<Table>>(key)? {
+            for value in declared.sequence_values::<String>() {
+                list.push(value?)?;
             }
         }
     }
+    Ok(list)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::lake::Project;
+    use crate::permissions::Allow;
+    use crate::test_support::{lock_cwd, options};
+
+    use super::*;
+
+    #[test]
+    fn restricts_the_standard_library() {
+        let _cwd = lock_cwd();
+        let dir = tempfile::tempdir().unwrap();
+        let build_file = dir.path().join("build.lake");
+        let script = r#"
+removed = {}
+for name, value in pairs({
+    io = io, require = require, package = package, debug = debug,
+    dofile = dofile, loadfile = loadfile,
+    execute = os.execute, getenv = os.getenv, remove = os.remove, exit = os.exit,
+}) do
+    table.insert(removed, name)
+end
+now = type(os.time()) .. " " .. type(os.clock()) .. " " .. type(os.date("%Y"))
+sum = load("return x + 1", "chunk", "t", { x = 1 })()
+binary, binary_error = load(string.dump(function() end), "dumped", "b")
+"#;
+        fs::write(&build_file, script).unwrap();
+
+        let project = Project::load(&build_file, &options(dir.path(), &Allow::default())).unwrap();
+        let globals = project.lua.globals();
+        let removed: Vec<String> = globals.get("removed").unwrap();
+        assert!(removed.is_empty(), "{:?}", removed);
+        assert_eq!(
+            globals.get::<String>("now").unwrap(),
+            "number number string"
+        );
+        assert_eq!(globals.get::<i64>("sum").unwrap(), 2);
+        assert!(globals.get::<Value>("binary").unwrap().is_nil());
+        let error: String = globals.get("binary_error").unwrap();
+        assert!(
+            error.contains("attempt to load a binary chunk"),
+            "{}",
+            error
+        );
+    }
 }
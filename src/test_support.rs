@@ -0,0 +1,44 @@
+//! Helpers shared by the unit tests
+
+use std::env;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, MutexGuard};
+
+use crate::config::Config;
+use crate::permissions::{Allow, Permissions};
+use crate::plugins::PluginOptions;
+
+/// Serializes the tests that change or depend on the working directory
+static CWD_LOCK: Mutex<()> = Mutex::new(());
+
+/// Working directory held by a test, restored when dropped
+pub struct CwdGuard {
+    dir: PathBuf,
+    _lock: MutexGuard<'static, ()>,
+}
+
+impl Drop for CwdGuard {
+    fn drop(&mut self) {
+        let _ = env::set_current_dir(&self.dir);
+    }
+}
+
+/// Hold the working directory until the guard is dropped
+pub fn lock_cwd() -> CwdGuard {
+    let lock = CWD_LOCK.lock().unwrap_or_else(|e| e.into_inner());
+    CwdGuard {
+        dir: env::current_dir().unwrap(),
+        _lock: lock,
+    }
+}
+
+/// Options of a build in `project_dir` with the given grants
+pub fn options(project_dir: &Path, allow: &Allow) -> PluginOptions {
+    PluginOptions {
+        dry_run: false,
+        config: Config::default(),
+        permissions: Permissions::new(allow, project_dir),
+        search_path: Vec::new(),
+        project_dir: project_dir.to_path_buf(),
+    }
+}
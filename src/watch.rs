@@ -0,0 +1,387 @@
+//! Watch mode for Lake
+//!
+//! Reruns the requested tasks whenever their declared inputs or watch patterns
+//! change, and reloads the build.lake when it or a file it includes is edited.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::Duration;
+
+use anyhow::{bail, Context, Result};
+use glob::Pattern;
+use notify::{Event, EventKind, RecursiveMode, Watcher};
+
+use crate::diagnostic;
+use crate::lake::Project;
+use crate::plugins::PluginOptions;
+
+/// Quiet period that ends a burst of file events
+const DEBOUNCE: Duration = Duration::from_millis(200);
+
+/// Watch the inputs of a command line and rerun it on every change
+pub fn watch(
+    build_file_path: &Path,
+    options: &PluginOptions,
+    command_line: &[String],
+    task_args: &[String],
+    jobs: usize,
+) -> Result<()> {
+    // Loading the project changes the working directory, so keep an absolute path
+    let build_file = build_file_path.canonicalize().context(format!(
+        "Failed to resolve build.lake path {:?}",
+        build_file_path
+    ))?;
+
+    let (event_tx, event_rx) = mpsc::channel();
+    let mut watcher =
+        notify::recommended_watcher(event_tx).context("Failed to start the file watcher")?;
+    let mut watched: Vec<PathBuf> = Vec::new();
+    let mut project: Option<Project> = None;
+
+    // Keep watching the last known build files while the project fails to load
+    let mut build_files = vec![build_file.clone()];
+
+    loop {
+        if project.is_none() {
+            match Project::load(&build_file, options) {
+                Ok(loaded) => {
+                    build_files = loaded.files().to_vec();
+                    project = Some(loaded);
+                }
+                Err(err) => diagnostic::report(&err),
+            }
+        }
+
+        let mut watch_set = WatchSet::new(&build_file, &build_files);
+        if let Some(project) = &project {
+            match project.run(command_line, task_args, jobs) {
+                Ok(()) => log::info!("Build finished"),
+                // Invalid command lines won't get better by waiting
+                Err(err) if err.downcast_ref::<clap::Error>().is_some() => return Err(err),
+                Err(err) => diagnostic::report(&err),
+            }
+
+            if let Err(err) = watch_set.add_tasks(project, command_line) {
+                diagnostic::report(&err);
+            }
+        }
+
+        // Replace the watches of the previous run
+        for path in watched.drain(..) {
+            let _ = watcher.unwatch(&path);
+        }
+        for (path, recursive) in watch_set.roots() {
+            let mode = if recursive {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            match watcher.watch(&path, mode) {
+                Ok(()) => watched.push(path),
+                Err(err) => log::warn!("Failed to watch {:?}: {}", path, err),
+            }
+        }
+
+        log::info!("Watching for changes...");
+        let changed = watch_set.wait(&event_rx)?;
+        let names: Vec<String> = changed.iter().map(|path| watch_set.display(path)).collect();
+        log::info!("Changed: {}", names.join(", "));
+
+        if build_files.iter().any(|file| changed.contains(file)) {
+            log::info!("Build files changed, reloading");
+            project = None;
+        }
+    }
+}
+
+/// Paths whose changes trigger a rerun
+struct WatchSet {
+    root: PathBuf,
+    build_files: Vec<PathBuf>,
+    patterns: Vec<WatchPattern>,
+    ignored: Vec<Pattern>,
+}
+
+/// A glob pattern declared by a task, with the directory its matches are in
+struct WatchPattern {
+    pattern: Pattern,
+    /// Directory before the first wildcard
+    dir: PathBuf,
+    /// Whether matches can be nested below `dir`
+    recursive: bool,
+}
+
+impl WatchSet {
+    fn new(build_file: &Path, build_files: &[PathBuf]) -> Self {
+        WatchSet {
+            root: build_file.parent().unwrap_or(Path::new("/")).to_path_buf(),
+            build_files: build_files.to_vec(),
+            patterns: Vec::new(),
+            ignored: Vec::new(),
+        }
+    }
+
+    /// Watch the inputs (or `watch` patterns) of every task the command line runs
+    fn add_tasks(&mut self, project: &Project, command_line: &[String]) -> Result<()> {
+        for name in project.resolve(command_line)? {
+            let Some(task) = project.graph().get(&name) else {
+                continue;
+            };
+
+            let patterns = if task.watch.is_empty() {
+                &task.inputs
+            } else {
+                &task.watch
+            };
+            for pattern in patterns {
+                self.patterns
+                    .push(self.compile_pattern(&task.dir, pattern)?);
+            }
+
+            // Outputs written by the build must not trigger another run
+            for output in &task.outputs {
+                let output = self.compile_pattern(&task.dir, output)?;
+                self.ignored.push(output.pattern);
+            }
+        }
+
+        if self.patterns.is_empty() {
+            log::warn!("No inputs or watch patterns declared, only the build files are watched");
+        }
+
+        Ok(())
+    }
+
+    /// Directories to watch, and whether to watch them recursively
+    fn roots(&self) -> BTreeMap<PathBuf, bool> {
+        let mut roots = BTreeMap::new();
+        roots.insert(self.root.clone(), false);
+        for file in &self.build_files {
+            if let Some(dir) = file.parent() {
+                roots.entry(dir.to_path_buf()).or_insert(false);
+            }
+        }
+
+        for pattern in &self.patterns {
+            let mut dir = pattern.dir.clone();
+            let mut recursive = pattern.recursive;
+
+            // Watch the closest existing parent of directories that are not created yet
+            while !dir.exists() {
+                if !dir.pop() {
+                    break;
+                }
+                recursive = true;
+            }
+
+            let entry = roots.entry(dir).or_insert(false);
+            *entry |= recursive;
+        }
+
+        roots
+    }
+
+    /// Check if a change to `path` should trigger a rerun
+    fn matches(&self, path: &Path) -> bool {
+        if self.build_files.iter().any(|file| file == path) {
+            return true;
+        }
+
+        let Ok(relative) = path.strip_prefix(&self.root) else {
+            return false;
+        };
+        if relative.starts_with(".lake") || self.ignored.iter().any(|p| p.matches_path(relative)) {
+            return false;
+        }
+
+        self.patterns.iter().any(|pattern| {
+            pattern.pattern.matches_path(relative) || pattern.pattern.matches_path(path)
+        })
+    }
+
+    /// Block until a relevant change, then collect changes until events settle down
+    fn wait(&self, event_rx: &Receiver<notify::Result<Event>>) -> Result<BTreeSet<PathBuf>> {
+        let mut changed = BTreeSet::new();
+
+        loop {
+            let event = if changed.is_empty() {
+                match event_rx.recv() {
+                    Ok(event) => event,
+                    Err(_) => bail!("The file watcher stopped unexpectedly"),
+                }
+            } else {
+                match event_rx.recv_timeout(DEBOUNCE) {
+                    Ok(event) => event,
+                    Err(RecvTimeoutError::Timeout) => return Ok(changed),
+                    Err(RecvTimeoutError::Disconnected) => {
+                        bail!("The file watcher stopped unexpectedly")
+                    }
+                }
+            };
+
+            match event {
+                Ok(event) if !matches!(event.kind, EventKind::Access(_)) => {
+                    changed.extend(event.paths.into_iter().filter(|p| self.matches(p)));
+                }
+                Ok(_) => {}
+                Err(err) => log::warn!("File watcher error: {}", err),
+            }
+        }
+    }
+
+    fn display(&self, path: &Path) -> String {
+        path.strip_prefix(&self.root)
+            .unwrap_or(path)
+            .display()
+            .to_string()
+    }
+
+    /// Compile a glob pattern declared by a task in `dir`, relative to the
+    /// project root and without the leading `./` it is often written with
+    fn compile_pattern(&self, dir: &Path, pattern: &str) -> Result<WatchPattern> {
+        let pattern = pattern.trim_start_matches("./");
+
+        // The directory to watch comes from the pattern before its directory is escaped
+        let (root, recursive) = pattern_root(pattern);
+
+        let full = match dir.strip_prefix(&self.root) {
+            Ok(relative) if relative.as_os_str().is_empty() => pattern.to_string(),
+            Ok(relative) => format!(
+                "{}/{}",
+                Pattern::escape(&relative.to_string_lossy()),
+                pattern
+            ),
+            Err(_) => format!("{}/{}", Pattern::escape(&dir.to_string_lossy()), pattern),
+        };
+        Ok(WatchPattern {
+            pattern: Pattern::new(&full).context(format!("Invalid glob pattern: {:?}", pattern))?,
+            dir: dir.join(root),
+            recursive,
+        })
+    }
+}
+
+/// Split a glob pattern into the directory before its first wildcard and
+/// whether matches can be nested below that directory
+fn pattern_root(pattern: &str) -> (PathBuf, bool) {
+    // Patterns of files included from outside the project are absolute
+    let mut root = if pattern.starts_with('/') {
+        PathBuf::from("/")
+    } else {
+        PathBuf::new()
+    };
+    let mut rest: Vec<&str> = Vec::new();
+
+    for component in pattern.split('/') {
+        if rest.is_empty() && !component.contains(['*', '?', '[']) {
+            root.push(component);
+        } else {
+            rest.push(component);
+        }
+    }
+
+    match rest.as_slice() {
+        // A plain file path, watch the directory that contains it
+        [] => {
+            root.pop();
+            (root, false)
+        }
+        [last] if !last.contains("**") => (root, false),
+        _ => (root, true),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::thread;
+
+    use super::*;
+
+    #[test]
+    fn splits_patterns_at_their_first_wildcard() {
+        assert_eq!(pattern_root("src/**/*.js"), (PathBuf::from("src"), true));
+        assert_eq!(pattern_root("src/*.js"), (PathBuf::from("src"), false));
+        assert_eq!(pattern_root("src/main.c"), (PathBuf::from("src"), false));
+        assert_eq!(pattern_root("*.c"), (PathBuf::new(), false));
+        assert_eq!(
+            pattern_root("assets/v[0-9]/*.png"),
+            (PathBuf::from("assets"), true)
+        );
+        assert_eq!(
+            pattern_root("/usr/include/*.h"),
+            (PathBuf::from("/usr/include"), false)
+        );
+    }
+
+    #[test]
+    fn matches_inputs_but_not_outputs() {
+        let mut watch_set = WatchSet::new(Path::new("/project/build.lake"), &[]);
+        let dir = Path::new("/project/lib[v2]");
+        let input = watch_set.compile_pattern(dir, "./src/*.c").unwrap();
+        watch_set.patterns.push(input);
+        let output = watch_set.compile_pattern(dir, "src/gen_*.c").unwrap();
+        watch_set.ignored.push(output.pattern);
+
+        assert!(watch_set.matches(Path::new("/project/lib[v2]/src/main.c")));
+        assert!(!watch_set.matches(Path::new("/project/libv/src/main.c")));
+        assert!(!watch_set.matches(Path::new("/project/lib[v2]/src/gen_table.c")));
+        assert!(!watch_set.matches(Path::new("/project/lib[v2]/src/main.h")));
+        assert!(!watch_set.matches(Path::new("/project/.lake/tasks/build.state")));
+        assert!(!watch_set.matches(Path::new("/elsewhere/src/main.c")));
+    }
+
+    #[test]
+    fn watches_the_unescaped_directory_of_patterns() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().join("project");
+        let dir = root.join("lib[v2]");
+        fs::create_dir_all(dir.join("src")).unwrap();
+
+        let mut watch_set = WatchSet::new(&root.join("build.lake"), &[]);
+        let pattern = watch_set.compile_pattern(&dir, "src/**/*.c").unwrap();
+        watch_set.patterns.push(pattern);
+        let pattern = watch_set.compile_pattern(&dir, "missing/*.h").unwrap();
+        watch_set.patterns.push(pattern);
+
+        let roots = watch_set.roots();
+        assert_eq!(roots.get(&dir.join("src")), Some(&true));
+        // Directories that do not exist yet are watched from their parent
+        assert_eq!(roots.get(&dir), Some(&true));
+        assert_eq!(roots.get(&root), Some(&false));
+    }
+
+    #[test]
+    fn reports_changed_files() {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path().canonicalize().unwrap();
+        fs::create_dir(root.join("src")).unwrap();
+
+        let mut watch_set = WatchSet::new(&root.join("build.lake"), &[]);
+        let pattern = watch_set.compile_pattern(&root, "src/*.c").unwrap();
+        watch_set.patterns.push(pattern);
+
+        let (event_tx, event_rx) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(event_tx).unwrap();
+        for (path, recursive) in watch_set.roots() {
+            let mode = if recursive {
+                RecursiveMode::Recursive
+            } else {
+                RecursiveMode::NonRecursive
+            };
+            watcher.watch(&path, mode).unwrap();
+        }
+
+        let src = root.join("src");
+        let writer = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(100));
+            fs::write(src.join("notes.txt"), "ignored").unwrap();
+            fs::write(src.join("main.c"), "int main;").unwrap();
+        });
+
+        let changed = watch_set.wait(&event_rx).unwrap();
+        writer.join().unwrap();
+        assert_eq!(changed, BTreeSet::from([root.join("src/main.c")]));
+    }
+}
@@ -0,0 +1,23 @@
+//! Native plugin loaded by the tests of `plugins::native`
+
+use lake_plugin_api::mlua::{Lua, Result as LuaResult};
+use lake_plugin_api::{declare_plugin, Plugin};
+
+pub struct FixturePlugin;
+
+impl Plugin for FixturePlugin {
+    fn register(&self, lua: &Lua) -> LuaResult<()> {
+        let fixture = lua.create_table()?;
+        fixture.set(
+            "greet",
+            lua.create_function(|_, name: String| Ok(format!("Hello, {}!", name)))?,
+        )?;
+        lua.globals().set("lake.fixture", fixture)
+    }
+
+    fn name(&self) -> &str {
+        "fixture"
+    }
+}
+
+declare_plugin!(FixturePlugin);
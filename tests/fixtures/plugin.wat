@@ -0,0 +1,44 @@
+;; WebAssembly plugin loaded by the tests of `plugins::wasm`
+(module
+  (import "lake" "env_get" (func $env_get (param i32 i32 i32 i32) (result i32)))
+  (import "lake" "read_file" (func $read_file (param i32 i32 i32 i32) (result i32)))
+  (import "lake" "write_file" (func $write_file (param i32 i32 i32 i32)))
+  (import "lake" "result" (func $result (param i32 i32)))
+
+  (memory (export "memory") 1)
+  (global $next (mut i32) (i32.const 1024))
+
+  ;; Bump allocator for the string arguments
+  (func (export "alloc") (param $len i32) (result i32)
+    (local $ptr i32)
+    (local.set $ptr (global.get $next))
+    (global.set $next (i32.add (global.get $next) (local.get $len)))
+    (local.get $ptr))
+
+  (func (export "add") (param i64 i64) (result i64)
+    (i64.add (local.get 0) (local.get 1)))
+
+  (func (export "half") (param f64) (result f64)
+    (f64.div (local.get 0) (f64.const 2)))
+
+  (func (export "echo") (param $ptr i32) (param $len i32)
+    (call $result (local.get $ptr) (local.get $len)))
+
+  ;; Return the value of a variable, or its length of -1 when unset
+  (func (export "env") (param $ptr i32) (param $len i32) (result i32)
+    (local $found i32)
+    (local.set $found (call $env_get (local.get $ptr) (local.get $len) (i32.const 0) (i32.const 1024)))
+    (if (i32.ge_s (local.get $found) (i32.const 0))
+      (then (call $result (i32.const 0) (local.get $found))))
+    (local.get $found))
+
+  (func (export "read") (param $ptr i32) (param $len i32)
+    (call $result
+      (i32.const 0)
+      (call $read_file (local.get $ptr) (local.get $len) (i32.const 0) (i32.const 1024))))
+
+  (func (export "write") (param i32 i32 i32 i32)
+    (call $write_file (local.get 0) (local.get 1) (local.get 2) (local.get 3)))
+
+  (func (export "spin")
+    (loop $forever (br $forever))))